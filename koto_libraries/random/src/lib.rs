@@ -3,14 +3,24 @@
 use {
     koto::runtime::{
         num2, num4, runtime_error, ExternalData, ExternalValue, MetaKey, MetaMap, RwLock, Value,
+        ValueNumber,
     },
     lazy_static::lazy_static,
-    rand::{Rng, SeedableRng},
+    rand::{distributions::Uniform, Rng, SeedableRng},
     rand_chacha::ChaCha20Rng,
     std::{fmt, sync::Arc},
 };
 
 pub fn make_module() -> Value {
+    make_module_with_rng(ChaCha20Rng::from_entropy())
+}
+
+/// Makes the module with its default generator seeded with `seed`, the same as calling `random.seed`.
+pub fn make_seeded_module(seed: u64) -> Value {
+    make_module_with_rng(ChaCha20Rng::seed_from_u64(seed))
+}
+
+fn make_module_with_rng(rng: ChaCha20Rng) -> Value {
     // The random module contains a default generator, with the default RNG interface extended with
     // the `generator` function.
 
@@ -28,7 +38,7 @@ pub fn make_module() -> Value {
         }
     });
 
-    let module_rng = ChaChaRng(ChaCha20Rng::from_entropy());
+    let module_rng = ChaChaRng(rng);
 
     Value::ExternalValue(ExternalValue::new(module_rng, module_meta))
 }
@@ -80,6 +90,25 @@ lazy_static! {
             _ => runtime_error!("random.pick - expected list or range as argument"),
         });
 
+        // An integer in `lo..=hi`, e.g. a velocity with `random.range 64, 127`.
+        meta.add_named_instance_fn_mut("range", |rng: &mut ChaChaRng, _, args| match args {
+            [Number(ValueNumber::I64(lo)), Number(ValueNumber::I64(hi))] => {
+                let (lo, hi) = if hi >= lo { (*lo, *hi) } else { (*hi, *lo) };
+                Ok(Number(rng.0.sample(Uniform::new_inclusive(lo, hi)).into()))
+            }
+            _ => runtime_error!("random.range - expected two integers as arguments"),
+        });
+
+        // A uniformly picked element of a list, e.g. a note of a scale.
+        meta.add_named_instance_fn_mut("choice", |rng: &mut ChaChaRng, _, args| match args {
+            [List(l)] if !l.data().is_empty() => {
+                let index = rng.0.gen_range(0, l.len());
+                Ok(l.data()[index].clone())
+            }
+            [List(_)] => runtime_error!("random.choice - the list is empty"),
+            _ => runtime_error!("random.choice - expected a list as argument"),
+        });
+
         meta.add_named_instance_fn_mut("seed", |rng: &mut ChaChaRng, _, args| match args {
             [Number(n)] => {
                 *rng = ChaChaRng(ChaCha20Rng::seed_from_u64(n.to_bits()));
@@ -114,3 +143,89 @@ impl fmt::Display for ChaChaRng {
         write!(f, "Rng")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, koto::Koto};
+
+    /// Runs `script` with the module seeded with `seed` in the prelude as "random".
+    fn run_seeded(seed: u64, script: &str) -> Result<Value, String> {
+        let mut runtime = Koto::default();
+        runtime
+            .prelude()
+            .add_value("random", make_seeded_module(seed));
+        let chunk = runtime.compile(script).map_err(|err| err.to_string())?;
+        runtime.run_chunk(chunk).map_err(|err| err.to_string())
+    }
+
+    fn integers(value: Value) -> Vec<i64> {
+        match value {
+            Value::List(list) => list
+                .data()
+                .iter()
+                .map(|value| match value {
+                    Value::Number(ValueNumber::I64(n)) => *n,
+                    other => panic!("{} is not an integer", other),
+                })
+                .collect(),
+            other => panic!("{} is not a list", other),
+        }
+    }
+
+    const RANGES: &str = "
+import random
+result = []
+for _ in 0..1000
+  result.push (random.range 5, 10)
+  result.push (random.range 3, -3)
+result
+";
+
+    #[test]
+    fn a_seeded_range_stays_in_bounds() {
+        let numbers = integers(run_seeded(1, RANGES).expect("the script runs"));
+        assert_eq!(numbers.len(), 2000);
+        for pair in numbers.chunks(2) {
+            assert!((5..=10).contains(&pair[0]), "{} is out of 5..=10", pair[0]);
+            assert!((-3..=3).contains(&pair[1]), "{} is out of -3..=3", pair[1]);
+        }
+        // Both ends are inclusive.
+        assert!(numbers.iter().step_by(2).any(|n| *n == 5));
+        assert!(numbers.iter().step_by(2).any(|n| *n == 10));
+    }
+
+    #[test]
+    fn choice_of_an_empty_list_is_an_error() {
+        let result = run_seeded(1, "import random\nrandom.choice []\n");
+        assert!(result.is_err());
+        assert!(run_seeded(1, "import random\nrandom.choice [42]\n").is_ok());
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_sequence() {
+        let first = integers(run_seeded(42, RANGES).expect("the script runs"));
+        let second = integers(run_seeded(42, RANGES).expect("the script runs"));
+        assert_eq!(first, second);
+        let other_seed = integers(run_seeded(43, RANGES).expect("the script runs"));
+        assert_ne!(first, other_seed);
+    }
+
+    #[test]
+    fn seeding_again_restarts_the_sequence() {
+        let script = "
+import random
+random.seed 7
+first = [random.number(), random.number(), random.number()]
+random.seed 7
+second = [random.number(), random.number(), random.number()]
+first == second
+";
+        match run_seeded(42, script) {
+            Ok(Value::Bool(same)) => assert!(same),
+            other => panic!(
+                "unexpected result: {:?}",
+                other.map(|value| value.to_string())
+            ),
+        }
+    }
+}
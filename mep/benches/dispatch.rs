@@ -0,0 +1,61 @@
+//! Dispatching a received message to the script's "midi.listen", with and without the cached listener.
+
+#![feature(stmt_expr_attributes)]
+// "mep" is a binary, the modules which the benchmarks need are compiled into them as they are.
+#![allow(dead_code)]
+#[path = "../src/backend.rs"]
+mod backend;
+#[path = "../src/extensions.rs"]
+mod extensions;
+#[path = "../src/listen.rs"]
+mod listen;
+#[path = "../src/tui.rs"]
+mod tui;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use extensions::{ListenerCache, ALL_CHANNELS};
+use koto::Koto;
+use listen::call_midi_listen_with;
+
+/// A runtime running a script whose "midi.listen" keeps the last message as "midi.received".
+fn runtime_keeping_received_message() -> Koto {
+    let mut runtime = Koto::default();
+    runtime.prelude().add_map("midi", koto_midi::make_module());
+    let chunk =
+        match runtime.compile("import midi\nmidi.listen = |message| midi.received = message\n") {
+            Ok(chunk) => chunk,
+            Err(err) => panic!("the script doesn't compile: {}", err),
+        };
+    if let Err(err) = runtime.run_chunk(chunk) {
+        panic!("the script doesn't run: {}", err);
+    }
+    runtime
+}
+
+/// Dispatches a note on to a script's "midi.listen", looking it up for every message unless `cached`.
+fn dispatch(criterion: &mut Criterion) {
+    for (name, cached) in [
+        ("dispatching a message with the listener cached", true),
+        ("dispatching a message looking up the listener", false),
+    ] {
+        let mut runtime = runtime_keeping_received_message();
+        let listener_cache = ListenerCache::default();
+        criterion.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                if !cached {
+                    listener_cache.replace(None);
+                }
+                call_midi_listen_with(
+                    0,
+                    black_box(&[0x90, 60, 100]),
+                    &mut runtime,
+                    ALL_CHANNELS,
+                    &listener_cache,
+                )
+            });
+        });
+    }
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
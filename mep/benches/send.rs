@@ -0,0 +1,62 @@
+//! Sending a fader sweep through an output, message by message or with "--batch-output".
+
+#![feature(stmt_expr_attributes)]
+// "mep" is a binary, the modules which the benchmarks need are compiled into them as they are.
+#![allow(dead_code)]
+#[path = "../src/backend.rs"]
+mod backend;
+#[path = "../src/extensions.rs"]
+mod extensions;
+#[path = "../src/tui.rs"]
+mod tui;
+
+use backend::OutputConnection;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use crossbeam_channel::unbounded;
+use extensions::{batch_messages, ActiveNotes, Output, OutputPort};
+
+/// Takes what is sent without writing it anywhere, so only what "mep" does before a port writes is measured.
+struct Discard;
+
+impl OutputConnection for Discard {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        black_box(message);
+        Ok(())
+    }
+}
+
+/// A fader sweep, the controller runs through all of its values.
+fn fader_sweep() -> Vec<Vec<u8>> {
+    (0..=127_u8).map(|value| vec![0xB0, 7, value]).collect()
+}
+
+fn send(criterion: &mut Criterion) {
+    let sweep = fader_sweep();
+    for (name, batch) in [
+        ("sending a fader sweep one by one", false),
+        ("sending a fader sweep batched", true),
+    ] {
+        let (error_to_main, _) = unbounded();
+        let port = OutputPort::spawn(
+            Output::Port(Box::new(Discard)),
+            error_to_main,
+            batch,
+            ActiveNotes::default(),
+            None,
+        );
+        criterion.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                for message in &sweep {
+                    port.send(message);
+                }
+                port.flush();
+            });
+        });
+    }
+    criterion.bench_function("joining a fader sweep with running status", |bencher| {
+        bencher.iter(|| batch_messages(black_box(&sweep)));
+    });
+}
+
+criterion_group!(benches, send);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use std::{env, fs, path::PathBuf};
+
+/// Exposes the version of the "koto" crate which "mep" is built against as "MEP_KOTO_VERSION".
+///
+/// "koto" is a git dependency so its version is read from the lock file, e.g. "0.8.1 (8ac8912)".
+fn main() {
+    let mut lock_file_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    lock_file_path.push("..");
+    lock_file_path.push("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock_file_path.display());
+    // The example scripts are built into the binary with "include_dir!", embed their changes too.
+    println!("cargo:rerun-if-changed=../example_scripts");
+
+    let koto_version = fs::read_to_string(&lock_file_path)
+        .ok()
+        .and_then(|lock_file| koto_version_from(&lock_file))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=MEP_KOTO_VERSION={}", koto_version);
+}
+
+fn koto_version_from(lock_file: &str) -> Option<String> {
+    let package = lock_file
+        .split("[[package]]")
+        .find(|package| package.lines().any(|line| line.trim() == "name = \"koto\""))?;
+    let field = |name: &str| {
+        package.lines().find_map(|line| {
+            line.trim()
+                .strip_prefix(name)
+                .and_then(|rest| rest.trim_start().strip_prefix('='))
+                .map(|value| value.trim().trim_matches('"').to_owned())
+        })
+    };
+    let version = field("version")?;
+    // Short commit hash of the git source, e.g. "git+https://...?branch=main#8ac8912185b5...".
+    match field("source").and_then(|source| source.split('#').nth(1).map(str::to_owned)) {
+        Some(commit) => Some(format!("{} ({})", version, &commit[..commit.len().min(7)])),
+        None => Some(version),
+    }
+}
@@ -0,0 +1,499 @@
+//! The midi ports which "mep" sends to and receives from, opened through midir or mocked in tests.
+
+use anyhow::{anyhow, bail, Result};
+use crossterm::style::Stylize;
+#[cfg(unix)]
+use midir::os::unix::{VirtualInput, VirtualOutput};
+use midir::{
+    MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort,
+};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use crate::tui::BULB;
+
+/// Called with the timestamp and the bytes of every message which an input port receives.
+pub type OnReceive = Box<dyn FnMut(u64, &[u8]) + Send>;
+
+/// An open output port.
+pub trait OutputConnection: Send {
+    fn send(&mut self, message: &[u8]) -> Result<(), String>;
+}
+
+/// An open input port, it calls its `OnReceive` until it is dropped.
+pub trait InputConnection: Send {}
+
+/// Opens the ports, "mep"'s own one named `port_name` or an existing one which contains `connect_to` in its name.
+///
+/// The own ports are virtual where the platform supports them.
+/// The name of the existing port is returned too, if one is connected.
+pub trait MidiBackend: Send + Sync {
+    fn open_input(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+        on_receive: OnReceive,
+    ) -> Result<(Box<dyn InputConnection>, Option<String>)>;
+
+    fn open_output(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+    ) -> Result<(Box<dyn OutputConnection>, Option<String>)>;
+
+    /// Names of all midi input and output ports available in the system.
+    fn port_names(&self) -> Result<(Vec<String>, Vec<String>)>;
+}
+
+impl OutputConnection for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        Self::send(self, message).map_err(|err| err.to_string())
+    }
+}
+
+impl InputConnection for MidiInputConnection<()> {}
+
+/// Ports of the system, every port is opened with its own midir client.
+pub struct Midir {
+    input_client_name: String,
+    output_client_name: String,
+}
+
+impl Midir {
+    pub fn new(input_client_name: &str, output_client_name: &str) -> Self {
+        Self {
+            input_client_name: input_client_name.to_owned(),
+            output_client_name: output_client_name.to_owned(),
+        }
+    }
+}
+
+impl MidiBackend for Midir {
+    fn open_input(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+        mut on_receive: OnReceive,
+    ) -> Result<(Box<dyn InputConnection>, Option<String>)> {
+        let mep_in = MidiInput::new(&self.input_client_name)?;
+        let callback = move |stamp: u64, message: &[u8], _: &mut ()| on_receive(stamp, message);
+        let port_substring = if let Some(port_substring) = connect_to {
+            port_substring
+        } else {
+            return open_own_input(mep_in, port_name, callback)
+                .map(|connection| (Box::new(connection) as Box<dyn InputConnection>, None));
+        };
+
+        // Connect to an existing (hardware) input port instead of creating a virtual one.
+        let port = if let Some(port) = find_input_port(&mep_in, port_substring) {
+            port
+        } else {
+            bail!(
+                "{} {}",
+                BULB,
+                format!(
+                    "Couldn't find a midi input port which contains \"{}\" in its name.",
+                    port_substring
+                )
+                .red()
+            );
+        };
+        let connected_port_name = mep_in.port_name(&port).ok();
+        let connection = mep_in
+            .connect(&port, port_name, callback, ())
+            .map_err(|err| {
+                anyhow!(
+                    "Couldn't connect to the midi input port containing {} in its name.\nError: {:?}",
+                    port_substring,
+                    err
+                )
+            })?;
+        Ok((Box::new(connection), connected_port_name))
+    }
+
+    fn open_output(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+    ) -> Result<(Box<dyn OutputConnection>, Option<String>)> {
+        let mep_out = MidiOutput::new(&self.output_client_name)?;
+        let port_substring = if let Some(port_substring) = connect_to {
+            port_substring
+        } else {
+            return open_own_output(mep_out, port_name)
+                .map(|connection| (Box::new(connection) as Box<dyn OutputConnection>, None));
+        };
+
+        let port = if let Some(port) = find_output_port(&mep_out, port_substring) {
+            port
+        } else {
+            bail!(
+                "{} {}",
+                BULB,
+                format!(
+                    "Couldn't find a midi output port which contains \"{}\" in its name.",
+                    port_substring
+                )
+                .red()
+            );
+        };
+        let connected_port_name = mep_out.port_name(&port).ok();
+        let connection = mep_out.connect(&port, port_name).map_err(|err| {
+            anyhow!(
+                "Couldn't connect to the midi output port containing {} in its name.\nError: {:?}",
+                port_substring,
+                err
+            )
+        })?;
+        Ok((Box::new(connection), connected_port_name))
+    }
+
+    fn port_names(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let midi_in = MidiInput::new(&self.input_client_name)?;
+        let midi_out = MidiOutput::new(&self.output_client_name)?;
+
+        let input_port_names = midi_in
+            .ports()
+            .iter()
+            .map(|port| midi_in.port_name(port))
+            .collect::<Result<Vec<String>, _>>()?;
+        let output_port_names = midi_out
+            .ports()
+            .iter()
+            .map(|port| midi_out.port_name(port))
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok((input_port_names, output_port_names))
+    }
+}
+
+/// Creates a virtual input port named `input_port_name`.
+#[cfg(unix)]
+fn open_own_input<F>(
+    mep_in: MidiInput,
+    input_port_name: &str,
+    callback: F,
+) -> Result<MidiInputConnection<()>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    mep_in
+        .create_virtual(input_port_name, callback, ())
+        .map_err(|err| {
+            anyhow!(
+                "Couldn't create virtual midi input port named {}.\nError: {:?}",
+                input_port_name,
+                err
+            )
+        })
+}
+
+/// Connects to an existing input port named `input_port_name`, e.g. one made with loopMIDI.
+///
+/// Windows doesn't support virtual midi ports.
+#[cfg(not(unix))]
+fn open_own_input<F>(
+    mep_in: MidiInput,
+    input_port_name: &str,
+    callback: F,
+) -> Result<MidiInputConnection<()>>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    let ports = mep_in.ports();
+    let port_names = ports
+        .iter()
+        .map(|port| mep_in.port_name(port).unwrap_or_default())
+        .collect::<Vec<String>>();
+    let port = if let Some(index) = find_port_named(&port_names, input_port_name) {
+        &ports[index]
+    } else {
+        bail!(
+            "{} {}",
+            BULB,
+            format!(
+                "Virtual midi ports are not supported on this platform. Please create a port named \"{}\" with a tool like loopMIDI, or connect to an existing one with \"--connect-in <port-substring>\".",
+                input_port_name
+            )
+            .red()
+        );
+    };
+    mep_in
+        .connect(port, input_port_name, callback, ())
+        .map_err(|err| {
+            anyhow!(
+                "Couldn't connect to the midi input port named {}.\nError: {:?}",
+                input_port_name,
+                err
+            )
+        })
+}
+
+/// Creates a virtual output port named `port_name`.
+#[cfg(unix)]
+fn open_own_output(midi_out: MidiOutput, port_name: &str) -> Result<MidiOutputConnection> {
+    midi_out.create_virtual(port_name).map_err(|err| {
+        anyhow!(
+            "Couldn't create virtual midi output port named {}.\nError: {:?}",
+            port_name,
+            err
+        )
+    })
+}
+
+/// Connects to an existing output port named `port_name`, e.g. one made with loopMIDI.
+///
+/// Windows doesn't support virtual midi ports.
+#[cfg(not(unix))]
+fn open_own_output(midi_out: MidiOutput, port_name: &str) -> Result<MidiOutputConnection> {
+    let ports = midi_out.ports();
+    let port_names = ports
+        .iter()
+        .map(|port| midi_out.port_name(port).unwrap_or_default())
+        .collect::<Vec<String>>();
+    let port = if let Some(index) = find_port_named(&port_names, port_name) {
+        &ports[index]
+    } else {
+        bail!(
+            "{} {}",
+            BULB,
+            format!(
+                "Virtual midi ports are not supported on this platform. Please create a port named \"{}\" with a tool like loopMIDI, or connect to an existing one with \"--connect-out <port-substring>\".",
+                port_name
+            )
+            .red()
+        );
+    };
+    midi_out.connect(port, port_name).map_err(|err| {
+        anyhow!(
+            "Couldn't connect to the midi output port named {}.\nError: {:?}",
+            port_name,
+            err
+        )
+    })
+}
+
+/// Index of the port named `port_name`, some drivers append a number to the names, e.g. "mep_in 1".
+///
+/// A plain substring would match "mep_out" with "mep_out_1".
+#[cfg(any(not(unix), test))]
+fn find_port_named(port_names: &[String], port_name: &str) -> Option<usize> {
+    port_names.iter().position(|name| {
+        name == port_name
+            || name
+                .strip_prefix(port_name)
+                .map_or(false, |rest| rest.starts_with(' '))
+    })
+}
+
+/// Finds the first output port which contains `port_substring` in its name.
+fn find_output_port(midi_output: &MidiOutput, port_substring: &str) -> Option<MidiOutputPort> {
+    midi_output.ports().into_iter().find(|port| {
+        midi_output
+            .port_name(port)
+            .map_or(false, |name| name.contains(port_substring))
+    })
+}
+
+/// Finds the first input port which contains `port_substring` in its name.
+fn find_input_port(midi_input: &MidiInput, port_substring: &str) -> Option<MidiInputPort> {
+    midi_input.ports().into_iter().find(|port| {
+        midi_input
+            .port_name(port)
+            .map_or(false, |name| name.contains(port_substring))
+    })
+}
+
+/// Ports which exist only in memory, what is sent is recorded and what is received is injected by tests.
+///
+/// Clones share the same ports.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Mock {
+    /// Names of the existing input and output ports which can be connected to.
+    existing_ports: Arc<Mutex<(Vec<String>, Vec<String>)>>,
+    /// Every message sent with the name of the output port it is sent through.
+    sent: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
+    /// Callbacks of the input ports, `None` once the port is closed.
+    receivers: Arc<Mutex<Vec<Arc<Mutex<Option<OnReceive>>>>>>,
+}
+
+#[cfg(test)]
+impl Mock {
+    /// Ports which can be connected to with `connect_to`, besides the virtual ones which are always opened.
+    pub fn with_existing_ports(input_port_names: &[&str], output_port_names: &[&str]) -> Self {
+        let mock = Self::default();
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        let mut existing_ports = mock.existing_ports.lock().unwrap();
+        existing_ports.0 = input_port_names.iter().map(ToString::to_string).collect();
+        existing_ports.1 = output_port_names.iter().map(ToString::to_string).collect();
+        drop(existing_ports);
+        mock
+    }
+
+    /// Calls every open input port as if they received `message`.
+    pub fn receive(&self, stamp: u64, message: &[u8]) {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        for receiver in self.receivers.lock().unwrap().iter() {
+            #[allow(clippy::unwrap_used)]
+            if let Some(on_receive) = receiver.lock().unwrap().as_mut() {
+                on_receive(stamp, message);
+            }
+        }
+    }
+
+    /// Messages sent through the output port with the given name so far, in order.
+    pub fn sent(&self, port_name: &str) -> Vec<Vec<u8>> {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == port_name)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    /// Name of the existing port which contains `connect_to`, or `port_name` for a virtual one.
+    fn resolve(
+        existing_port_names: &[String],
+        port_name: &str,
+        connect_to: Option<&str>,
+    ) -> Result<(String, Option<String>)> {
+        match connect_to {
+            None => Ok((port_name.to_owned(), None)),
+            Some(port_substring) => existing_port_names
+                .iter()
+                .find(|name| name.contains(port_substring))
+                .map(|name| (name.clone(), Some(name.clone())))
+                .ok_or_else(|| anyhow!("There is no mock port containing \"{}\".", port_substring)),
+        }
+    }
+}
+
+#[cfg(test)]
+struct MockInput(Arc<Mutex<Option<OnReceive>>>);
+
+#[cfg(test)]
+impl InputConnection for MockInput {}
+
+#[cfg(test)]
+impl Drop for MockInput {
+    fn drop(&mut self) {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        drop(self.0.lock().unwrap().take());
+    }
+}
+
+#[cfg(test)]
+struct MockOutput {
+    port_name: String,
+    sent: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
+}
+
+#[cfg(test)]
+impl OutputConnection for MockOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        self.sent
+            .lock()
+            .unwrap()
+            .push((self.port_name.clone(), message.to_vec()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl MidiBackend for Mock {
+    fn open_input(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+        on_receive: OnReceive,
+    ) -> Result<(Box<dyn InputConnection>, Option<String>)> {
+        let (_, connected_port_name) = self.port_names().and_then(|(input_port_names, _)| {
+            Self::resolve(&input_port_names, port_name, connect_to)
+        })?;
+        let receiver = Arc::new(Mutex::new(Some(on_receive)));
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        self.receivers.lock().unwrap().push(Arc::clone(&receiver));
+        Ok((Box::new(MockInput(receiver)), connected_port_name))
+    }
+
+    fn open_output(
+        &self,
+        port_name: &str,
+        connect_to: Option<&str>,
+    ) -> Result<(Box<dyn OutputConnection>, Option<String>)> {
+        let (port_name, connected_port_name) =
+            self.port_names().and_then(|(_, output_port_names)| {
+                Self::resolve(&output_port_names, port_name, connect_to)
+            })?;
+        let output = MockOutput {
+            port_name,
+            sent: Arc::clone(&self.sent),
+        };
+        Ok((Box::new(output), connected_port_name))
+    }
+
+    fn port_names(&self) -> Result<(Vec<String>, Vec<String>)> {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        Ok(self.existing_ports.lock().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn received_messages_reach_the_open_inputs_only() {
+        let mock = Mock::default();
+        let (received_to_test, received) = channel();
+        let on_receive: OnReceive = Box::new(move |stamp, message| {
+            let _ = received_to_test.send((stamp, message.to_vec()));
+        });
+        let input = match mock.open_input("mep_in", None, on_receive) {
+            Ok((input, _)) => input,
+            Err(err) => panic!("the input isn't opened: {}", err),
+        };
+        mock.receive(1, &[0x90, 60, 100]);
+        drop(input);
+        mock.receive(2, &[0x80, 60, 0]);
+        assert_eq!(
+            received.try_iter().collect::<Vec<_>>(),
+            vec![(1, vec![0x90, 60, 100])]
+        );
+    }
+
+    #[test]
+    fn outputs_connect_to_an_existing_port_by_a_part_of_its_name() {
+        let mock = Mock::with_existing_ports(&[], &["IAC Driver Bus 1"]);
+        let mut output = match mock.open_output("mep_out", Some("Bus 1")) {
+            Ok((output, connected_port_name)) => {
+                assert_eq!(connected_port_name.as_deref(), Some("IAC Driver Bus 1"));
+                output
+            }
+            Err(err) => panic!("the output isn't connected: {}", err),
+        };
+        assert_eq!(output.send(&[0xF8]), Ok(()));
+        assert_eq!(mock.sent("IAC Driver Bus 1"), vec![vec![0xF8]]);
+        assert!(mock.open_output("mep_out", Some("Bus 2")).is_err());
+    }
+
+    #[test]
+    fn own_ports_are_found_by_their_whole_name() {
+        let port_names = ["mep_out_1".to_owned(), "mep_out 2".to_owned()];
+        assert_eq!(find_port_named(&port_names, "mep_out"), Some(1));
+        assert_eq!(find_port_named(&port_names, "mep_out_1"), Some(0));
+        assert_eq!(find_port_named(&port_names, "mep_in"), None);
+    }
+}
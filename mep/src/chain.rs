@@ -0,0 +1,222 @@
+//! Scripts which process the incoming messages before the running script when "mep" is run with "--chain".
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+use koto::{
+    runtime::{runtime_error, RuntimeError, Value, ValueMap, ValueNumber},
+    Koto,
+};
+
+use crate::{
+    call_midi_listen_with,
+    extensions::{self, list_to_midi_message, ListenerCache, ALL_CHANNELS},
+    make_random_module,
+};
+
+/// A script in the chain before the running one.
+///
+/// Its "midi.send" passes the messages on to the next script instead of sending them through an output port.
+pub struct ChainStage {
+    pub path: String,
+    runtime: Koto,
+    passed_on: Arc<Mutex<Vec<Vec<u8>>>>,
+    listener_cache: ListenerCache,
+    /// A bypassed stage passes the messages on untouched, set by "midi.bypass" and "midi.solo".
+    bypassed: Arc<AtomicBool>,
+}
+
+impl ChainStage {
+    /// Loads the script at `path` and runs it once, like the running script is when it is chosen.
+    pub fn new(
+        path: &str,
+        log_to_main: Sender<String>,
+        started_at: Instant,
+        seed: Option<u64>,
+        args: &ValueMap,
+        middle_c_octave: i64,
+        scripts_folder_path: &Path,
+    ) -> Result<Self> {
+        let passed_on = Arc::new(Mutex::new(Vec::new()));
+
+        let mut midi_module = koto_midi::make_module();
+        extensions::add_message_helpers(&mut midi_module);
+        extensions::add_read_cc14(&mut midi_module);
+        extensions::add_transforms(&mut midi_module, middle_c_octave);
+        extensions::add_note_names(&mut midi_module, middle_c_octave);
+        extensions::add_log(&mut midi_module, log_to_main);
+        extensions::add_now(&mut midi_module, started_at);
+        extensions::add_load_data(
+            &mut midi_module,
+            Arc::new(Mutex::new(PathBuf::from(path))),
+            scripts_folder_path.to_path_buf(),
+        );
+        midi_module.add_map("args", args.clone());
+        midi_module.add_fn("send", {
+            let passed_on = Arc::clone(&passed_on);
+            move |vm, args| match vm.get_args(args) {
+                [Value::List(message)] => {
+                    if let Some(message) = list_to_midi_message(message) {
+                        // `lock.unwrap()` will always succeed because no one panics while holding it.
+                        #[allow(clippy::unwrap_used)]
+                        passed_on.lock().unwrap().push(message);
+                        Ok(Value::Empty)
+                    } else {
+                        runtime_error!(
+                            "midi.send - expected a list of bytes (integers ranged to 0..=255)"
+                        )
+                    }
+                }
+                _ => runtime_error!(
+                    "midi.send - in a chain a script passes a single list of bytes to the next script, output port indexes are only available in the last one"
+                ),
+            }
+        });
+
+        let mut runtime = Koto::default();
+        runtime.set_script_path(Some(PathBuf::from(path)));
+        let mut prelude = runtime.prelude();
+        prelude.add_map("midi", midi_module);
+        prelude.add_value("random", make_random_module(seed));
+
+        let mut stage = Self {
+            path: path.to_owned(),
+            runtime,
+            passed_on,
+            listener_cache: ListenerCache::default(),
+            bypassed: Arc::new(AtomicBool::new(false)),
+        };
+        stage.reload()?;
+        Ok(stage)
+    }
+
+    /// Compiles and runs the script again, e.g. after it is changed.
+    pub fn reload(&mut self) -> Result<()> {
+        // It might define another "midi.listen".
+        self.listener_cache.replace(None);
+        let script = fs::read_to_string(&self.path)?;
+        let chunk = self
+            .runtime
+            .compile(&script)
+            .map_err(|err| anyhow!(err.to_string()))?;
+        self.runtime
+            .run_chunk(chunk)
+            .map(|_| ())
+            .map_err(|err| anyhow!(err.to_string()))
+    }
+
+    pub fn is_bypassed(&self) -> bool {
+        self.bypassed.load(Ordering::Relaxed)
+    }
+
+    /// Calls "midi.listen" of the script with the message and returns the messages it passed on.
+    pub fn process(&mut self, stamp: u64, message: &[u8]) -> Result<Vec<Vec<u8>>, RuntimeError> {
+        let result = call_midi_listen_with(
+            stamp,
+            message,
+            &mut self.runtime,
+            ALL_CHANNELS,
+            &self.listener_cache,
+        );
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        let passed_on = std::mem::take(&mut *self.passed_on.lock().unwrap());
+        result.map(|_| passed_on)
+    }
+}
+
+/// Passes a message through every stage in order, the messages the last stage passes on are returned.
+///
+/// Returns the path of the failing script together with its error.
+pub fn run_chain(
+    stages: &mut [ChainStage],
+    stamp: u64,
+    message: &[u8],
+) -> Result<Vec<Vec<u8>>, (String, RuntimeError)> {
+    let mut messages = vec![message.to_vec()];
+    for stage in stages {
+        if stage.is_bypassed() {
+            continue;
+        }
+        let mut passed_on = Vec::new();
+        for message in &messages {
+            passed_on.extend(
+                stage
+                    .process(stamp, message)
+                    .map_err(|err| (stage.path.clone(), err))?,
+            );
+        }
+        messages = passed_on;
+    }
+    Ok(messages)
+}
+
+/// Adds "midi.bypass" and "midi.solo" to the running script, so it can turn the stages of the chain off and on.
+///
+/// Stages are addressed by their index in "--chain", the bypassed ones stay so until they are changed again.
+pub fn add_bypass(midi_module: &mut ValueMap, stages: &[ChainStage]) {
+    let bypassed = stages
+        .iter()
+        .map(|stage| Arc::clone(&stage.bypassed))
+        .collect::<Vec<Arc<AtomicBool>>>();
+    midi_module.add_fn("bypass", {
+        let bypassed = bypassed.clone();
+        move |vm, args| match vm.get_args(args) {
+            [Value::Number(ValueNumber::I64(stage)), Value::Bool(bypass)] => {
+                if let Some(flag) = stage_flag(&bypassed, *stage) {
+                    flag.store(*bypass, Ordering::Relaxed);
+                    Ok(Value::Empty)
+                } else {
+                    runtime_error!(
+                        "midi.bypass - stage index should be in 0..{} range, got {}",
+                        bypassed.len(),
+                        stage
+                    )
+                }
+            }
+            _ => runtime_error!(
+                "midi.bypass - expected the index of a stage in the chain and a boolean as arguments. Ex. midi.bypass 0, true"
+            ),
+        }
+    });
+    midi_module.add_fn("solo", move |vm, args| match vm.get_args(args) {
+        // Without a stage every stage runs again.
+        [] => {
+            for flag in &bypassed {
+                flag.store(false, Ordering::Relaxed);
+            }
+            Ok(Value::Empty)
+        }
+        [Value::Number(ValueNumber::I64(stage))] => {
+            if stage_flag(&bypassed, *stage).is_none() {
+                return runtime_error!(
+                    "midi.solo - stage index should be in 0..{} range, got {}",
+                    bypassed.len(),
+                    stage
+                );
+            }
+            for (index, flag) in bypassed.iter().enumerate() {
+                flag.store(i64::try_from(index).ok() != Some(*stage), Ordering::Relaxed);
+            }
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!(
+            "midi.solo - expected the index of a stage in the chain as argument or no arguments. Ex. midi.solo 1"
+        ),
+    });
+}
+
+fn stage_flag(bypassed: &[Arc<AtomicBool>], stage: i64) -> Option<&Arc<AtomicBool>> {
+    usize::try_from(stage)
+        .ok()
+        .and_then(|stage| bypassed.get(stage))
+}
@@ -0,0 +1,104 @@
+//! Optional defaults read from "~/.mep/config.toml".
+//!
+//! Command line options always take precedence over these.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use crossterm::style::Color;
+use serde::Deserialize;
+
+use crate::tui::Theme;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Same as "--port".
+    pub port: Option<String>,
+    /// Same as "--port-in".
+    pub port_in: Option<String>,
+    /// Same as "--port-out".
+    pub port_out: Option<String>,
+    /// Same as "--client-name".
+    pub client_name: Option<String>,
+    /// Same as "--script".
+    pub default_script: Option<String>,
+    /// Colors of the terminal interface, under "[theme]".
+    pub theme: ThemeConfig,
+}
+
+/// Names of the colors which replace the default ones, e.g. "dark_blue" or "grey".
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub intro: Option<String>,
+    pub index: Option<String>,
+    pub highlight: Option<String>,
+    pub name: Option<String>,
+    pub prompt: Option<String>,
+    pub error: Option<String>,
+    pub ok: Option<String>,
+    pub compile_error: Option<String>,
+    pub runtime_error: Option<String>,
+    pub notice: Option<String>,
+    pub text: Option<String>,
+}
+
+impl ThemeConfig {
+    /// Replaces the colors of the theme with the ones which are given.
+    pub fn apply_to(&self, mut theme: Theme) -> Result<Theme> {
+        let colors = [
+            (&self.intro, &mut theme.intro, "intro"),
+            (&self.index, &mut theme.index, "index"),
+            (&self.highlight, &mut theme.highlight, "highlight"),
+            (&self.name, &mut theme.name, "name"),
+            (&self.prompt, &mut theme.prompt, "prompt"),
+            (&self.error, &mut theme.error, "error"),
+            (&self.ok, &mut theme.ok, "ok"),
+            (
+                &self.compile_error,
+                &mut theme.compile_error,
+                "compile_error",
+            ),
+            (
+                &self.runtime_error,
+                &mut theme.runtime_error,
+                "runtime_error",
+            ),
+            (&self.notice, &mut theme.notice, "notice"),
+            (&self.text, &mut theme.text, "text"),
+        ];
+        for (name, color, key) in colors {
+            if let Some(name) = name {
+                *color = Color::try_from(name.as_str()).map_err(|_| {
+                    anyhow!(
+                        "\"{}\" of the theme is not a color, try one like \"dark_blue\" or \"grey\", got \"{}\".",
+                        key,
+                        name
+                    )
+                })?;
+            }
+        }
+        Ok(theme)
+    }
+}
+
+impl Config {
+    /// Reads the config file in the scripts folder, if there is none the defaults are used.
+    pub fn read_from(scripts_folder_path: &Path) -> Result<Self> {
+        let config_path = scripts_folder_path.join(CONFIG_FILE_NAME);
+        if !config_path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&config_path)?;
+        toml::from_str(&contents).map_err(|err| {
+            anyhow!(
+                "Couldn't read the configuration in \"{}\". {}",
+                config_path.display(),
+                err
+            )
+        })
+    }
+}
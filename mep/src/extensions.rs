@@ -0,0 +1,2202 @@
+//! Functions which "mep" adds to the "midi" module on top of "koto_midi".
+
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{bounded, unbounded, RecvTimeoutError, Sender};
+use koto::runtime::{runtime_error, RuntimeError, Value, ValueList, ValueMap, ValueNumber};
+
+use crate::backend::OutputConnection;
+
+/// Where the messages sent through an output port go.
+pub enum Output {
+    /// A midi output port, virtual or connected to an existing one.
+    Port(Box<dyn OutputConnection>),
+    /// Messages are logged with the index of the output instead, when "mep" is run with "--dry-run".
+    DryRun {
+        index: usize,
+        log_to_main: Sender<String>,
+    },
+    /// Messages are dropped while the existing port it was connected to is unplugged.
+    Disconnected,
+}
+
+impl Output {
+    /// Sends a midi message and reports a failure to main.
+    ///
+    /// Returns whether the message is sent, it isn't while the port is disconnected.
+    fn send(&mut self, message: &[u8], error_to_main: &Sender<String>) -> bool {
+        match self {
+            Self::Port(connection) => {
+                if let Err(e) = connection.send(message) {
+                    // Main might be gone already while exiting, nothing to report to then.
+                    let _ = error_to_main
+                        .send(format!("Error when trying to send midi message: {}", e));
+                    return false;
+                }
+                true
+            }
+            Self::DryRun { index, log_to_main } => {
+                // The log is unbounded, every message is logged even if main falls behind.
+                // Main might be gone already while exiting, nothing to log to then.
+                let _ = log_to_main.send(format!("out {}: {}", index, hex_bytes(message)));
+                true
+            }
+            Self::Disconnected => false,
+        }
+    }
+
+    /// Sends the messages and empties the list, joined together at once where the port allows it.
+    ///
+    /// Returns whether they are all sent, `None` if there is nothing to send.
+    fn send_batch(
+        &mut self,
+        messages: &mut Vec<Vec<u8>>,
+        error_to_main: &Sender<String>,
+    ) -> Option<bool> {
+        let joined = JOINS_MESSAGES && messages.len() > 1 && matches!(self, Self::Port(_));
+        let sent = if joined {
+            Some(self.send(&batch_messages(messages), error_to_main))
+        } else {
+            // Every message is sent, even after one of them fails.
+            messages.iter().fold(None, |all_sent, message| {
+                let sent = self.send(message, error_to_main);
+                Some(all_sent.unwrap_or(true) && sent)
+            })
+        };
+        messages.clear();
+        sent
+    }
+}
+
+/// Whether a port accepts several messages joined in a single send.
+///
+/// CoreMIDI parses what is sent as a stream of bytes, ALSA and WinMM expect a single message per send.
+const JOINS_MESSAGES: bool = cfg!(target_os = "macos");
+
+/// What is queued for the thread which owns an output.
+enum Outgoing {
+    Message(Vec<u8>),
+    /// Dropped by the sender thread if it is over "--max-send-rate".
+    Limited(Vec<u8>),
+    /// Notified once every message queued before it is sent.
+    Flush(Sender<()>),
+    /// The messages queued after it are sent through this output instead.
+    Replace(Output),
+}
+
+/// Queues messages for an output which is owned by its own sender thread.
+///
+/// Sending never waits for a lock, so the script, the midi input callback and the clock
+/// don't stall each other while one of them is sending.
+#[derive(Clone)]
+pub struct OutputPort {
+    queue: Sender<Outgoing>,
+    active_notes: ActiveNotes,
+    /// Whether the last send succeeded, set by the sender thread.
+    healthy: Arc<AtomicBool>,
+}
+
+/// A token bucket which allows sending `rate` messages per second, in bursts of up to a second's worth.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    /// Whether messages are being dropped, it is only told once until sending catches up.
+    dropping: bool,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            refilled_at: Instant::now(),
+            dropping: false,
+        }
+    }
+
+    /// Takes a token for a message, returns `None` if it can be sent.
+    ///
+    /// Otherwise returns whether dropping has just started, so it is told once.
+    fn take(&mut self) -> Option<bool> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.dropping = false;
+            None
+        } else {
+            Some(!std::mem::replace(&mut self.dropping, true))
+        }
+    }
+}
+
+impl OutputPort {
+    /// Moves the output to a sender thread which lives as long as there are ports queueing to it.
+    ///
+    /// With `batch` the messages which queue up while a send is in progress are sent right after it,
+    /// without waiting for the queue again. On macOS they are also joined into a single send, see `batch_messages`.
+    pub fn spawn(
+        mut output: Output,
+        error_to_main: Sender<String>,
+        batch: bool,
+        active_notes: ActiveNotes,
+        max_send_rate: Option<f64>,
+    ) -> Self {
+        let (queue, outgoing) = unbounded();
+        let healthy = Arc::new(AtomicBool::new(!matches!(output, Output::Disconnected)));
+        let thread_healthy = Arc::clone(&healthy);
+        thread::spawn(move || {
+            let healthy = thread_healthy;
+            // Owned by the thread, so the ports don't share it behind a lock.
+            let mut limiter = max_send_rate.map(RateLimiter::new);
+            let mut messages = Vec::new();
+            let send_batch = |output: &mut Output, messages: &mut Vec<Vec<u8>>| {
+                if let Some(sent) = output.send_batch(messages, &error_to_main) {
+                    healthy.store(sent, Ordering::Relaxed);
+                }
+            };
+            while let Ok(first) = outgoing.recv() {
+                let queued = std::iter::once(first).chain(
+                    // Only what is already waiting, so nothing is delayed for a batch.
+                    batch.then(|| outgoing.try_iter()).into_iter().flatten(),
+                );
+                for queued in queued {
+                    match queued {
+                        Outgoing::Message(message) => messages.push(message),
+                        Outgoing::Limited(message) => {
+                            match limiter.as_mut().and_then(RateLimiter::take) {
+                                None => messages.push(message),
+                                Some(true) => {
+                                    // Main might be gone already while exiting, nothing to report to then.
+                                    let _ = error_to_main.send(
+                                        "Messages are sent faster than \"--max-send-rate\" allows, the ones over it are dropped."
+                                            .to_owned(),
+                                    );
+                                }
+                                Some(false) => {}
+                            }
+                        }
+                        Outgoing::Flush(flushed) => {
+                            send_batch(&mut output, &mut messages);
+                            let _ = flushed.send(());
+                        }
+                        Outgoing::Replace(replacement) => {
+                            send_batch(&mut output, &mut messages);
+                            output = replacement;
+                            // Nothing might be sent for a while, tell right away whether it can be.
+                            healthy
+                                .store(!matches!(output, Output::Disconnected), Ordering::Relaxed);
+                        }
+                    }
+                }
+                send_batch(&mut output, &mut messages);
+            }
+        });
+        Self {
+            queue,
+            active_notes,
+            healthy,
+        }
+    }
+
+    /// Whether the last message is sent without an error and the port is connected.
+    pub fn is_ok(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Queues a message to be sent, the message should be already validated.
+    ///
+    /// Messages over "--max-send-rate" are dropped by the sender thread, except the ones which `is_never_dropped`.
+    /// A dropped note is still tracked as active, a panic only sends an extra note off for it.
+    pub fn send(&self, message: &[u8]) {
+        if is_never_dropped(message) {
+            self.send_unlimited(message);
+        } else {
+            self.active_notes.track(message);
+            // The sender thread only stops after every port is dropped, so this can't fail.
+            let _ = self.queue.send(Outgoing::Limited(message.to_vec()));
+        }
+    }
+
+    /// Queues a message to be sent even over "--max-send-rate", e.g. to silence the output.
+    pub fn send_unlimited(&self, message: &[u8]) {
+        self.active_notes.track(message);
+        // The sender thread only stops after every port is dropped, so this can't fail.
+        let _ = self.queue.send(Outgoing::Message(message.to_vec()));
+    }
+
+    /// Sends the messages queued from now on through another output, e.g. after a port is reconnected.
+    pub fn replace(&self, output: Output) {
+        let _ = self.queue.send(Outgoing::Replace(output));
+    }
+
+    /// Blocks until the messages queued so far are sent, e.g. before exiting.
+    pub fn flush(&self) {
+        let (flushed_to_port, flushed) = bounded(1);
+        if self.queue.send(Outgoing::Flush(flushed_to_port)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+}
+
+/// Whether a message is sent even over "--max-send-rate".
+///
+/// Real time messages keep the clock going. Note offs and "all sound off" or "all notes off" control changes
+/// end what is already playing, dropping them would leave notes hanging.
+fn is_never_dropped(message: &[u8]) -> bool {
+    match message {
+        [status, ..] if *status >= 0xF8 => true,
+        [status, ..] if status & 0xF0 == 0x80 => true,
+        [status, _, 0] if status & 0xF0 == 0x90 => true,
+        [status, 120 | 123, _] if status & 0xF0 == 0xB0 => true,
+        _ => false,
+    }
+}
+
+/// Joins the messages to be sent at once, leaving out the status bytes which running status allows.
+///
+/// A channel message doesn't repeat the status byte of the channel message before it. System common
+/// messages, e.g. system exclusive, end the running status and real time messages don't affect it.
+pub fn batch_messages(messages: &[Vec<u8>]) -> Vec<u8> {
+    let mut batch = Vec::with_capacity(messages.iter().map(Vec::len).sum());
+    let mut running_status = None;
+    for message in messages {
+        match message.split_first() {
+            Some((&status, data)) if (0x80..=0xEF).contains(&status) => {
+                if running_status != Some(status) {
+                    batch.push(status);
+                    running_status = Some(status);
+                }
+                batch.extend_from_slice(data);
+            }
+            Some((&status, _)) => {
+                if status < 0xF8 {
+                    running_status = None;
+                }
+                batch.extend_from_slice(message);
+            }
+            None => {}
+        }
+    }
+    batch
+}
+
+/// A midi message which waits to be sent until `due`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScheduledMessage {
+    pub due: Instant,
+    pub message: Vec<u8>,
+}
+
+/// Scheduled messages, the earliest due one on top.
+pub type Schedule = Arc<Mutex<BinaryHeap<Reverse<ScheduledMessage>>>>;
+
+/// A function which "midi.every" calls repeatedly.
+pub struct Timer {
+    interval: Duration,
+    due: Instant,
+    callback: Value,
+}
+
+/// The "midi" module and "midi.listen" of the loaded script, so they are not looked up for every message.
+pub type ListenerCache = RefCell<Option<(ValueMap, Option<Value>)>>;
+
+/// Timers of the running script.
+pub type Timers = Arc<Mutex<Vec<Timer>>>;
+
+/// Beats per minute which "midi.send_after_beats" counts in, `None` while no tempo is known.
+pub type Tempo = Arc<Mutex<Option<f64>>>;
+
+/// The last received messages with their timestamps in microseconds.
+///
+/// It never grows beyond its capacity, the oldest message is dropped to make room for a new one.
+#[derive(Clone)]
+pub struct Recorder {
+    messages: Arc<Mutex<VecDeque<(u64, Vec<u8>)>>>,
+    capacity: usize,
+}
+
+impl Recorder {
+    /// A recorder with zero capacity records nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, stamp: u64, message: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        // `lock.unwrap()` will always succeed, the lock is never held while panicking.
+        #[allow(clippy::unwrap_used)]
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back((stamp, message.to_vec()));
+    }
+
+    /// Writes the recorded messages to a file, one per line as the timestamp followed by the bytes in hex.
+    /// e.g. "1234567 90 3C 7F"
+    pub fn dump_to(&self, path: &str) -> std::io::Result<()> {
+        let mut text = String::new();
+        // `lock.unwrap()` will always succeed, the lock is never held while panicking.
+        #[allow(clippy::unwrap_used)]
+        for (stamp, message) in self.messages.lock().unwrap().iter() {
+            let _ = writeln!(text, "{} {}", stamp, hex_bytes(message));
+        }
+        fs::write(path, text)
+    }
+}
+
+/// Notes which are held, counted by their channel and note number.
+///
+/// A note is held while it has more note ons than note offs, a note on with zero velocity counts as a
+/// note off. "All notes off" and "all sound off" release every note on their channel.
+#[derive(Clone, Default)]
+pub struct ActiveNotes {
+    counts: Arc<Mutex<BTreeMap<(u8, u8), usize>>>,
+}
+
+impl ActiveNotes {
+    pub fn track(&self, message: &[u8]) {
+        let (status, channel) = match message.first() {
+            Some(status) => (status & 0xF0, status & 0x0F),
+            None => return,
+        };
+        // The lock is only held to update the counts, never while sending.
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        let mut counts = self.counts.lock().unwrap();
+        match (status, message) {
+            (0x90, [_, note, velocity]) if *velocity > 0 => {
+                *counts.entry((channel, *note)).or_insert(0) += 1;
+            }
+            (0x80 | 0x90, [_, note, _]) => {
+                if let Some(count) = counts.get_mut(&(channel, *note)) {
+                    *count -= 1;
+                    if *count == 0 {
+                        counts.remove(&(channel, *note));
+                    }
+                }
+            }
+            (0xB0, [_, 120 | 123, _]) => {
+                counts.retain(|(held_channel, _), _| *held_channel != channel);
+            }
+            _ => {}
+        }
+    }
+
+    /// Held notes as channel and note pairs, ordered by channel and then note.
+    pub fn held(&self) -> Vec<(u8, u8)> {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        self.counts.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// Adds "midi.active_notes" which lists the held notes as "[channel, note]" pairs.
+///
+/// Both the received and the sent notes are tracked all the time, the ones in `sources` are listed.
+/// A note which is held in both is listed once.
+pub fn add_active_notes(midi_module: &mut ValueMap, sources: Vec<ActiveNotes>) {
+    midi_module.add_fn("active_notes", move |_, _| {
+        let mut held = sources
+            .iter()
+            .flat_map(ActiveNotes::held)
+            .collect::<Vec<(u8, u8)>>();
+        held.sort_unstable();
+        held.dedup();
+        let held = held
+            .into_iter()
+            .map(|(channel, note)| bytes_to_list(&[channel, note]))
+            .collect::<Vec<Value>>();
+        Ok(Value::List(ValueList::from_slice(&held)))
+    });
+}
+
+/// Adds "midi.dump" which writes the recorded messages to the given file.
+pub fn add_dump(midi_module: &mut ValueMap, recorder: &Recorder) {
+    let recorder = recorder.clone();
+    midi_module.add_fn("dump", move |vm, args| match vm.get_args(args) {
+        [Value::Str(path)] => {
+            if recorder.capacity == 0 {
+                return runtime_error!(
+                    "midi.dump - nothing is recorded, please run \"mep\" with \"--record <count>\""
+                );
+            }
+            recorder.dump_to(path).map(|_| Value::Empty).map_err(|err| {
+                RuntimeError::from(format!("midi.dump - couldn't write to {}: {}", path, err))
+            })
+        }
+        _ => runtime_error!("midi.dump - expected a file path as argument"),
+    });
+}
+
+/// Adds "midi.load_data" which reads a file next to the running script, e.g. a lookup table.
+///
+/// The path is relative to the folder of the script at `script_path` and may not lead out of `scripts_folder_path`.
+/// Files with a ".json" extension are parsed, others are returned as strings.
+pub fn add_load_data(
+    midi_module: &mut ValueMap,
+    script_path: Arc<Mutex<PathBuf>>,
+    scripts_folder_path: PathBuf,
+) {
+    midi_module.add_fn("load_data", move |vm, args| match vm.get_args(args) {
+        [Value::Str(relative_path)] => {
+            // `lock.unwrap()` will always succeed because no one panics while holding it.
+            #[allow(clippy::unwrap_used)]
+            let script_folder = script_path
+                .lock()
+                .unwrap()
+                .parent()
+                .map_or_else(|| scripts_folder_path.clone(), Path::to_path_buf);
+            let path = match resolve_data_path(
+                &script_folder,
+                &scripts_folder_path,
+                relative_path.as_str(),
+            ) {
+                Ok(path) => path,
+                Err(err) => return runtime_error!("midi.load_data - {}", err),
+            };
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) => {
+                    return runtime_error!(
+                        "midi.load_data - couldn't read {}: {}",
+                        path.display(),
+                        err
+                    )
+                }
+            };
+            let is_json = path
+                .extension()
+                .map_or(false, |extension| extension.eq_ignore_ascii_case("json"));
+            if is_json {
+                serde_json::from_str(&text).map(json_to_value).or_else(|err| {
+                    runtime_error!(
+                        "midi.load_data - {} is not valid JSON: {}",
+                        path.display(),
+                        err
+                    )
+                })
+            } else {
+                Ok(Value::Str(text.into()))
+            }
+        }
+        _ => runtime_error!(
+            "midi.load_data - expected a path relative to the script as argument. Ex. midi.load_data \"scales.json\""
+        ),
+    });
+}
+
+/// Joins `relative_path` to `script_folder`, making sure the file is in `scripts_folder_path`.
+fn resolve_data_path(
+    script_folder: &Path,
+    scripts_folder_path: &Path,
+    relative_path: &str,
+) -> Result<PathBuf, String> {
+    if Path::new(relative_path).is_absolute() {
+        return Err(format!(
+            "\"{}\" should be relative to the script",
+            relative_path
+        ));
+    }
+    let path = script_folder
+        .join(relative_path)
+        .canonicalize()
+        .map_err(|err| format!("couldn't find \"{}\": {}", relative_path, err))?;
+    // Symbolic links are resolved too, so neither ".." nor a link leads out of the scripts folder.
+    let scripts_folder_path = scripts_folder_path
+        .canonicalize()
+        .map_err(|err| err.to_string())?;
+    if path.starts_with(&scripts_folder_path) {
+        Ok(path)
+    } else {
+        Err(format!(
+            "\"{}\" is outside of the scripts folder",
+            relative_path
+        ))
+    }
+}
+
+/// Converts a parsed JSON value to a koto value, `null` becomes `()`.
+fn json_to_value(json: serde_json::Value) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Empty,
+        serde_json::Value::Bool(boolean) => Value::Bool(boolean),
+        serde_json::Value::Number(number) => number.as_i64().map_or_else(
+            || Value::Number(number.as_f64().unwrap_or(f64::NAN).into()),
+            |integer| Value::Number(integer.into()),
+        ),
+        serde_json::Value::String(text) => Value::Str(text.into()),
+        serde_json::Value::Array(values) => Value::List(ValueList::from_slice(
+            &values
+                .into_iter()
+                .map(json_to_value)
+                .collect::<Vec<Value>>(),
+        )),
+        serde_json::Value::Object(entries) => {
+            let mut map = ValueMap::new();
+            for (key, value) in entries {
+                map.add_value(&key, json_to_value(value));
+            }
+            Value::Map(map)
+        }
+    }
+}
+
+/// A channel filter is a bit mask where bit `n` allows channel `n`.
+pub const ALL_CHANNELS: u16 = 0xFFFF;
+
+/// Converts a list of koto values to a midi message.
+///
+/// Returns `None` if any of the values is not an integer in `0..=255` range.
+pub fn list_to_midi_message(message: &ValueList) -> Option<Vec<u8>> {
+    message
+        .data()
+        .iter()
+        .map(|value| match value {
+            #[allow(clippy::cast_sign_loss)]
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::as_conversions)]
+            // These are all fine because the value of `byte` is checked if it is in u8 range before.
+            Value::Number(ValueNumber::I64(byte)) if (0..=255).contains(byte) => Some(*byte as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Formats bytes in hex separated by spaces, e.g. "90 3C 7F".
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// State which belongs to the running script.
+#[derive(Clone)]
+pub struct ScriptState {
+    pub channel_filter: Arc<AtomicU16>,
+    pub clock: Arc<Mutex<Option<Clock>>>,
+    /// Whether incoming messages are forwarded to the first output port as they are.
+    pub thru: Arc<AtomicBool>,
+    thru_by_default: bool,
+    /// Whether choosing another script sends "all notes off" and "all sound off" first.
+    pub panic_on_switch: Arc<AtomicBool>,
+    panic_on_switch_by_default: bool,
+    /// Path of the running script, data files are loaded relative to it.
+    pub path: Arc<Mutex<PathBuf>>,
+    /// Set with "midi.clock_start", starts as the one given with "--bpm".
+    pub tempo: Tempo,
+    tempo_by_default: Option<f64>,
+    /// Set with "midi.reset", main rebuilds the runtime once the script returns.
+    pub reset_requested: Arc<AtomicBool>,
+    pub timers: Timers,
+    /// Emptied when the script is (re)loaded, it might define another "midi.listen".
+    pub listener_cache: ListenerCache,
+}
+
+impl ScriptState {
+    pub fn new(
+        thru_by_default: bool,
+        panic_on_switch_by_default: bool,
+        path: &str,
+        tempo_by_default: Option<f64>,
+    ) -> Self {
+        Self {
+            channel_filter: Arc::new(AtomicU16::new(ALL_CHANNELS)),
+            clock: Arc::new(Mutex::new(None)),
+            thru: Arc::new(AtomicBool::new(thru_by_default)),
+            thru_by_default,
+            panic_on_switch: Arc::new(AtomicBool::new(panic_on_switch_by_default)),
+            panic_on_switch_by_default,
+            path: Arc::new(Mutex::new(PathBuf::from(path))),
+            tempo: Arc::new(Mutex::new(tempo_by_default)),
+            tempo_by_default,
+            reset_requested: Arc::new(AtomicBool::new(false)),
+            timers: Timers::default(),
+            listener_cache: ListenerCache::default(),
+        }
+    }
+
+    /// Keeps the path of the script which is (re)loaded.
+    pub fn set_path(&self, path: &str) {
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        let mut script_path = self.path.lock().unwrap();
+        *script_path = PathBuf::from(path);
+    }
+
+    /// Brings the state back to what a freshly loaded script expects.
+    pub fn reset(&self) {
+        self.channel_filter.store(ALL_CHANNELS, Ordering::Relaxed);
+        self.thru.store(self.thru_by_default, Ordering::Relaxed);
+        self.panic_on_switch
+            .store(self.panic_on_switch_by_default, Ordering::Relaxed);
+        stop_clock(&self.clock);
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        let mut tempo = self.tempo.lock().unwrap();
+        *tempo = self.tempo_by_default;
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        self.timers.lock().unwrap().clear();
+        self.listener_cache.replace(None);
+    }
+}
+
+impl std::fmt::Debug for ScriptState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptState")
+            .field("channel_filter", &self.channel_filter)
+            .field("thru", &self.thru)
+            .field("panic_on_switch", &self.panic_on_switch)
+            .field("tempo", &self.tempo)
+            .finish()
+    }
+}
+
+/// A thread which sends midi timing clock messages until it is stopped.
+pub struct Clock {
+    /// Wakes the thread up to stop it, also in the middle of a long tick.
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Clock {
+    /// Starts sending the timing clock messages at the tempo, after a start message.
+    fn start(bpm: f64, port: OutputPort) -> Self {
+        let (stop, stopped) = bounded::<()>(1);
+        // 24 timing clock messages per quarter note.
+        let tick = Duration::from_secs_f64(60.0 / bpm / 24.0);
+        let handle = thread::spawn(move || {
+            port.send(&[0xFA]);
+            let start = Instant::now();
+            let mut ticks: u32 = 0;
+            loop {
+                port.send(&[0xF8]);
+                ticks = ticks.wrapping_add(1);
+                // Every tick is timed from the start so the time spent sending and
+                // oversleeping doesn't accumulate as drift.
+                let next_tick = start + tick * ticks;
+                let wait = next_tick.saturating_duration_since(Instant::now());
+                if !matches!(stopped.recv_timeout(wait), Err(RecvTimeoutError::Timeout)) {
+                    break;
+                }
+            }
+            port.send(&[0xFC]);
+        });
+        Self { stop, handle }
+    }
+}
+
+/// Checks that `number` is an integer in `0..=max` range and converts it to a byte.
+fn byte_in_range(
+    function_name: &str,
+    argument_name: &str,
+    number: &ValueNumber,
+    max: u8,
+) -> Result<u8, RuntimeError> {
+    if let ValueNumber::I64(integer) = number {
+        match u8::try_from(*integer) {
+            Ok(byte) if byte <= max => return Ok(byte),
+            _ => {}
+        }
+    }
+    runtime_error!(
+        "midi.{} - \"{}\" should be an integer in 0..={} range, got {}",
+        function_name,
+        argument_name,
+        max,
+        number
+    )
+}
+
+/// Names of the pitch classes starting from C, notes are named with sharps.
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Parses a note name like "C4", "F#3", "Bb5" or "C-1" to its note number.
+///
+/// Middle C, 60, is in the octave `middle_c_octave`, e.g. 4 makes it "C4" and 3 makes it "C3".
+/// The letter may be lowercase and followed by any number of "#" or "b".
+/// Returns `None` if it is not a note name or the note is out of `0..=127` range.
+pub fn note_number(name: &str, middle_c_octave: i64) -> Option<u8> {
+    let mut chars = name.trim().chars();
+    let pitch_class: i64 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave_start = rest.find(|c: char| c == '-' || c.is_ascii_digit())?;
+    let (accidentals, octave) = rest.split_at(octave_start);
+    let accidentals = accidentals.chars().try_fold(0_i64, |sum, c| match c {
+        '#' => Some(sum + 1),
+        'b' => Some(sum - 1),
+        _ => None,
+    })?;
+    let octave = octave.parse::<i64>().ok()?;
+    let number = octave
+        .checked_sub(middle_c_octave)?
+        .checked_mul(12)?
+        .checked_add(60 + pitch_class + accidentals)?;
+    u8::try_from(number).ok().filter(|note| *note <= 127)
+}
+
+/// Names a note number, e.g. 60 is "C4" if `middle_c_octave` is 4.
+pub fn note_name(note: u8, middle_c_octave: i64) -> String {
+    let octave = i64::from(note / 12) - 5 + middle_c_octave;
+    format!("{}{}", NOTE_NAMES[usize::from(note % 12)], octave)
+}
+
+/// Takes a note either as its number or as its name, see `note_number`.
+fn note_argument(
+    function_name: &str,
+    argument_name: &str,
+    value: &Value,
+    middle_c_octave: i64,
+) -> Result<u8, RuntimeError> {
+    match value {
+        Value::Number(number) => byte_in_range(function_name, argument_name, number, 127),
+        Value::Str(name) => note_number(name.as_str(), middle_c_octave).map_or_else(
+            || {
+                runtime_error!(
+                    "midi.{} - \"{}\" should be a note name in 0..=127 range like \"C4\", \"F#3\" or \"Bb5\", got \"{}\"",
+                    function_name,
+                    argument_name,
+                    name.as_str()
+                )
+            },
+            Ok,
+        ),
+        _ => runtime_error!(
+            "midi.{} - \"{}\" should be a note number or a note name, got {}",
+            function_name,
+            argument_name,
+            value
+        ),
+    }
+}
+
+/// Adds "midi.note_number" and "midi.note_name" which convert between note numbers and names.
+pub fn add_note_names(midi_module: &mut ValueMap, middle_c_octave: i64) {
+    midi_module.add_fn("note_number", move |vm, args| match vm.get_args(args) {
+        [name @ Value::Str(_)] => Ok(Value::Number(
+            i64::from(note_argument("note_number", "name", name, middle_c_octave)?).into(),
+        )),
+        _ => runtime_error!(
+            "midi.note_number - expected a note name as argument. Ex. midi.note_number \"C#4\""
+        ),
+    });
+
+    midi_module.add_fn("note_name", move |vm, args| match vm.get_args(args) {
+        [Value::Number(note)] => Ok(Value::Str(
+            note_name(
+                byte_in_range("note_name", "note", note, 127)?,
+                middle_c_octave,
+            )
+            .into(),
+        )),
+        _ => runtime_error!(
+            "midi.note_name - expected a note number as argument. Ex. midi.note_name 60"
+        ),
+    });
+}
+
+/// Adds "midi.note_on", "midi.note_off" and "midi.cc" which build and send channel voice messages.
+///
+/// Notes may be given by their names too, see `note_number`.
+pub fn add_channel_message_helpers(
+    midi_module: &mut ValueMap,
+    port: &OutputPort,
+    middle_c_octave: i64,
+) {
+    let helpers: [(&'static str, u8, [&'static str; 2]); 3] = [
+        ("note_on", 0x90, ["note", "velocity"]),
+        ("note_off", 0x80, ["note", "velocity"]),
+        ("cc", 0xB0, ["controller", "value"]),
+    ];
+
+    for (name, status, [data_1_name, data_2_name]) in helpers {
+        let port = port.clone();
+        midi_module.add_fn(name, move |vm, args| match vm.get_args(args) {
+            [Value::Number(channel), data_1 @ (Value::Number(_) | Value::Str(_)), Value::Number(data_2)]
+                if data_1_name == "note" || matches!(data_1, Value::Number(_)) =>
+            {
+                let message = [
+                    status | byte_in_range(name, "channel", channel, 15)?,
+                    note_argument(name, data_1_name, data_1, middle_c_octave)?,
+                    byte_in_range(name, data_2_name, data_2, 127)?,
+                ];
+                port.send(&message);
+                Ok(Value::Empty)
+            }
+            _ if data_1_name == "note" => runtime_error!(
+                "midi.{} - expected a channel, a note number or name and a velocity as arguments. Ex. midi.{} 0, \"C4\", 100",
+                name,
+                name
+            ),
+            _ => runtime_error!(
+                "midi.{} - expected three numbers as arguments (channel, {}, {})",
+                name,
+                data_1_name,
+                data_2_name
+            ),
+        });
+    }
+}
+
+/// Adds "midi.cc14" which sends a 14 bit value through a pair of controllers and "midi.read_cc14" which reads one.
+///
+/// Controllers 0..=31 carry the most significant 7 bits and the ones 32 above them the least significant 7 bits.
+/// The most significant one is sent first, as receivers may reset the least significant one when it arrives.
+pub fn add_cc14(midi_module: &mut ValueMap, port: &OutputPort) {
+    add_read_cc14(midi_module);
+    let port = port.clone();
+    midi_module.add_fn("cc14", move |vm, args| match vm.get_args(args) {
+        [Value::Number(channel), Value::Number(controller), Value::Number(value)] => {
+            let status = 0xB0 | byte_in_range("cc14", "channel", channel, 15)?;
+            let controller = byte_in_range("cc14", "controller", controller, 31)?;
+            let value = match value {
+                ValueNumber::I64(integer) => u16::try_from(*integer).ok().filter(|value| *value <= CC14_MAX),
+                ValueNumber::F64(_) => None,
+            };
+            let value = if let Some(value) = value {
+                value
+            } else {
+                return runtime_error!(
+                    "midi.cc14 - \"value\" should be an integer in 0..={} range",
+                    CC14_MAX
+                );
+            };
+            let (msb, lsb) = split_cc14(value);
+            port.send(&[status, controller, msb]);
+            port.send(&[status, controller + 32, lsb]);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!(
+            "midi.cc14 - expected three numbers as arguments (channel, controller, value). Ex. midi.cc14 0, 1, 8192"
+        ),
+    });
+}
+
+/// Adds "midi.read_cc14" which joins the values of a pair of received control change messages.
+///
+/// It is added on its own to the stages of a chain, which have no port to send "midi.cc14" through.
+pub fn add_read_cc14(midi_module: &mut ValueMap) {
+    midi_module.add_fn("read_cc14", |vm, args| match vm.get_args(args) {
+        [Value::List(msb_message), Value::List(lsb_message)] => {
+            match (
+                list_to_midi_message(msb_message).as_deref(),
+                list_to_midi_message(lsb_message).as_deref(),
+            ) {
+                (Some(&[msb_status, msb_controller, msb]), Some(&[lsb_status, lsb_controller, lsb]))
+                    if msb_status & 0xF0 == 0xB0
+                        && msb_status == lsb_status
+                        && msb_controller <= 31
+                        && lsb_controller == msb_controller + 32 =>
+                {
+                    Ok(Value::Number(i64::from(join_cc14(msb, lsb)).into()))
+                }
+                _ => runtime_error!(
+                    "midi.read_cc14 - expected two control change messages on the same channel, the second one's controller 32 above the first one's. Ex. midi.read_cc14 [176, 1, 64], [176, 33, 0]"
+                ),
+            }
+        }
+        _ => runtime_error!("midi.read_cc14 - expected two control change messages as arguments"),
+    });
+}
+
+/// Largest value which a pair of controllers carries.
+const CC14_MAX: u16 = 16383;
+
+/// Splits a 14 bit value into its most and least significant 7 bits.
+pub fn split_cc14(value: u16) -> (u8, u8) {
+    (
+        u8::try_from((value >> 7) & 0x7F).unwrap_or(0x7F),
+        u8::try_from(value & 0x7F).unwrap_or(0x7F),
+    )
+}
+
+/// Joins the most and least significant 7 bits of a 14 bit value.
+pub fn join_cc14(msb: u8, lsb: u8) -> u16 {
+    (u16::from(msb & 0x7F) << 7) | u16::from(lsb & 0x7F)
+}
+
+/// Adds "midi.output_ok" which tells whether an output port sends without errors, the first one if no index is given.
+pub fn add_output_ok(midi_module: &mut ValueMap, ports: &[OutputPort]) {
+    let ports = ports.to_vec();
+    midi_module.add_fn("output_ok", move |vm, args| match vm.get_args(args) {
+        [] => Ok(Value::Bool(ports[0].is_ok())),
+        [Value::Number(ValueNumber::I64(index))] => {
+            match usize::try_from(*index)
+                .ok()
+                .and_then(|index| ports.get(index))
+            {
+                Some(port) => Ok(Value::Bool(port.is_ok())),
+                None => runtime_error!(
+                    "midi.output_ok - there is no output port with index {}, there are {}",
+                    index,
+                    ports.len()
+                ),
+            }
+        }
+        _ => {
+            runtime_error!("midi.output_ok - expected no arguments or the index of an output port")
+        }
+    });
+}
+
+/// Adds "midi.thru" which turns forwarding incoming messages to the first output port on or off.
+///
+/// Messages are forwarded after "midi.listen" is called with them, unless it returns `false`.
+pub fn add_thru(midi_module: &mut ValueMap, thru: &Arc<AtomicBool>) {
+    let thru = Arc::clone(thru);
+    midi_module.add_fn("thru", move |vm, args| match vm.get_args(args) {
+        [Value::Bool(enabled)] => {
+            thru.store(*enabled, Ordering::Relaxed);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!("midi.thru - expected a boolean as argument"),
+    });
+}
+
+/// Adds "midi.panic_on_switch" which sets whether choosing another script sends "all notes off" and "all sound off" first.
+pub fn add_panic_on_switch(midi_module: &mut ValueMap, panic_on_switch: &Arc<AtomicBool>) {
+    let panic_on_switch = Arc::clone(panic_on_switch);
+    midi_module.add_fn("panic_on_switch", move |vm, args| match vm.get_args(args) {
+        [Value::Bool(enabled)] => {
+            panic_on_switch.store(*enabled, Ordering::Relaxed);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!("midi.panic_on_switch - expected a boolean as argument"),
+    });
+}
+
+/// Adds "midi.reset" which asks main to start the script over in a fresh runtime.
+///
+/// The runtime can't be replaced while the script runs in it, so it is only requested here.
+pub fn add_reset(midi_module: &mut ValueMap, reset_requested: &Arc<AtomicBool>) {
+    let reset_requested = Arc::clone(reset_requested);
+    midi_module.add_fn("reset", move |_, _| {
+        reset_requested.store(true, Ordering::Relaxed);
+        Ok(Value::Empty)
+    });
+}
+
+/// Adds "midi.channel_filter" which sets the channels that "midi.listen" receives messages from.
+///
+/// Passing an empty list resets the filter to all channels.
+pub fn add_channel_filter(midi_module: &mut ValueMap, channel_filter: &Arc<AtomicU16>) {
+    let channel_filter = Arc::clone(channel_filter);
+    midi_module.add_fn("channel_filter", move |vm, args| match vm.get_args(args) {
+        [Value::List(channels)] => {
+            let mut allowed_channels = 0_u16;
+            for channel in channels.data().iter() {
+                if let Value::Number(channel) = channel {
+                    allowed_channels |=
+                        1 << byte_in_range("channel_filter", "channel", channel, 15)?;
+                } else {
+                    return runtime_error!(
+                        "midi.channel_filter - expected a list of channel numbers, got {}",
+                        channel
+                    );
+                }
+            }
+            if allowed_channels == 0 {
+                allowed_channels = ALL_CHANNELS;
+            }
+            channel_filter.store(allowed_channels, Ordering::Relaxed);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!("midi.channel_filter - expected a list of channel numbers as argument"),
+    });
+}
+
+/// Decides if a message should reach "midi.listen" according to the channel filter.
+///
+/// Only channel voice and mode messages (0x80..=0xEF) carry a channel, in the low nibble of the status byte.
+/// System messages (0xF0..=0xFF) always pass.
+///
+/// A message which starts with a data byte (0x00..=0x7F) would rely on running status.
+/// Since the preceding status byte is not available here its channel can't be known, so it passes too.
+pub fn passes_channel_filter(message: &[u8], channel_filter: u16) -> bool {
+    match message.first() {
+        Some(status) if (0x80..0xF0).contains(status) => {
+            channel_filter & (1 << (status & 0x0F)) != 0
+        }
+        _ => true,
+    }
+}
+
+/// Adds "midi.after" which schedules a midi message to be sent after a delay in milliseconds.
+pub fn add_after(midi_module: &mut ValueMap, schedule: &Schedule) {
+    let schedule = Arc::clone(schedule);
+    midi_module.add_fn("after", move |vm, args| match vm.get_args(args) {
+        [Value::Number(delay_ms), Value::List(message)] => {
+            let delay_ms = f64::from(*delay_ms);
+            if !delay_ms.is_finite() || delay_ms < 0.0 {
+                return runtime_error!(
+                    "midi.after - delay should be a positive number of milliseconds, got {}",
+                    delay_ms
+                );
+            }
+            let message = if let Some(message) = list_to_midi_message(message) {
+                message
+            } else {
+                return runtime_error!(
+                    "midi.after - expected a list of bytes (integers ranged to 0..=255) as the message"
+                );
+            };
+
+            push_scheduled(&schedule, Duration::from_secs_f64(delay_ms / 1000.0), message);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!(
+            "midi.after - expected a delay in milliseconds and a list of bytes as arguments. Ex. midi.after 250, [144, 60, 100]"
+        ),
+    });
+}
+
+/// Adds "midi.send_after_beats" which schedules a midi message to be sent after a number of beats.
+///
+/// Beats are turned into time with the tempo at the moment of the call.
+/// A message which is already scheduled keeps its time if the tempo changes later.
+pub fn add_send_after_beats(midi_module: &mut ValueMap, schedule: &Schedule, tempo: &Tempo) {
+    let schedule = Arc::clone(schedule);
+    let tempo = Arc::clone(tempo);
+    midi_module.add_fn("send_after_beats", move |vm, args| match vm.get_args(args) {
+        [Value::Number(beats), Value::List(message)] => {
+            let beats = f64::from(*beats);
+            if !beats.is_finite() || beats < 0.0 {
+                return runtime_error!(
+                    "midi.send_after_beats - beats should be a positive number, got {}",
+                    beats
+                );
+            }
+            let message = if let Some(message) = list_to_midi_message(message) {
+                message
+            } else {
+                return runtime_error!(
+                    "midi.send_after_beats - expected a list of bytes (integers ranged to 0..=255) as the message"
+                );
+            };
+            // `lock.unwrap()` will always succeed because no one panics while holding it.
+            #[allow(clippy::unwrap_used)]
+            let bpm = if let Some(bpm) = *tempo.lock().unwrap() {
+                bpm
+            } else {
+                return runtime_error!(
+                    "midi.send_after_beats - the tempo isn't known, call \"midi.clock_start\" or run \"mep\" with \"--bpm <bpm>\""
+                );
+            };
+            push_scheduled(&schedule, Duration::from_secs_f64(beats * 60.0 / bpm), message);
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!(
+            "midi.send_after_beats - expected a number of beats and a list of bytes as arguments. Ex. midi.send_after_beats 0.5, [144, 60, 100]"
+        ),
+    });
+}
+
+fn push_scheduled(schedule: &Schedule, delay: Duration, message: Vec<u8>) {
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    schedule.lock().unwrap().push(Reverse(ScheduledMessage {
+        due: Instant::now() + delay,
+        message,
+    }));
+}
+
+/// Adds "midi.every" which calls a function repeatedly, every given milliseconds.
+pub fn add_every(midi_module: &mut ValueMap, timers: &Timers) {
+    let timers = Arc::clone(timers);
+    midi_module.add_fn("every", move |vm, args| match vm.get_args(args) {
+        [Value::Number(interval_ms), callback @ Value::Function(_)] => {
+            let interval_ms = f64::from(*interval_ms);
+            if !interval_ms.is_finite() || interval_ms < 1.0 {
+                return runtime_error!(
+                    "midi.every - interval should be at least a millisecond, got {}",
+                    interval_ms
+                );
+            }
+            let interval = Duration::from_secs_f64(interval_ms / 1000.0);
+            // `lock.unwrap()` will always succeed because no one panics while holding it.
+            #[allow(clippy::unwrap_used)]
+            timers.lock().unwrap().push(Timer {
+                interval,
+                due: Instant::now() + interval,
+                callback: callback.clone(),
+            });
+            Ok(Value::Empty)
+        }
+        _ => runtime_error!(
+            "midi.every - expected an interval in milliseconds and a function as arguments. Ex. midi.every 500, || midi.send [0xF8]"
+        ),
+    });
+}
+
+/// Time left until the earliest timer is due, `None` if there are no timers.
+pub fn next_timer_due_in(timers: &Timers) -> Option<Duration> {
+    let now = Instant::now();
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    timers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|timer| timer.due.saturating_duration_since(now))
+        .min()
+}
+
+/// The functions of the timers which are due, with the indexes of their timers.
+pub fn due_timers(timers: &Timers) -> Vec<(usize, Value)> {
+    let now = Instant::now();
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    timers
+        .lock()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .filter(|(_, timer)| timer.due <= now)
+        .map(|(index, timer)| (index, timer.callback.clone()))
+        .collect()
+}
+
+/// Moves the due timers to their next call, after their functions are called.
+///
+/// A timer whose function took longer than its interval skips the calls it missed instead of catching up with them.
+pub fn reschedule_timers(timers: &Timers) {
+    let now = Instant::now();
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    for timer in timers.lock().unwrap().iter_mut() {
+        if timer.due <= now {
+            timer.due += timer.interval;
+            if timer.due <= now {
+                timer.due = now + timer.interval;
+            }
+        }
+    }
+}
+
+/// Removes the timers at the given indexes, e.g. the ones whose functions threw an error.
+pub fn remove_timers(timers: &Timers, indexes: &[usize]) {
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    let mut timers = timers.lock().unwrap();
+    // From the last one, so the indexes of the rest stay the same.
+    for index in indexes.iter().rev() {
+        if *index < timers.len() {
+            timers.remove(*index);
+        }
+    }
+}
+
+/// Time left until the earliest scheduled message is due, `None` if nothing is scheduled.
+pub fn next_due_in(schedule: &Schedule) -> Option<Duration> {
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    schedule
+        .lock()
+        .unwrap()
+        .peek()
+        .map(|Reverse(next)| next.due.saturating_duration_since(Instant::now()))
+}
+
+/// Sends the scheduled messages which are due.
+pub fn send_due_messages(schedule: &Schedule, port: &OutputPort) {
+    let now = Instant::now();
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    let mut schedule = schedule.lock().unwrap();
+    // Only the earliest entry needs to be checked on every pass.
+    while schedule
+        .peek()
+        .map_or(false, |Reverse(next)| next.due <= now)
+    {
+        if let Some(Reverse(next)) = schedule.pop() {
+            port.send(&next.message);
+        }
+    }
+}
+
+/// Adds "midi.log" which sends its arguments to main to be shown below the list of scripts.
+pub fn add_log(midi_module: &mut ValueMap, log_to_main: Sender<String>) {
+    midi_module.add_fn("log", move |vm, args| {
+        let message = vm
+            .get_args(args)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(" ");
+        // The log is unbounded so this never blocks the script, nor drops a line.
+        // Main might be gone already while exiting, nothing to log to then.
+        let _ = log_to_main.send(message);
+        Ok(Value::Empty)
+    });
+}
+
+/// Adds "midi.now" which returns the microseconds elapsed since `started_at`, e.g. the start of "mep".
+///
+/// It is backed by a monotonic clock, so it never goes backwards.
+pub fn add_now(midi_module: &mut ValueMap, started_at: Instant) {
+    midi_module.add_fn("now", move |_, _| {
+        let elapsed = i64::try_from(started_at.elapsed().as_micros()).unwrap_or(i64::MAX);
+        Ok(Value::Number(elapsed.into()))
+    });
+}
+
+/// Makes the map of "midi.args" from the assignments given with "--set", e.g. "transpose=12".
+///
+/// Values which look like numbers become numbers, the others strings.
+pub fn make_args<'a>(assignments: impl Iterator<Item = &'a str>) -> ValueMap {
+    let mut args = ValueMap::default();
+    for assignment in assignments {
+        if let Some((key, value)) = assignment.split_once('=') {
+            args.add_value(key.trim(), parse_arg_value(value.trim()));
+        }
+    }
+    args
+}
+
+fn parse_arg_value(value: &str) -> Value {
+    if let Ok(number) = value.parse::<i64>() {
+        return Value::Number(number.into());
+    }
+    match value.parse::<f64>() {
+        // "nan" or "inf" are more likely meant as text.
+        Ok(number) if number.is_finite() => Value::Number(number.into()),
+        _ => Value::Str(value.into()),
+    }
+}
+
+/// Adds "midi.sysex" which sends a framed system exclusive message of any length at once.
+pub fn add_sysex(midi_module: &mut ValueMap, port: &OutputPort) {
+    let port = port.clone();
+    midi_module.add_fn("sysex", move |vm, args| match vm.get_args(args) {
+        [Value::List(message)] => {
+            let message = if let Some(message) = list_to_midi_message(message) {
+                message
+            } else {
+                return runtime_error!(
+                    "midi.sysex - expected a list of bytes (integers ranged to 0..=255)"
+                );
+            };
+            match (message.first(), message.last()) {
+                (Some(0xF0), Some(0xF7)) if message.len() >= 2 => {
+                    port.send(&message);
+                    Ok(Value::Empty)
+                }
+                _ => runtime_error!(
+                    "midi.sysex - a system exclusive message should start with 0xF0 and end with 0xF7"
+                ),
+            }
+        }
+        _ => runtime_error!("midi.sysex - expected a list of bytes as argument"),
+    });
+}
+
+/// Adds "midi.send" which sends a message through the first output or the one at the given index.
+///
+/// A wrong argument doesn't stop the script, it is reported to main instead.
+pub fn add_send(midi_module: &mut ValueMap, ports: &[OutputPort], error_to_main: Sender<String>) {
+    let ports = ports.to_vec();
+    let send_error_message = "Error calling \"midi.send\": Wrong argument type, please try to use a list of bytes (integers ranged to 0..=255) as an argument, optionally preceded by an output port index. Ex. [144, 65, 127] or 1, [144, 65, 127]";
+    midi_module.add_fn("send", move |vm, args| {
+        let (port_index, message) = match vm.get_args(args) {
+            [Value::List(message)] => (0, message),
+            [Value::Number(ValueNumber::I64(port_index)), Value::List(message)] => {
+                (*port_index, message)
+            }
+            _ => {
+                return error_to_main
+                    .send(send_error_message.to_owned())
+                    .map(|_| Value::Empty)
+                    .map_err(|err| RuntimeError::from(err.to_string()));
+            }
+        };
+
+        let port = if let Some(port) = usize::try_from(port_index)
+            .ok()
+            .and_then(|port_index| ports.get(port_index))
+        {
+            port
+        } else {
+            // Output port index is out of range.
+            return error_to_main
+                .send(send_error_message.to_owned())
+                .map(|_| Value::Empty)
+                .map_err(|err| RuntimeError::from(err.to_string()));
+        };
+
+        if let Some(midi_message_to_send) = list_to_midi_message(message) {
+            port.send(&midi_message_to_send);
+        } else {
+            // Main might be gone already while exiting, nothing to report to then.
+            let _ = error_to_main.send(send_error_message.into());
+        }
+        Ok(Value::Empty)
+    });
+}
+
+/// Adds "midi.send_all" which sends a list of midi messages in order, e.g. the notes of a chord.
+///
+/// Every message is validated before anything is sent.
+pub fn add_send_all(midi_module: &mut ValueMap, ports: &[OutputPort]) {
+    let ports = ports.to_vec();
+    midi_module.add_fn("send_all", move |vm, args| {
+        let (port_index, messages) = match vm.get_args(args) {
+            [Value::List(messages)] => (0, messages),
+            [Value::Number(ValueNumber::I64(port_index)), Value::List(messages)] => {
+                (*port_index, messages)
+            }
+            _ => {
+                return runtime_error!(
+                    "midi.send_all - expected a list of messages as argument. Ex. midi.send_all [[144, 60, 100], [144, 64, 100]]"
+                )
+            }
+        };
+        let port = if let Some(port) = usize::try_from(port_index)
+            .ok()
+            .and_then(|port_index| ports.get(port_index))
+        {
+            port
+        } else {
+            return runtime_error!(
+                "midi.send_all - output port index should be in 0..{} range, got {}",
+                ports.len(),
+                port_index
+            );
+        };
+
+        let mut midi_messages = Vec::with_capacity(messages.len());
+        for (index, message) in messages.data().iter().enumerate() {
+            match message {
+                Value::List(message) => {
+                    if let Some(message) = list_to_midi_message(message) {
+                        midi_messages.push(message);
+                    } else {
+                        return runtime_error!(
+                            "midi.send_all - message at index {} should be a list of bytes (integers ranged to 0..=255)",
+                            index
+                        );
+                    }
+                }
+                _ => {
+                    return runtime_error!(
+                        "midi.send_all - message at index {} should be a list, got {}",
+                        index,
+                        message
+                    )
+                }
+            }
+        }
+
+        for message in &midi_messages {
+            port.send(message);
+        }
+        Ok(Value::Empty)
+    });
+}
+
+/// Adds "midi.send_to" which sends a message through the output given its name in "--outputs".
+pub fn add_send_to(midi_module: &mut ValueMap, output_names: &[String], ports: &[OutputPort]) {
+    let named_ports: HashMap<String, OutputPort> = output_names
+        .iter()
+        .cloned()
+        .zip(ports.iter().cloned())
+        .collect();
+    let known_names = if output_names.is_empty() {
+        "none, name them with \"--outputs <name,name..>\"".to_owned()
+    } else {
+        output_names.join(", ")
+    };
+    midi_module.add_fn("send_to", move |vm, args| match vm.get_args(args) {
+        [Value::Str(name), Value::List(message)] => {
+            let port = if let Some(port) = named_ports.get(name.as_str()) {
+                port
+            } else {
+                return runtime_error!(
+                    "midi.send_to - there is no output named \"{}\", known outputs are: {}",
+                    name.as_str(),
+                    known_names
+                );
+            };
+            if let Some(message) = list_to_midi_message(message) {
+                port.send(&message);
+                Ok(Value::Empty)
+            } else {
+                runtime_error!(
+                    "midi.send_to - expected a list of bytes (integers ranged to 0..=255) as the message"
+                )
+            }
+        }
+        _ => runtime_error!(
+            "midi.send_to - expected an output name and a list of bytes as arguments. Ex. midi.send_to \"to-daw\", [144, 60, 100]"
+        ),
+    });
+}
+
+/// Stops the running clock if there is one and waits for it to send its stop message.
+pub fn stop_clock(clock: &Mutex<Option<Clock>>) {
+    // `lock.unwrap()` will always succeed because no one panics while holding it.
+    #[allow(clippy::unwrap_used)]
+    if let Some(clock) = clock.lock().unwrap().take() {
+        let _ = clock.stop.send(());
+        // The clock thread doesn't panic, it would only stop sending if it did.
+        let _ = clock.handle.join();
+    }
+}
+
+/// Adds "midi.clock_start" and "midi.clock_stop" which run a midi clock at the given tempo.
+///
+/// The tempo is kept after the clock is stopped, "midi.send_after_beats" counts in it.
+pub fn add_clock(
+    midi_module: &mut ValueMap,
+    clock: &Arc<Mutex<Option<Clock>>>,
+    tempo: &Tempo,
+    port: &OutputPort,
+) {
+    {
+        let clock = Arc::clone(clock);
+        let tempo = Arc::clone(tempo);
+        let port = port.clone();
+        midi_module.add_fn("clock_start", move |vm, args| match vm.get_args(args) {
+            [Value::Number(bpm)] => {
+                let bpm = f64::from(*bpm);
+                if !bpm.is_finite() || bpm <= 0.0 {
+                    return runtime_error!(
+                        "midi.clock_start - tempo should be a positive number of beats per minute, got {}",
+                        bpm
+                    );
+                }
+                // Restart if there is already a clock running.
+                stop_clock(&clock);
+                // `lock.unwrap()` will always succeed because no one panics while holding it.
+                #[allow(clippy::unwrap_used)]
+                tempo.lock().unwrap().replace(bpm);
+
+                // `lock.unwrap()` will always succeed because no one panics while holding it.
+                #[allow(clippy::unwrap_used)]
+                clock
+                    .lock()
+                    .unwrap()
+                    .replace(Clock::start(bpm, port.clone()));
+                Ok(Value::Empty)
+            }
+            _ => runtime_error!("midi.clock_start - expected the tempo in beats per minute as argument"),
+        });
+    }
+
+    let clock = Arc::clone(clock);
+    midi_module.add_fn("clock_stop", move |_, _| {
+        stop_clock(&clock);
+        Ok(Value::Empty)
+    });
+}
+
+/// Sends "all notes off" and "all sound off" on all channels of the given ports.
+///
+/// Returns once they are sent, so it is safe to exit right after.
+pub fn send_panic(ports: &[OutputPort]) {
+    for port in ports {
+        for channel in 0..16_u8 {
+            // Control change 123 is "all notes off" and 120 is "all sound off".
+            port.send_unlimited(&[0xB0 | channel, 123, 0]);
+            port.send_unlimited(&[0xB0 | channel, 120, 0]);
+        }
+    }
+    for port in ports {
+        port.flush();
+    }
+}
+
+/// Adds "midi.panic" which silences everything on all output ports.
+pub fn add_panic(midi_module: &mut ValueMap, ports: &[OutputPort]) {
+    let ports = ports.to_vec();
+    midi_module.add_fn("panic", move |_, _| {
+        send_panic(&ports);
+        Ok(Value::Empty)
+    });
+}
+
+/// Names the type of a midi message like the members of "midi.types".
+///
+/// Returns `None` if the message is malformed, e.g. it has a wrong length for its status byte,
+/// a data byte out of `0..=127` range or if it doesn't start with a status byte.
+/// A note on with zero velocity is a note off.
+pub fn message_type(message: &[u8]) -> Option<&'static str> {
+    let (status, data) = message.split_first()?;
+    let (message_type, data_length) = match status {
+        0x80..=0x8F => ("note_off", 2),
+        0x90..=0x9F if data.get(1) == Some(&0) => ("note_off", 2),
+        0x90..=0x9F => ("note_on", 2),
+        0xA0..=0xAF => ("poly_after_touch", 2),
+        0xB0..=0xBF => ("control_change", 2),
+        0xC0..=0xCF => ("program_change", 1),
+        0xD0..=0xDF => ("after_touch", 1),
+        0xE0..=0xEF => ("pitch_bend", 2),
+        0xF0 => {
+            // Any length is fine as long as it is framed.
+            return match data.split_last() {
+                Some((0xF7, payload)) if payload.iter().all(|byte| *byte < 0x80) => {
+                    Some("system_exclusive")
+                }
+                _ => None,
+            };
+        }
+        0xF1 => ("time_code_quarter_frame", 1),
+        0xF2 => ("song_position", 2),
+        0xF3 => ("song_select", 1),
+        0xF6 => ("tune_request", 0),
+        0xF7 => ("end_of_exclusive", 0),
+        0xF8 => ("timing_clock", 0),
+        0xFA => ("start", 0),
+        0xFB => ("continue", 0),
+        0xFC => ("stop", 0),
+        0xFE => ("active_sensing", 0),
+        0xFF => ("reset", 0),
+        0xF4 | 0xF5 | 0xF9 | 0xFD => ("undefined", 0),
+        // A data byte, running status is not supported here.
+        _ => return None,
+    };
+    if data.len() == data_length && data.iter().all(|byte| *byte < 0x80) {
+        Some(message_type)
+    } else {
+        None
+    }
+}
+
+/// Finds the handler the script defined for the type of a channel voice message, e.g. "midi.on_note_on".
+///
+/// Returns the name of the handler, the handler and the arguments to call it with.
+/// Arguments are the channel followed by the data bytes, pitch bend's data bytes are combined to a single `0..=16383` value.
+pub fn find_type_handler(
+    midi_module_map: &ValueMap,
+    message: &[u8],
+) -> Option<(&'static str, Value, Vec<Value>)> {
+    let handler_name = match message_type(message)? {
+        "note_off" => "on_note_off",
+        "note_on" => "on_note_on",
+        "poly_after_touch" => "on_poly_after_touch",
+        "control_change" => "on_cc",
+        "program_change" => "on_program_change",
+        "after_touch" => "on_after_touch",
+        "pitch_bend" => "on_pitch_bend",
+        _ => return None,
+    };
+    let handler = match midi_module_map.data().get_with_string(handler_name) {
+        Some(handler @ Value::Function(_)) => handler.clone(),
+        _ => return None,
+    };
+
+    let (status, data) = message.split_first()?;
+    let mut args = vec![Value::Number(i64::from(status & 0x0F).into())];
+    match data {
+        [lsb, msb] if handler_name == "on_pitch_bend" => {
+            args.push(Value::Number(
+                ((i64::from(*msb) << 7) | i64::from(*lsb)).into(),
+            ));
+        }
+        _ => args.extend(
+            data.iter()
+                .map(|byte| Value::Number(i64::from(*byte).into())),
+        ),
+    }
+    Some((handler_name, handler, args))
+}
+
+/// Converts the only argument of a helper to a well formed midi message.
+fn message_argument(function_name: &str, args: &[Value]) -> Result<Vec<u8>, RuntimeError> {
+    match args {
+        [Value::List(message)] => match list_to_midi_message(message) {
+            Some(message) if message_type(&message).is_some() => Ok(message),
+            _ => runtime_error!(
+                "midi.{} - expected a well formed midi message",
+                function_name
+            ),
+        },
+        _ => runtime_error!(
+            "midi.{} - expected a list of bytes as argument",
+            function_name
+        ),
+    }
+}
+
+/// Adds "midi.message_type", "midi.channel" and "midi.data" which inspect raw midi messages.
+pub fn add_message_helpers(midi_module: &mut ValueMap) {
+    midi_module.add_fn("message_type", |vm, args| {
+        let message = message_argument("message_type", vm.get_args(args))?;
+        // The message is checked to be well formed, so it always has a type.
+        Ok(Value::Str(
+            message_type(&message).unwrap_or("malformed").into(),
+        ))
+    });
+
+    midi_module.add_fn("channel", |vm, args| {
+        let message = message_argument("channel", vm.get_args(args))?;
+        match message[0] {
+            status @ 0x80..=0xEF => Ok(Value::Number(i64::from(status & 0x0F).into())),
+            _ => runtime_error!("midi.channel - only channel messages have a channel"),
+        }
+    });
+
+    midi_module.add_fn("data", |vm, args| {
+        let message = message_argument("data", vm.get_args(args))?;
+        // Data bytes are the ones after the status byte, without the end of a system exclusive message.
+        let data = match message.as_slice() {
+            [0xF0, payload @ .., 0xF7] => payload,
+            [_, data @ ..] => data,
+            [] => &[],
+        };
+        Ok(bytes_to_list(data))
+    });
+}
+
+/// Makes a list of koto values from bytes.
+fn bytes_to_list(bytes: &[u8]) -> Value {
+    let values = bytes
+        .iter()
+        .map(|byte| Value::Number(byte.into()))
+        .collect::<Vec<Value>>();
+    Value::List(ValueList::from_slice(&values))
+}
+
+/// Shifts the note of a note on, note off or poly after touch message by `semitones`.
+///
+/// Other messages are returned as they are. A note which would go out of `0..=127` range
+/// is clamped to it, or the message is dropped and `None` is returned if `drop_out_of_range` is set.
+pub fn transpose(message: &[u8], semitones: i64, drop_out_of_range: bool) -> Option<Vec<u8>> {
+    match (message_type(message), message) {
+        (Some("note_on" | "note_off" | "poly_after_touch"), [status, note, rest @ ..]) => {
+            let note = transpose_note(*note, semitones, drop_out_of_range)?;
+            let mut transposed_message = vec![*status, note];
+            transposed_message.extend_from_slice(rest);
+            Some(transposed_message)
+        }
+        _ => Some(message.to_vec()),
+    }
+}
+
+/// Shifts a note by `semitones`, clamping it to `0..=127` range or returning `None` if `drop_out_of_range` is set.
+fn transpose_note(note: u8, semitones: i64, drop_out_of_range: bool) -> Option<u8> {
+    let transposed = i64::from(note).saturating_add(semitones);
+    match u8::try_from(transposed) {
+        Ok(note) if note <= 127 => Some(note),
+        _ if drop_out_of_range => None,
+        _ => Some(u8::try_from(transposed.clamp(0, 127)).unwrap_or(127)),
+    }
+}
+
+/// Reshapes the velocity of a note on message as `127 * (velocity / 127) ^ gamma`.
+///
+/// A `gamma` below 1 makes soft notes louder, above 1 makes them softer.
+/// A note on never becomes a note off, velocities above zero stay at least 1. Other messages are returned as they are.
+pub fn velocity_curve(message: &[u8], gamma: f64) -> Vec<u8> {
+    match (message_type(message), message) {
+        (Some("note_on"), [status, note, velocity]) => {
+            let curved = (f64::from(*velocity) / 127.0).powf(gamma) * 127.0;
+            #[allow(clippy::cast_sign_loss)]
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::as_conversions)]
+            // This is fine because the value is clamped to the range of a data byte before.
+            let velocity = curved.round().clamp(1.0, 127.0) as u8;
+            vec![*status, *note, velocity]
+        }
+        _ => message.to_vec(),
+    }
+}
+
+/// Maps `value` linearly from `in_lo..in_hi` to `out_lo..out_hi`.
+///
+/// Either range may be descending. With `clamp` the result never leaves the output range.
+/// Returns `None` if the input range has no width.
+pub fn map_range(value: f64, input: (f64, f64), output: (f64, f64), clamp: bool) -> Option<f64> {
+    let (in_lo, in_hi) = input;
+    let (out_lo, out_hi) = output;
+    let width = in_hi - in_lo;
+    if width == 0.0 {
+        return None;
+    }
+    let mapped = out_lo + (value - in_lo) / width * (out_hi - out_lo);
+    Some(if clamp {
+        mapped.clamp(out_lo.min(out_hi), out_lo.max(out_hi))
+    } else {
+        mapped
+    })
+}
+
+/// Adds "midi.transpose" and "midi.velocity_curve" which return a transformed copy of a message
+/// and "midi.map_range" which scales a number from one range to another.
+///
+/// "midi.transpose" also transposes a single note given by its number or name, see `note_number`.
+pub fn add_transforms(midi_module: &mut ValueMap, middle_c_octave: i64) {
+    midi_module.add_fn("transpose", move |vm, args| {
+        let (transposed, semitones, drop_out_of_range) = match vm.get_args(args) {
+            [transposed, Value::Number(ValueNumber::I64(semitones))] => {
+                (transposed, *semitones, false)
+            }
+            [transposed, Value::Number(ValueNumber::I64(semitones)), Value::Str(mode)] => {
+                match mode.as_str() {
+                    "clamp" => (transposed, *semitones, false),
+                    "drop" => (transposed, *semitones, true),
+                    _ => {
+                        return runtime_error!(
+                            "midi.transpose - mode should be \"clamp\" or \"drop\", got \"{}\"",
+                            mode.as_str()
+                        )
+                    }
+                }
+            }
+            _ => {
+                return runtime_error!(
+                    "midi.transpose - expected a list of bytes or a note and an integer amount of semitones as arguments. Ex. midi.transpose [144, 60, 100], 12"
+                )
+            }
+        };
+        let message = match transposed {
+            Value::List(message) => message,
+            note => {
+                let note = note_argument("transpose", "note", note, middle_c_octave)?;
+                return Ok(transpose_note(note, semitones, drop_out_of_range)
+                    .map_or(Value::Empty, |note| Value::Number(i64::from(note).into())));
+            }
+        };
+        let message = if let Some(message) = list_to_midi_message(message) {
+            message
+        } else {
+            return runtime_error!(
+                "midi.transpose - expected a list of bytes (integers ranged to 0..=255) as the message"
+            );
+        };
+        Ok(transpose(&message, semitones, drop_out_of_range)
+            .map_or(Value::Empty, |message| bytes_to_list(&message)))
+    });
+
+    midi_module.add_fn("velocity_curve", |vm, args| match vm.get_args(args) {
+        [Value::List(message), Value::Number(gamma)] => {
+            let gamma = f64::from(*gamma);
+            if !gamma.is_finite() || gamma <= 0.0 {
+                return runtime_error!(
+                    "midi.velocity_curve - gamma should be a positive number, got {}",
+                    gamma
+                );
+            }
+            if let Some(message) = list_to_midi_message(message) {
+                Ok(bytes_to_list(&velocity_curve(&message, gamma)))
+            } else {
+                runtime_error!(
+                    "midi.velocity_curve - expected a list of bytes (integers ranged to 0..=255) as the message"
+                )
+            }
+        }
+        _ => runtime_error!(
+            "midi.velocity_curve - expected a list of bytes and a gamma as arguments. Ex. midi.velocity_curve [144, 60, 100], 0.5"
+        ),
+    });
+
+    midi_module.add_fn("map_range", |vm, args| {
+        let (numbers, clamp) = match vm.get_args(args) {
+            [Value::Number(value), Value::Number(in_lo), Value::Number(in_hi), Value::Number(out_lo), Value::Number(out_hi)] => {
+                ([*value, *in_lo, *in_hi, *out_lo, *out_hi], false)
+            }
+            [Value::Number(value), Value::Number(in_lo), Value::Number(in_hi), Value::Number(out_lo), Value::Number(out_hi), Value::Str(mode)] => {
+                match mode.as_str() {
+                    "clamp" => ([*value, *in_lo, *in_hi, *out_lo, *out_hi], true),
+                    "extend" => ([*value, *in_lo, *in_hi, *out_lo, *out_hi], false),
+                    _ => {
+                        return runtime_error!(
+                            "midi.map_range - mode should be \"clamp\" or \"extend\", got \"{}\"",
+                            mode.as_str()
+                        )
+                    }
+                }
+            }
+            _ => {
+                return runtime_error!(
+                    "midi.map_range - expected a value, the input range and the output range as numbers. Ex. midi.map_range 64, 0, 127, 0.0, 1.0"
+                )
+            }
+        };
+        let [value, in_lo, in_hi, out_lo, out_hi] = numbers.map(f64::from);
+        let mapped = if let Some(mapped) =
+            map_range(value, (in_lo, in_hi), (out_lo, out_hi), clamp)
+        {
+            mapped
+        } else {
+            return runtime_error!(
+                "midi.map_range - the input range should have a width, got {} to {}",
+                in_lo,
+                in_hi
+            );
+        };
+        // Integer ranges give integers, e.g. for the data bytes of a message.
+        if numbers
+            .iter()
+            .all(|number| matches!(number, ValueNumber::I64(_)))
+        {
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::as_conversions)]
+            // This is fine because the result is in the output range unless it is extended on purpose.
+            Ok(Value::Number((mapped.round() as i64).into()))
+        } else {
+            Ok(Value::Number(mapped.into()))
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MidiBackend, Mock};
+
+    /// An output of a mock backend, see `sent_messages` for what is sent through it.
+    fn mock_output(mock: &Mock) -> Output {
+        match mock.open_output("mep_out", None) {
+            Ok((connection, _)) => Output::Port(connection),
+            Err(err) => panic!("the mock output isn't opened: {}", err),
+        }
+    }
+
+    /// A port whose sent messages are recorded by the returned mock, after a flush they are all there.
+    fn mock_port_limited_to(max_send_rate: Option<f64>) -> (OutputPort, Mock) {
+        let mock = Mock::default();
+        let (error_to_main, _) = unbounded();
+        let port = OutputPort::spawn(
+            mock_output(&mock),
+            error_to_main,
+            false,
+            ActiveNotes::default(),
+            max_send_rate,
+        );
+        (port, mock)
+    }
+
+    fn mock_port() -> (OutputPort, Mock) {
+        mock_port_limited_to(None)
+    }
+
+    fn sent_messages(mock: &Mock) -> Vec<Vec<u8>> {
+        mock.sent("mep_out")
+    }
+
+    fn disconnected_port() -> OutputPort {
+        let (error_to_main, _) = unbounded();
+        OutputPort::spawn(
+            Output::Disconnected,
+            error_to_main,
+            false,
+            ActiveNotes::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn a_slow_clock_stops_without_waiting_for_its_tick() {
+        // A tick is 250 seconds long at this tempo.
+        let clock = Mutex::new(Some(Clock::start(0.01, disconnected_port())));
+        let stopping_at = Instant::now();
+        stop_clock(&clock);
+        assert!(stopping_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn message_types_are_named_like_the_members_of_midi_types() {
+        assert_eq!(message_type(&[0x90, 60, 100]), Some("note_on"));
+        assert_eq!(message_type(&[0x90, 60, 0]), Some("note_off"));
+        assert_eq!(message_type(&[0xB3, 1, 64]), Some("control_change"));
+        assert_eq!(message_type(&[0xB3, 1]), None);
+        assert_eq!(message_type(&[60, 100]), None);
+    }
+
+    /// Runs `script` with "midi.transpose", "midi.velocity_curve" and "midi.map_range" in the prelude.
+    fn run_with_transforms(script: &str) -> Result<Value, String> {
+        let mut midi_module = ValueMap::new();
+        add_transforms(&mut midi_module, 4);
+        let mut runtime = koto::Koto::default();
+        runtime.prelude().add_map("midi", midi_module);
+        let chunk = runtime.compile(script).map_err(|err| err.to_string())?;
+        runtime.run_chunk(chunk).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn map_range_maps_clamps_and_extends() {
+        // Value, input range, output range, clamp and the expected result.
+        let table = [
+            (64.0, (0.0, 127.0), (0.0, 1.0), false, Some(64.0 / 127.0)),
+            (0.0, (0.0, 127.0), (127.0, 0.0), false, Some(127.0)),
+            (127.0, (127.0, 0.0), (0.0, 100.0), false, Some(0.0)),
+            (0.25, (1.0, 0.0), (-1.0, 1.0), false, Some(0.5)),
+            (200.0, (0.0, 127.0), (0.0, 100.0), true, Some(100.0)),
+            (
+                200.0,
+                (0.0, 127.0),
+                (0.0, 100.0),
+                false,
+                Some(20000.0 / 127.0),
+            ),
+            (-10.0, (0.0, 127.0), (127.0, 0.0), true, Some(127.0)),
+            (300.0, (0.0, 127.0), (127.0, 0.0), true, Some(0.0)),
+            (5.0, (3.0, 3.0), (0.0, 1.0), false, None),
+            (5.0, (3.0, 3.0), (0.0, 1.0), true, None),
+        ];
+        for (value, input, output, clamp, expected) in table {
+            let mapped = map_range(value, input, output, clamp);
+            match (mapped, expected) {
+                (Some(mapped), Some(expected)) => assert!(
+                    (mapped - expected).abs() < 1e-9,
+                    "{} from {:?} to {:?} is {}, not {}",
+                    value,
+                    input,
+                    output,
+                    mapped,
+                    expected
+                ),
+                (None, None) => {}
+                _ => panic!(
+                    "{} from {:?} to {:?} is {:?}, not {:?}",
+                    value, input, output, mapped, expected
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn map_range_gives_integers_only_for_integer_ranges() {
+        assert!(matches!(
+            run_with_transforms("import midi\nmidi.map_range 64, 0, 127, 0, 100\n"),
+            Ok(Value::Number(ValueNumber::I64(50)))
+        ));
+        assert!(matches!(
+            run_with_transforms("import midi\nmidi.map_range 64, 0, 128, 0.0, 1.0\n"),
+            Ok(Value::Number(ValueNumber::F64(mapped))) if (mapped - 0.5).abs() < 1e-9
+        ));
+        assert!(matches!(
+            run_with_transforms("import midi\nmidi.map_range 200, 0, 127, 0, 100, \"clamp\"\n"),
+            Ok(Value::Number(ValueNumber::I64(100)))
+        ));
+        assert!(run_with_transforms("import midi\nmidi.map_range 5, 3, 3, 0, 1\n").is_err());
+    }
+
+    #[test]
+    fn every_note_name_parses_back_to_its_number() {
+        for middle_c_octave in [3, 4, 5] {
+            for note in 0..=127_u8 {
+                let name = note_name(note, middle_c_octave);
+                assert_eq!(note_number(&name, middle_c_octave), Some(note), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn sharps_and_flats_are_the_same_note() {
+        assert_eq!(note_number("C#4", 4), Some(61));
+        assert_eq!(note_number("Db4", 4), Some(61));
+        assert_eq!(note_number("db4", 4), Some(61));
+        assert_eq!(note_number("Bb3", 4), Some(58));
+        assert_eq!(note_number("A#3", 4), Some(58));
+        assert_eq!(note_number("B#3", 4), Some(60));
+        assert_eq!(note_number("Cb4", 4), Some(59));
+        assert_eq!(note_number("F##4", 4), Some(67));
+        assert_eq!(note_name(61, 4), "C#4");
+    }
+
+    #[test]
+    fn middle_c_moves_with_its_octave() {
+        assert_eq!(note_number("C4", 4), Some(60));
+        assert_eq!(note_number("C3", 3), Some(60));
+        assert_eq!(note_number("C5", 5), Some(60));
+        assert_eq!(note_number("C-1", 4), Some(0));
+        assert_eq!(note_number("C-2", 3), Some(0));
+        assert_eq!(note_number("G9", 4), Some(127));
+        assert_eq!(note_name(60, 3), "C3");
+        assert_eq!(note_name(0, 4), "C-1");
+        assert_eq!(note_name(127, 4), "G9");
+    }
+
+    #[test]
+    fn invalid_note_names_are_rejected() {
+        for name in [
+            "", "H4", "C", "#4", "C$4", "Cx4", "4C", "C4.5", "C 4x", "G#9", "Cb-1",
+        ] {
+            assert_eq!(note_number(name, 4), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn every_14_bit_value_survives_a_split_and_a_join() {
+        for value in 0..=CC14_MAX {
+            let (msb, lsb) = split_cc14(value);
+            assert!(
+                msb <= 127 && lsb <= 127,
+                "{} is split to {} and {}",
+                value,
+                msb,
+                lsb
+            );
+            assert_eq!(join_cc14(msb, lsb), value);
+        }
+        assert_eq!(split_cc14(8192), (64, 0));
+        assert_eq!(split_cc14(CC14_MAX), (127, 127));
+    }
+
+    #[test]
+    fn read_cc14_joins_a_pair_of_controllers() {
+        let run = |script: &str| -> Result<Value, String> {
+            let mut midi_module = ValueMap::new();
+            add_read_cc14(&mut midi_module);
+            let mut runtime = koto::Koto::default();
+            runtime.prelude().add_map("midi", midi_module);
+            let chunk = runtime.compile(script).map_err(|err| err.to_string())?;
+            runtime.run_chunk(chunk).map_err(|err| err.to_string())
+        };
+        assert!(matches!(
+            run("import midi\nmidi.read_cc14 [176, 1, 64], [176, 33, 1]\n"),
+            Ok(Value::Number(ValueNumber::I64(8193)))
+        ));
+        // Not a pair of controllers, or not on the same channel.
+        assert!(run("import midi\nmidi.read_cc14 [176, 1, 64], [176, 34, 1]\n").is_err());
+        assert!(run("import midi\nmidi.read_cc14 [176, 1, 64], [177, 33, 1]\n").is_err());
+    }
+
+    #[test]
+    fn messages_are_sent_in_the_order_they_are_queued() {
+        let (port, sent) = mock_port();
+        port.send(&[0x90, 60, 100]);
+        port.send(&[0xF8]);
+        port.send(&[0x80, 60, 0]);
+        port.flush();
+        assert_eq!(
+            sent_messages(&sent),
+            vec![vec![0x90, 60, 100], vec![0xF8], vec![0x80, 60, 0]]
+        );
+        assert!(port.is_ok());
+    }
+
+    #[test]
+    fn a_long_sysex_message_is_sent_as_a_single_message() {
+        let (port, sent) = mock_port();
+        let mut message = vec![0xF0_u8];
+        message.extend(std::iter::repeat(0x42).take(498));
+        message.push(0xF7);
+        port.send(&message);
+        port.flush();
+        assert_eq!(sent_messages(&sent), vec![message]);
+    }
+
+    #[test]
+    fn nothing_is_sent_after_the_output_is_disconnected() {
+        let (port, sent) = mock_port();
+        port.send(&[0x90, 60, 100]);
+        port.replace(Output::Disconnected);
+        port.send(&[0x80, 60, 0]);
+        port.flush();
+        assert_eq!(sent_messages(&sent), vec![vec![0x90, 60, 100]]);
+        assert!(!port.is_ok());
+    }
+
+    #[test]
+    fn cc14_sends_the_most_significant_bits_first() {
+        let (port, sent) = mock_port();
+        let mut midi_module = ValueMap::new();
+        add_cc14(&mut midi_module, &port);
+        let mut runtime = koto::Koto::default();
+        runtime.prelude().add_map("midi", midi_module);
+        let result = runtime
+            .compile("import midi\nmidi.cc14 2, 1, 8193\n")
+            .map_err(|err| err.to_string())
+            .and_then(|chunk| runtime.run_chunk(chunk).map_err(|err| err.to_string()));
+        assert!(result.is_ok(), "{:?}", result.err());
+        port.flush();
+        assert_eq!(
+            sent_messages(&sent),
+            vec![vec![0xB2, 1, 64], vec![0xB2, 33, 1]]
+        );
+    }
+
+    #[test]
+    fn panic_silences_every_channel() {
+        let (port, sent) = mock_port();
+        send_panic(&[port]);
+        let sent = sent_messages(&sent);
+        for channel in 0..16_u8 {
+            assert!(sent.contains(&vec![0xB0 | channel, 123, 0]));
+            assert!(sent.contains(&vec![0xB0 | channel, 120, 0]));
+        }
+    }
+
+    #[test]
+    fn a_dry_run_logs_every_batched_message_on_its_own() {
+        let (log_to_main, logs) = unbounded();
+        let (error_to_main, _) = unbounded();
+        let mut output = Output::DryRun {
+            index: 1,
+            log_to_main,
+        };
+        let mut messages = vec![vec![0x90, 60, 100], vec![0x90, 64, 100], vec![0x80, 60, 0]];
+        assert_eq!(output.send_batch(&mut messages, &error_to_main), Some(true));
+        assert!(messages.is_empty());
+        assert_eq!(
+            logs.try_iter().collect::<Vec<String>>(),
+            vec!["out 1: 90 3C 64", "out 1: 90 40 64", "out 1: 80 3C 00"]
+        );
+    }
+
+    #[test]
+    fn messages_which_end_notes_are_never_dropped() {
+        let (port, sent) = mock_port_limited_to(Some(1.0));
+        // The only message a second which the rate allows.
+        port.send(&[0x90, 60, 100]);
+        // Over the rate.
+        port.send(&[0x90, 62, 100]);
+        port.send(&[0xB0, 1, 64]);
+        // Never dropped.
+        port.send(&[0x80, 60, 0]);
+        port.send(&[0x90, 62, 0]);
+        port.send(&[0xB3, 123, 0]);
+        port.send(&[0xB3, 120, 0]);
+        port.send(&[0xF8]);
+        port.flush();
+        assert_eq!(
+            sent_messages(&sent),
+            vec![
+                vec![0x90, 60, 100],
+                vec![0x80, 60, 0],
+                vec![0x90, 62, 0],
+                vec![0xB3, 123, 0],
+                vec![0xB3, 120, 0],
+                vec![0xF8],
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Calling the functions which a script defines in "midi", e.g. "midi.listen" for every received message.
+
+use crossterm::style::Stylize;
+use koto::{
+    runtime::{RuntimeError, RuntimeErrorType, Value, ValueList},
+    Koto,
+};
+
+use crate::extensions::{find_type_handler, passes_channel_filter, ListenerCache};
+
+macro_rules! midi_listen_error {
+    ($l:literal) => {
+        Err(RuntimeError::with_prefix(
+            RuntimeError::from($l.to_owned()),
+            &"Error".magenta().to_string(),
+        ))
+    };
+}
+
+/// Text to show for an error thrown by the script.
+///
+/// Errors thrown with a string are shown as they are, others as koto formats them e.g. with their trace.
+pub fn runtime_error_message(err: &RuntimeError) -> String {
+    match &err.error {
+        RuntimeErrorType::StringError(error_message) => error_message.clone(),
+        _ => err.to_string(),
+    }
+}
+
+/// Whether a value returned from "midi.listen" lets thru forward the message, only `false` rejects it.
+fn forwards(returned: &Value) -> bool {
+    !matches!(returned, Value::Bool(false))
+}
+
+/// Calls the handler of the message in the script and returns whether thru may forward the message.
+///
+/// "midi" and "midi.listen" are looked up once and kept in `listener_cache` until a script is (re)loaded.
+pub fn call_midi_listen_with(
+    stamp: u64,
+    message: &[u8],
+    runtime: &mut Koto,
+    channel_filter: u16,
+    listener_cache: &ListenerCache,
+) -> Result<bool, RuntimeError> {
+    if !passes_channel_filter(message, channel_filter) {
+        // The script is not interested in this channel.
+        return Ok(true);
+    }
+    let cached = listener_cache.borrow().clone();
+    let (midi_module_map, message_listener) = if let Some(cached) = cached {
+        cached
+    } else {
+        let midi_module_map = match runtime.prelude().data().get_with_string("midi") {
+            Some(Value::Map(midi_module_map)) => midi_module_map.clone(),
+            Some(_) => return midi_listen_error!("\"midi\" has been found but it is not a map. Try importing \"midi\" on top of your script like \"import midi\". And do not use the same name further."),
+            None => return midi_listen_error!("Try importing \"midi\" on top of your script like \"import midi\""),
+        };
+        let message_listener = midi_module_map.data().get_with_string("listen").cloned();
+        listener_cache.replace(Some((midi_module_map.clone(), message_listener.clone())));
+        (midi_module_map, message_listener)
+    };
+
+    // A handler for the type of the message, e.g. "midi.on_note_on", takes precedence over "midi.listen".
+    if let Some((handler_name, handler, args)) = find_type_handler(&midi_module_map, message) {
+        return runtime
+            .call_function(handler, &args)
+            .map(|returned| forwards(&returned))
+            .map_err(|err| {
+                RuntimeError::with_prefix(
+                    RuntimeError::from(format!(
+                        "Calling \"midi.{}\" is failed, {}",
+                        handler_name,
+                        err.to_string()
+                    )),
+                    &"Error".magenta().to_string(),
+                )
+            });
+    }
+    match message_listener {
+        Some(Value::Function(listener_function)) => {
+            // Make a list of koto values from u8 slice.
+            let message_values = message
+                .iter()
+                .map(|byte| Value::Number(byte.into()))
+                .collect::<Vec<Value>>();
+            let message_list = Value::List(ValueList::from_slice(&message_values));
+            let arg_count = listener_function.arg_count;
+            let message_listener = Value::Function(listener_function);
+            // Call "midi.listen" function in script with the midi message.
+            // The timestamp (in microseconds) is only passed to listeners which accept a second argument.
+            let listen_result = if arg_count == 2 {
+                let stamp = Value::Number(i64::try_from(stamp).unwrap_or(i64::MAX).into());
+                runtime.call_function(message_listener, &[message_list, stamp])
+            } else {
+                runtime.call_function(message_listener, &[message_list])
+            };
+            listen_result
+                .map(|returned| forwards(&returned))
+                .map_err(|err| {
+                    RuntimeError::with_prefix(
+                        RuntimeError::from(format!(
+                            "Calling \"midi.listen\" is failed, {}",
+                            err.to_string()
+                        )),
+                        &"Error".magenta().to_string(),
+                    )
+                })
+        }
+        Some(_) => midi_listen_error!("\"midi.listen\" is defined but it is not a function"),
+        None => midi_listen_error!("Try defining a function as \"midi.listen\". If not there please try importing \"midi\" on top of your script like \"import midi\"."),
+    }
+}
+
+/// Whether the script defines "midi.listen" or one of the handlers for a type of message as a function.
+pub fn has_midi_listener(runtime: &Koto) -> bool {
+    match runtime.prelude().data().get_with_string("midi") {
+        Some(Value::Map(midi_module_map)) => midi_module_map.data().iter().any(|(key, value)| {
+            let key = key.to_string();
+            matches!(value, Value::Function(_)) && (key == "listen" || key.starts_with("on_"))
+        }),
+        _ => false,
+    }
+}
+
+/// Calls "midi.startup" if the script defines it.
+#[allow(clippy::option_if_let_else)]
+pub fn call_midi_startup(runtime: &mut Koto) -> Result<Value, RuntimeError> {
+    let startup = match runtime.prelude().data().get_with_string("midi") {
+        Some(Value::Map(midi_module_map)) => {
+            midi_module_map.data().get_with_string("startup").cloned()
+        }
+        _ => None,
+    };
+    match startup {
+        None | Some(Value::Empty) => Ok(Value::Empty),
+        Some(startup @ Value::Function(_)) => runtime.call_function(startup, &[]).map_err(|err| {
+            RuntimeError::with_prefix(
+                RuntimeError::from(format!(
+                    "Calling \"midi.startup\" is failed, {}",
+                    err.to_string()
+                )),
+                &"Error".magenta().to_string(),
+            )
+        }),
+        Some(_) => midi_listen_error!("\"midi.startup\" is defined but it is not a function"),
+    }
+}
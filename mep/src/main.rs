@@ -23,44 +23,102 @@
 )]
 #![feature(stmt_expr_attributes)]
 
+mod backend;
+mod chain;
+mod config;
+mod extensions;
+mod listen;
+mod monitor;
+mod osc;
+mod replay;
+mod shutdown;
 mod tui;
+use backend::{MidiBackend, Midir, OnReceive};
+use chain::{add_bypass, run_chain, ChainStage};
+use config::Config;
 use dirs::home_dir;
+use extensions::{
+    next_due_in, send_due_messages, send_panic, ActiveNotes, Output, OutputPort, Recorder,
+    Schedule, ScriptState,
+};
+use listen::{call_midi_listen_with, call_midi_startup, has_midi_listener, runtime_error_message};
+use monitor::{spawn_port_monitor, PortChange};
+use osc::{add_osc_send, call_midi_osc_listen, open_osc_out, spawn_osc_receiver};
+use replay::{parse_input_file, spawn_replay};
+use shutdown::{InputPort, Shutdown};
 use std::{
     fs,
     io::stdin,
+    net::UdpSocket,
     path::{Path, PathBuf},
     sync::mpsc::channel,
-    sync::{
-        mpsc::{Receiver, TryRecvError},
-        Arc, Mutex,
-    },
-    time::Duration,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tui::{Tui, BULB};
+use tui::{ErrorCategory, LogEvent, LogFormat, TerminalGuard, Theme, Tui, BULB};
 
 use koto::{
-    runtime::{RuntimeError, RuntimeErrorType, Value, ValueList, ValueNumber},
+    runtime::{Value, ValueKey, ValueMap},
     Koto,
 };
-use midir::{
-    os::unix::{VirtualInput, VirtualOutput},
-    MidiInput, MidiOutput,
-};
 
 // TODO: Use and make use of Context
 use anyhow::{anyhow, bail, Result};
 use clap::{App, Arg, ArgMatches};
-use crossterm::style::Stylize;
+use crossbeam_channel::{after, bounded, never, select, unbounded, Receiver, Sender};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    style::Stylize,
+    tty::IsTty,
+};
 
+use include_dir::{include_dir, Dir};
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 
 const SCRIPTS_FOLDER_NAME: &str = ".mep";
+/// Names of the midi clients which own the ports, unless "--client-name" is given.
+const INPUT_CLIENT_NAME: &str = "mep_input";
+const OUTPUT_CLIENT_NAME: &str = "mep_output";
+/// Modules which "mep" adds to the prelude of koto, keep in sync with the ones added to "runtime.prelude()".
+const PRELUDE_MODULES: &[&str] = &["midi", "random"];
+const MIN_WATCH_DEBOUNCE_MS: u64 = 10;
+const WATCH_RETRY_MIN_MS: u64 = 250;
+const WATCH_RETRY_MAX_MS: u64 = 4000;
+/// Consecutive failures of setting up the watcher after which it is reported as not recovering.
+const WATCH_PERSISTENT_FAILURES: u32 = 5;
+/// The example scripts are built into the binary, an installed "mep" can't find the repository they are in.
+static EXAMPLE_SCRIPTS: Dir = include_dir!("../example_scripts");
 #[derive(Debug)]
 enum WatcherToMainMessage {
     NoticeWrite(PathBuf),
     NoticeRemove(PathBuf),
     Create(PathBuf),
+    /// "~/.mep" folder itself has vanished, the watcher keeps retrying until it is back.
+    ScriptsFolderRemoved,
     Error(notify::Error, Option<PathBuf>),
+    /// The watcher couldn't be set up, it keeps retrying. The flag is set once retrying doesn't seem to help.
+    WatcherFailed(String, bool),
+    /// The watcher is set up again after a failure which was reported as persistent.
+    WatcherRecovered,
+}
+/// What the user did in the script picker.
+#[derive(Debug)]
+enum UserInput {
+    /// An index typed and then "enter" pressed.
+    Choice(String),
+    /// What is typed so far, only in raw mode since the terminal doesn't echo it.
+    Typed(String),
+    /// Arrow keys move the highlight in the list.
+    Up,
+    Down,
+    /// "enter" pressed without typing, chooses the highlighted script.
+    Commit,
+    /// The search query typed after "/", `None` when the search is cancelled with "esc".
+    Search(Option<String>),
+    /// The terminal is resized, only in raw mode.
+    Resize,
+    /// "r" pressed, loads the running script again from the disk. Only in raw mode.
+    Reload,
 }
 #[derive(Debug)]
 struct Context {
@@ -69,22 +127,39 @@ struct Context {
     chosen_index_checked: usize,
     chosen_script: String,
     chosen_script_path: String,
+    script_state: ScriptState,
+    follow_new: bool,
+    /// Whether the scripts folder is filled with the example scripts if it is removed, only the default one is.
+    recreate_with_examples: bool,
+    /// The script given as a path, it is the only one listed when the scripts folder changes.
+    single_script: Option<String>,
+    /// Scripts which the running script imports, changing one of them loads the running script again.
+    dependencies: Vec<Dependency>,
+    /// Modules from the scripts folder which are added to the prelude for the running script, see `add_shared_modules`.
+    shared_modules: Vec<String>,
 }
-impl From<(PathBuf, Vec<String>, usize, String, String)> for Context {
-    fn from(members: (PathBuf, Vec<String>, usize, String, String)) -> Self {
-        Self {
-            scripts_folder_path: members.0,
-            available_scripts: members.1,
-            chosen_index_checked: members.2,
-            chosen_script: members.3,
-            chosen_script_path: members.4,
-        }
-    }
+
+/// A script which the running script imports, directly or through another import.
+#[derive(Debug)]
+struct Dependency {
+    /// The name it is imported with, e.g. "helpers" for "import helpers" or "lib.helpers" for "import lib.helpers".
+    module: String,
+    path: PathBuf,
+    /// Whether it is found in the scripts folder instead of next to the script which imports it.
+    from_scripts_folder: bool,
 }
 
 fn main() -> Result<()> {
+    // "midi.now" of every script, also the ones in the chain, counts from here.
+    let started_at = Instant::now();
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::with_name("version-full")
+                .help("Prints version information together with the version of koto and the modules added to its prelude")
+                .long("version-full")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("port")
                 .help("You may give a name to your midi io port")
@@ -93,9 +168,289 @@ fn main() -> Result<()> {
                 .value_name("name")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("client-name")
+                .help("Name of the midi clients which own the ports, e.g. as Audio MIDI Setup on macOS groups them. Defaults to \"mep_input\" and \"mep_output\".")
+                .long("client-name")
+                .value_name("name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port-in")
+                .help("You may give a name to your midi input port, overrides the name derived from \"--port\"")
+                .long("port-in")
+                .value_name("name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port-out")
+                .help("You may give a name to your midi output port, overrides the name derived from \"--port\"")
+                .long("port-out")
+                .value_name("name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("outputs")
+                .help("Number of virtual midi output ports to create, scripts may address them by index in \"midi.send\". Give comma separated names instead of a count to address them by name in \"midi.send_to\" too")
+                .long("outputs")
+                .value_name("count|names")
+                .default_value("1")
+                .validator(|value| match value.parse::<usize>() {
+                    Ok(count) if count > 0 => Ok(()),
+                    Ok(_) => Err("Please provide a positive integer.".to_owned()),
+                    Err(_) => parse_output_names(&value).map(|_| ()),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-in")
+                .help("Connect to the first existing midi input port which contains the given text in its name, instead of creating a virtual input port")
+                .long("connect-in")
+                .value_name("port-substring")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-out")
+                .help("Connect the first output to the first existing midi output port which contains the given text in its name, instead of creating a virtual output port")
+                .long("connect-out")
+                .value_name("port-substring")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("osc-in")
+                .help("Receive OSC messages on the given address and pass them to \"midi.osc_listen\". Ex. 0.0.0.0:9000")
+                .long("osc-in")
+                .value_name("address:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("osc-out")
+                .help("Send the OSC messages of \"midi.osc_send\" to the given address. Ex. 127.0.0.1:9001")
+                .long("osc-out")
+                .value_name("address:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list-ports")
+                .help("List available midi input and output ports and exit.")
+                .long("list-ports")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("print-path")
+                .help("Print the path of the scripts folder which would be used and exit. Respects \"--home\" and \"--scripts-dir\".")
+                .long("print-path")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("script")
+                .help("Run a script without asking, by its index in the list or a part of its file name")
+                .short("s")
+                .long("script")
+                .value_name("name-or-index")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("script-path")
+                .help("Run the koto script at this path on its own, without \".mep\" folder and without asking. It is reloaded when it changes.")
+                .value_name("script-path")
+                .conflicts_with_all(&["script", "chain", "scripts-dir", "clean", "reset"])
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("chain")
+                .help("Run several scripts in a chain, separated with commas. Each script's \"midi.send\" passes the messages to the next one and the last one is the running script, e.g. \"--chain split,delay\".")
+                .long("chain")
+                .value_name("names-or-indexes")
+                .takes_value(true)
+                .conflicts_with("script"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .help("Don't open any midi ports, log what the script would send below the list of scripts instead.")
+                .long("dry-run")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("input-file")
+                .help("Feed the messages in this file to the script as if they were received, one per line e.g. \"250: 144, 60, 100\" to wait 250 milliseconds before it. Files written by \"midi.dump\" work too. With \"--dry-run\" \"mep\" exits once they are processed.")
+                .long("input-file")
+                .value_name("path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .help("Pass a value to the scripts as \"midi.args.<key>\", e.g. \"--set transpose=12\". May be given several times.")
+                .long("set")
+                .value_name("key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| {
+                    if value.split_once('=').map_or(false, |(key, _)| !key.trim().is_empty()) {
+                        Ok(())
+                    } else {
+                        Err("Please provide a key and a value as \"key=value\".".to_owned())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .help("Run without the terminal interface, for running as a service. Requires \"--script\", messages are written to stderr.")
+                .long("headless")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("middle-c")
+                .help("Name of the middle C, note 60, in the note names which scripts use, e.g. with \"midi.note_number\".")
+                .long("middle-c")
+                .value_name("C3|C4|C5")
+                .possible_values(&["C3", "C4", "C5"])
+                .default_value("C4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bpm")
+                .help("Tempo which \"midi.send_after_beats\" counts in until a script calls \"midi.clock_start\".")
+                .long("bpm")
+                .value_name("bpm")
+                .validator(|value| match value.parse::<f64>() {
+                    Ok(bpm) if bpm.is_finite() && bpm > 0.0 => Ok(()),
+                    _ => Err("Please provide a positive number.".to_owned()),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Leave out the intro, the instructions and the notices about the scripts folder. Errors are still shown.")
+                .short("q")
+                .long("quiet")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .help("Don't color the terminal interface, e.g. when the output is saved to a file.")
+                .long("no-color")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .help("Append the errors and reloads to the given file with their time, in addition to showing them.")
+                .long("log-file")
+                .value_name("path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("Write the log file as text or as one JSON object per line, for other tools to read.")
+                .long("log-format")
+                .value_name("text|json")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .requires("log-file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("thru")
+                .help("Forward every incoming message to the output port as it is, in addition to what the script sends. Scripts may change it with \"midi.thru\".")
+                .long("thru")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("batch-output")
+                .help("Send the messages which pile up while sending right after each other, without waiting for each one to be handed over. On macOS they are also joined with running status into a single send. Reduces the load of dense streams, e.g. a fader sweep.")
+                .long("batch-output")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("max-send-rate")
+                .help("Drop the messages which are sent through an output faster than the given number of messages per second, to protect the devices after it. Unlimited by default.")
+                .long("max-send-rate")
+                .value_name("messages-per-second")
+                .validator(|value| match value.parse::<f64>() {
+                    Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(()),
+                    _ => Err("Please provide a positive number.".to_owned()),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("active-notes")
+                .help("Which notes \"midi.active_notes\" lists as held, the received ones, the sent ones or both.")
+                .long("active-notes")
+                .value_name("in|out|both")
+                .possible_values(&["in", "out", "both"])
+                .default_value("out")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record")
+                .help("Keep the last <count> received messages so scripts may write them to a file with \"midi.dump\".")
+                .long("record")
+                .value_name("count")
+                .default_value("0")
+                .validator(|value| {
+                    value
+                        .parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "Please provide a positive integer.".to_owned())
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .help("Seed the \"random\" module, so the scripts which use it behave the same on every run.")
+                .long("seed")
+                .value_name("number")
+                .validator(|value| {
+                    value
+                        .parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| "Please provide a positive integer.".to_owned())
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("follow-new")
+                .help("Switch to a newly created script in \"~/.mep\" instead of only listing it.")
+                .long("follow-new")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-auto-panic")
+                .help("Don't send \"all notes off\" and \"all sound off\" on all channels when the script errors.")
+                .long("no-auto-panic")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-panic-on-switch")
+                .help("Don't send \"all notes off\" and \"all sound off\" on all channels when another script is chosen. Scripts may change it with \"midi.panic_on_switch\".")
+                .long("no-panic-on-switch")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("watch-debounce")
+                .help("Milliseconds to wait for a script to settle before reloading it, increase it if your editor writes files in chunks. Minimum is 10.")
+                .long("watch-debounce")
+                .value_name("ms")
+                .default_value("100")
+                .validator(|value| {
+                    value
+                        .parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| "Please provide a positive integer.".to_owned())
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scripts-dir")
+                .help("Use the scripts in this directory instead of the ones in \".mep\" folder. It should exist, it is not filled with example scripts.")
+                .long("scripts-dir")
+                .value_name("path")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("home")
-                .help("If \"mep\" couldn't determine your home directory, to help it please run it with \"--home <absolute-path-to-your-home-directory>\"")
+                .help("Look for \".mep\" folder in this directory instead of your home directory. Also helps if \"mep\" couldn't determine your home directory.")
                 .long("home")
                 .value_name("home")
                 .takes_value(true),
@@ -104,66 +459,214 @@ fn main() -> Result<()> {
             Arg::with_name("clean")
                 .help("Remove \"~/.mep\" directory.")
                 .long("clean")
+                // Only the folder "mep" fills with example scripts is removed, not the user's own.
+                .conflicts_with("scripts-dir")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .help("Don't ask for confirmation before \"--clean\" removes \"~/.mep\" directory.")
+                .short("y")
+                .long("yes")
                 .takes_value(false),
         )
         .arg(
             Arg::with_name("reset")
                 .help("Remove \"~/.mep\" folder's contents and populate with example scripts.")
                 .long("reset")
+                .conflicts_with("scripts-dir")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-backup")
+                .help("Don't back up \"~/.mep\" folder to \"~/.mep.bak-<timestamp>\" before \"--reset\".")
+                .long("no-backup")
                 .takes_value(false),
         )
         .get_matches();
 
-    let tui = Tui::new();
+    let headless = matches.is_present("headless");
+    let theme = if matches.is_present("no-color") {
+        Theme::plain()
+    } else {
+        Theme::default()
+    };
+    let tui = Tui::new(headless, matches.is_present("quiet"), theme);
+    // Leave the terminal as it was found whatever happens, headless mode doesn't touch it.
+    let _terminal_guard = (!headless).then(TerminalGuard::install);
+
+    if let Some(log_file_path) = matches.value_of("log-file") {
+        let log_format = match matches.value_of("log-format") {
+            Some("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        };
+        if let Err(err) = tui.set_log_file(Path::new(log_file_path), log_format) {
+            bail!(
+                "{} {}",
+                BULB,
+                format!("Couldn't open the log file {}: {}", log_file_path, err).red()
+            );
+        }
+    }
+
+    if matches.is_present("version-full") {
+        tui.version_full(
+            env!("CARGO_PKG_VERSION"),
+            env!("MEP_KOTO_VERSION"),
+            PRELUDE_MODULES,
+        )?;
+        // Exit successfully
+        return Ok(());
+    }
 
-    // Try to discover user's home directory
-    let home = match home_dir() {
-        Some(dir) => dir,
-        None => {
-            if let Some(path) = matches.value_of("home") {
-                PathBuf::from(path)
-            } else {
+    if matches.is_present("list-ports") {
+        let (input_port_names, output_port_names) =
+            Midir::new(INPUT_CLIENT_NAME, OUTPUT_CLIENT_NAME).port_names()?;
+        tui.list_ports(&input_port_names, &output_port_names)?;
+        // Exit successfully
+        return Ok(());
+    }
+
+    // A script given as a path runs on its own, the folder it is in is only watched for its changes.
+    let single_script = if let Some(path) = matches.value_of("script-path") {
+        match fs::canonicalize(path) {
+            Ok(script_path) if script_path.is_file() && has_koto_extension(&script_path) => {
+                Some(script_path)
+            }
+            _ => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!("\"{}\" is not an existing \".koto\" script.", path).red()
+                );
+            }
+        }
+    } else {
+        None
+    };
+
+    // "--scripts-dir" points to any folder, e.g. one under version control, instead of ".mep" in the home directory.
+    let explicit_scripts_dir = matches.value_of("scripts-dir").is_some() || single_script.is_some();
+    let scripts_folder_path = if let Some(script_path) = &single_script {
+        // A canonical path of a file always has a parent.
+        script_path
+            .parent()
+            .map_or_else(|| PathBuf::from("/"), Path::to_path_buf)
+    } else if let Some(path) = matches.value_of("scripts-dir") {
+        match fs::canonicalize(path) {
+            Ok(dir) if dir.is_dir() => dir,
+            _ => {
                 tui.clear_lines(1)?;
-                bail!("{} {}", BULB, "\"mep\" couldn't determine the location of your home directory, to help it please run it with \"--home <absolute-path-to-your-home-directory>\"".blue());
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!(
+                        "\"{}\" which is given with \"--scripts-dir\" is not an existing directory.",
+                        path
+                    )
+                    .red()
+                );
             }
         }
+    } else {
+        get_scripts_folder_path(&find_home(&tui, &matches)?.to_string_lossy())
     };
 
-    let scripts_folder_path = get_scripts_folder_path(&home.to_string_lossy());
+    if matches.is_present("print-path") {
+        tui.print_path(&scripts_folder_path)?;
+        // Exit successfully
+        return Ok(());
+    }
 
+    // Handled before the configuration is read, so a broken one can be cleaned or reset.
     if matches.is_present("clean") {
-        fs::remove_dir_all(scripts_folder_path)?;
-        tui.removed_scripts_folder()?;
+        if !matches.is_present("yes") {
+            tui.confirm_clean(&scripts_folder_path)?;
+            let mut answer = String::new();
+            stdin().read_line(&mut answer)?;
+            // Anything but an explicit yes, including an empty answer, keeps the folder.
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                return Ok(());
+            }
+        }
+        fs::remove_dir_all(&scripts_folder_path)?;
+        tui.removed_scripts_folder(&scripts_folder_path)?;
         // Exit successfully
         return Ok(());
     }
 
     if matches.is_present("reset") {
-        fs::remove_dir_all(&scripts_folder_path)?;
-        tui.reset_scripts_folder()?;
+        if scripts_folder_path.exists() {
+            if !matches.is_present("no-backup") {
+                // Keep the user's own scripts around, e.g. "~/.mep.bak-1634567890".
+                let backup_path = get_backup_folder_path(&scripts_folder_path)?;
+                copy_directory_contents(&scripts_folder_path, &backup_path)?;
+                tui.backed_up_scripts_folder(&backup_path)?;
+            }
+            fs::remove_dir_all(&scripts_folder_path)?;
+        }
+        tui.reset_scripts_folder(&scripts_folder_path)?;
+        populate_with_examples(&scripts_folder_path)?;
+    }
+
+    // Command line options override the ones in "~/.mep/config.toml".
+    // A single script doesn't belong to a scripts folder, so there is no configuration to read.
+    let config = if single_script.is_some() {
+        Config::default()
+    } else {
+        match Config::read_from(&scripts_folder_path) {
+            Ok(config) => config,
+            Err(err) => {
+                tui.clear_lines(1)?;
+                bail!("{} {}", BULB, err.to_string().red());
+            }
+        }
+    };
+    match config.theme.apply_to(theme) {
+        Ok(theme) => tui.set_theme(theme),
+        Err(err) => {
+            tui.clear_lines(1)?;
+            bail!("{} {}", BULB, err.to_string().red());
+        }
+    }
 
-        let mut examples_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        examples_path.push("..");
-        examples_path.push("example_scripts");
+    // Every script in "--chain" but the last one runs before the running script.
+    let chain = matches.value_of("chain").map_or_else(Vec::new, |chain| {
+        chain
+            .split(',')
+            .map(str::trim)
+            .filter(|name_or_index| !name_or_index.is_empty())
+            .collect::<Vec<&str>>()
+    });
 
-        copy_directory_contents(examples_path, &scripts_folder_path)?;
+    // A script chosen by a launcher or a shell profile, "--script" and "--chain" take precedence over it.
+    let env_script = std::env::var("MEP_SCRIPT")
+        .ok()
+        .filter(|name_or_index| !name_or_index.trim().is_empty());
+
+    if headless
+        && single_script.is_none()
+        && matches.value_of("script").is_none()
+        && chain.is_empty()
+        && env_script.is_none()
+        && config.default_script.is_none()
+    {
+        bail!("\"--headless\" requires a script to be chosen with \"--script\", \"MEP_SCRIPT\" or \"default_script\" in the configuration.");
     }
 
     if !scripts_folder_path.exists() {
+        // Only the default folder is filled with examples, "--scripts-dir" is checked to exist above.
         tui.scripts_folder_not_found()?;
-
-        let mut examples_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        examples_path.push("..");
-        examples_path.push("example_scripts");
-
-        copy_directory_contents(
-            &format!("{}", examples_path.display()),
-            &scripts_folder_path,
-        )?;
+        populate_with_examples(&scripts_folder_path)?;
     }
 
     let mut available_scripts = vec![];
-    collect_available_scripts_to(&mut available_scripts, &scripts_folder_path)?;
+    if let Some(script_path) = &single_script {
+        available_scripts.push(script_path.display().to_string());
+    } else {
+        collect_available_scripts_to(&mut available_scripts, &scripts_folder_path)?;
+    }
 
     tui.clear()?;
     tui.intro()?;
@@ -178,199 +681,624 @@ fn main() -> Result<()> {
         );
     }
 
-    tui.list_scripts(&available_scripts)?;
+    tui.list_scripts(&available_scripts, &scripts_folder_path)?;
+
+    // Short enough to feel immediate, long enough for most editors to finish writing a file.
+    // This flag defaults to 100 and clap validates that it is an integer.
+    #[allow(clippy::unwrap_used)]
+    let watch_debounce = Duration::from_millis(
+        matches
+            .value_of("watch-debounce")
+            .unwrap()
+            .parse::<u64>()?
+            .max(MIN_WATCH_DEBOUNCE_MS),
+    );
 
     // Start a watcher for "~/.mep" folder in its own thread.
-    let (to_main, from_watcher) = channel::<WatcherToMainMessage>();
-    let _watcher_thread = std::thread::spawn(move || -> Result<()> {
-        fn is_koto_script(path: &Path) -> Result<()> {
-            // Is meaningful errors needed here?
-            path.extension().map_or_else(
-                || Err(anyhow!("File does not have any extension.")),
-                |extension| match extension.to_str() {
-                    Some(extension) if "koto" == extension => Ok(()),
-                    None | Some(_) => Err(anyhow!("File does not have a \".koto\" extension.")),
-                },
-            )
+    let (to_main, from_watcher) = unbounded::<WatcherToMainMessage>();
+    let watcher_path = scripts_folder_path.clone();
+    // Only the changes of a single script are relevant, not the ones of the other scripts next to it.
+    // Writes to the others are still passed on, the single script might import them.
+    let watched_script = single_script.clone();
+    let watched_folder = scripts_folder_path.clone();
+    let is_script = move |path: &Path| is_koto_script(path, &watched_folder);
+    let is_watched = {
+        let is_script = is_script.clone();
+        move |path: &Path| {
+            is_script(path)
+                && watched_script
+                    .as_ref()
+                    .map_or(true, |script_path| script_path == path)
         }
-
+    };
+    let _watcher_thread = std::thread::spawn(move || {
+        let mut retry_in = Duration::from_millis(WATCH_RETRY_MIN_MS);
+        let mut reported_removal = false;
+        let mut failures: u32 = 0;
         loop {
             let (sender, receiver) = channel();
-            let mut watcher = watcher(sender, Duration::from_millis(100))?;
-            let mut watcher_path = home.clone();
-            watcher_path.push(SCRIPTS_FOLDER_NAME);
-            watcher.watch(watcher_path, RecursiveMode::Recursive)?;
-
-            if let Ok(event) = receiver.recv() {
-                match event {
-                    DebouncedEvent::NoticeWrite(path) => {
-                        if is_koto_script(&path).is_ok() {
-                            to_main.send(WatcherToMainMessage::NoticeWrite(path))?;
-                        }
-                    }
-                    DebouncedEvent::NoticeRemove(path) => {
-                        if is_koto_script(&path).is_ok() {
-                            to_main.send(WatcherToMainMessage::NoticeRemove(path))?;
-                        }
-                    }
-                    DebouncedEvent::Create(path) => {
-                        if is_koto_script(&path).is_ok() {
-                            to_main.send(WatcherToMainMessage::Create(path))?;
+            let watched = watcher(sender, watch_debounce).and_then(|mut watcher| {
+                watcher
+                    .watch(&watcher_path, RecursiveMode::Recursive)
+                    .map(|_| watcher)
+            });
+            // Kept alive until the next event is received.
+            let _watcher = match watched {
+                Ok(watcher) => watcher,
+                Err(err) => {
+                    let sent = if watcher_path.exists() {
+                        failures = failures.saturating_add(1);
+                        // Report the first failure and once more when retrying doesn't seem to help.
+                        if failures == 1 || failures == WATCH_PERSISTENT_FAILURES {
+                            to_main.send(WatcherToMainMessage::WatcherFailed(
+                                err.to_string(),
+                                failures == WATCH_PERSISTENT_FAILURES,
+                            ))
+                        } else {
+                            Ok(())
                         }
+                    } else if reported_removal {
+                        Ok(())
+                    } else {
+                        // The folder is removed while running, let main recreate it and re-arm when it is back.
+                        reported_removal = true;
+                        to_main.send(WatcherToMainMessage::ScriptsFolderRemoved)
+                    };
+                    if sent.is_err() {
+                        // Main is gone.
+                        return;
                     }
-                    DebouncedEvent::Error(err, path) => {
-                        to_main.send(WatcherToMainMessage::Error(err, path))?;
-                    }
-                    DebouncedEvent::Write(_)
-                    | DebouncedEvent::Remove(_)
-                    | DebouncedEvent::Chmod(_)
-                    | DebouncedEvent::Rescan
-                    | DebouncedEvent::Rename(_, _) => {
-                        // Currently ignoring these.
-                        // Rename event is actually a real mv.
-                    }
+                    std::thread::sleep(retry_in);
+                    retry_in = (retry_in * 2).min(Duration::from_millis(WATCH_RETRY_MAX_MS));
+                    continue;
+                }
+            };
+            if failures >= WATCH_PERSISTENT_FAILURES
+                && to_main
+                    .send(WatcherToMainMessage::WatcherRecovered)
+                    .is_err()
+            {
+                return;
+            }
+            failures = 0;
+            retry_in = Duration::from_millis(WATCH_RETRY_MIN_MS);
+            reported_removal = false;
+
+            let message = match receiver.recv() {
+                Ok(DebouncedEvent::NoticeWrite(path)) if is_script(&path) => {
+                    Some(WatcherToMainMessage::NoticeWrite(path))
+                }
+                Ok(DebouncedEvent::NoticeRemove(path)) if is_watched(&path) => {
+                    Some(WatcherToMainMessage::NoticeRemove(path))
+                }
+                Ok(DebouncedEvent::Create(path)) if is_watched(&path) => {
+                    Some(WatcherToMainMessage::Create(path))
+                }
+                Ok(DebouncedEvent::Error(err, path)) => {
+                    Some(WatcherToMainMessage::Error(err, path))
+                }
+                // Currently ignoring the rest, rename event is actually a real mv.
+                // If the watcher itself has stopped it is set up again.
+                Ok(_) | Err(_) => None,
+            };
+            if let Some(message) = message {
+                if to_main.send(message).is_err() {
+                    return;
                 }
             }
         }
     });
 
-    let mut choice = String::new();
-    let mut chosen_index_checked: usize;
-    // This operation is sound because, at this point we know that "available_scripts" is greater than 0.
-    let max_idx = available_scripts.len() - 1;
+    // Ctrl-C, either as a signal or as a key in raw mode, shuts "mep" down cleanly.
+    let (interrupt_to_main, interrupts) = bounded::<()>(1);
+    ctrlc::set_handler({
+        let interrupt_to_main = interrupt_to_main.clone();
+        move || {
+            let _ = interrupt_to_main.try_send(());
+        }
+    })?;
 
-    loop {
-        // Get user input
-        stdin().read_line(&mut choice)?;
-        chosen_index_checked = if let Ok(idx) = choice.trim().parse() {
+    // Pick scripts with the arrow keys when attached to a terminal.
+    let raw_mode = if !headless && stdin().is_tty() {
+        Some(tui.enable_raw_mode()?)
+    } else {
+        None
+    };
+    // A receiver for the thread for non-blocking stdin, headless mode doesn't read stdin at all.
+    let user_input = if headless {
+        never()
+    } else {
+        spawn_user_input_channel(raw_mode.is_some(), interrupt_to_main)
+    };
+
+    let chosen_index_checked = if single_script.is_some() {
+        // It is the only one in the list.
+        0
+    } else if let Some(name_or_index) = matches
+        .value_of("script")
+        .or_else(|| chain.last().copied())
+        .or_else(|| env_script.as_deref().map(str::trim))
+        .or_else(|| config.default_script.as_deref())
+    {
+        // Script is chosen from the command line, the environment or the configuration, skip asking.
+        if let Some(idx) = find_script(&available_scripts, name_or_index) {
             idx
         } else {
-            // User entered invalid value or negative value, try again
-            choice.clear();
-            tui.ignore_choice()?;
-            continue;
-        };
-        if chosen_index_checked > max_idx {
-            // User entered index out of positive bounds, try again
-            choice.clear();
-            tui.ignore_choice()?;
-            continue;
+            tui.clear_lines(1)?;
+            bail!(
+                "{} {}",
+                BULB,
+                format!(
+                    "There is no script in \"~/.mep\" with the index or a name containing \"{}\".",
+                    name_or_index
+                )
+                .red()
+            );
         }
-        break;
-    }
+    } else {
+        // Nothing is highlighted until an arrow key is pressed.
+        let mut picker_index: Option<usize> = None;
+        loop {
+            // Get user input
+            let input = select! {
+                recv(user_input) -> input => if let Ok(input) = input {
+                    input
+                } else {
+                    // Without stdin there is no way to choose a script.
+                    tui.clear_lines(1)?;
+                    bail!(
+                        "{} {}",
+                        BULB,
+                        "stdin is closed before a script is chosen. Choose one with \"--script <name-or-index>\" to run without it.".red()
+                    );
+                },
+                // Exit successfully, nothing is sent or created yet.
+                recv(interrupts) -> _ => return Ok(()),
+            };
+            if let Some(idx) = handle_picker_input(
+                &tui,
+                input,
+                &mut picker_index,
+                &available_scripts,
+                &scripts_folder_path,
+            )? {
+                break idx;
+            }
+        }
+    };
 
     let chosen_script = fs::read_to_string(&available_scripts[chosen_index_checked])?;
     let chosen_script_path = available_scripts[chosen_index_checked].clone();
 
-    // Init script runtime
-    let mut runtime = Koto::default();
-    runtime.set_script_path(Some(PathBuf::from(
-        &available_scripts[chosen_index_checked],
-    )));
+    // Messages to feed to the script as if they were received.
+    let replayed_messages = if let Some(input_file_path) = matches.value_of("input-file") {
+        match fs::read_to_string(input_file_path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| parse_input_file(&text))
+        {
+            Ok(replayed_messages) => Some(replayed_messages),
+            Err(err) => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!("Couldn't read the input file {}: {}", input_file_path, err).red()
+                );
+            }
+        }
+    } else {
+        None
+    };
+
+    // "--dry-run" doesn't touch midi at all, what would be sent is logged instead.
+    let dry_run = matches.is_present("dry-run");
+    // A single name for both clients, so their ports are grouped together.
+    let client_name = matches
+        .value_of("client-name")
+        .or_else(|| config.client_name.as_deref());
+    let input_client_name = client_name.unwrap_or(INPUT_CLIENT_NAME);
+    let output_client_name = client_name.unwrap_or(OUTPUT_CLIENT_NAME);
+    let (backend, input_port_name, output_port_name) = if dry_run {
+        (None, String::new(), String::new())
+    } else {
+        let (backend, input_port_name, output_port_name) =
+            init_midi_io(&matches, &config, input_client_name, output_client_name);
+        (Some(backend), input_port_name, output_port_name)
+    };
 
-    let (mep_in, mep_out, input_port_name, output_port_name) = init_midi_io(&matches)?;
+    // "midi.log" and the outputs of "--dry-run" write here.
+    // It is unbounded so none of the lines are lost, the outputs of "--dry-run" are compared in tests.
+    let (log_to_main, logs) = unbounded();
+
+    // This flag defaults to 1 and clap validates that it is a positive integer or a list of names.
+    #[allow(clippy::unwrap_used)]
+    let outputs_value = matches.value_of("outputs").unwrap();
+    let (output_count, output_names) = match outputs_value.parse::<usize>() {
+        Ok(count) => (count, Vec::new()),
+        Err(_) => {
+            let output_names = parse_output_names(outputs_value).map_err(|err| anyhow!(err))?;
+            (output_names.len(), output_names)
+        }
+    };
+    let (outputs, connected_output_port_name) = if let Some(backend) = &backend {
+        match open_output_ports(
+            backend.as_ref(),
+            &output_port_name,
+            output_count,
+            matches.value_of("connect-out"),
+        ) {
+            Ok(opened) => opened,
+            Err(err) => {
+                tui.clear_lines(1)?;
+                return Err(err);
+            }
+        }
+    } else {
+        let dry_run_outputs = (0..output_count)
+            .map(|index| Output::DryRun {
+                index,
+                log_to_main: log_to_main.clone(),
+            })
+            .collect();
+        (dry_run_outputs, None)
+    };
 
-    let mep_out_port = Arc::new(Mutex::new(
-        mep_out.create_virtual(&output_port_name).map_err(|err| {
-            anyhow!(
-                "Couldn't create virtual midi output port named {}.\nError: {:?}",
-                output_port_name,
-                err
+    let (midi_send_error_to_main, midi_send_errors) = bounded(256);
+
+    // Every output is sent through by its own thread, see `OutputPort`.
+    // Held notes are tracked as they are received and sent, for "midi.active_notes".
+    let received_notes = ActiveNotes::default();
+    let sent_notes = ActiveNotes::default();
+
+    // clap validates that the rate is a positive number.
+    let max_send_rate = matches
+        .value_of("max-send-rate")
+        .and_then(|rate| rate.parse::<f64>().ok());
+
+    let mep_out_ports: Vec<OutputPort> = outputs
+        .into_iter()
+        .map(|output| {
+            OutputPort::spawn(
+                output,
+                midi_send_error_to_main.clone(),
+                matches.is_present("batch-output"),
+                sent_notes.clone(),
+                max_send_rate,
             )
-        })?,
-    ));
+        })
+        .collect();
 
     // Init "koto_midi" library
     let mut midi_module = koto_midi::make_module();
-    let send_error_message = "Error calling \"midi.send\": Wrong argument type, please try to use a list of bytes (integers ranged to 0..=255) as an argument. Ex. [144, 65, 127]";
 
-    // Add "midi.send" function
-    let (midi_send_error_to_main, midi_send_errors) = std::sync::mpsc::sync_channel(256);
-    midi_module.add_fn("send", move |vm, args| {
-        if let [Value::List(message)] = vm.get_args(args) {
-            let msg: Result<Vec<u8>, _> = message
-                .data()
-                .iter()
-                .map(|value| {
-                    if let Value::Number(num) = *value {
-                        match num {
-                            #[allow(clippy::cast_sign_loss)]
-                            #[allow(clippy::cast_possible_truncation)]
-                            #[allow(clippy::as_conversions)]
-                            // These are all fine because the value of `byte` is checked if it is in u8 range before.
-                            ValueNumber::I64(byte) if (0..=255).contains(&byte) => Ok(byte as u8),
-                            _ => {
-                                // `unwrap()` will always succeed channel receiver is in main.
-                                #[allow(clippy::unwrap_used)]
-                                midi_send_error_to_main
-                                    .send(send_error_message.into())
-                                    .unwrap();
-                                Err(())
-                            }
-                        }
-                    } else {
-                        // `unwrap()` will always succeed succeed channel receiver is in main.
-                        #[allow(clippy::unwrap_used)]
-                        midi_send_error_to_main
-                            .send(send_error_message.into())
-                            .unwrap();
-                        Err(())
-                    }
-                })
-                .collect();
-            if let Ok(midi_message_to_send) = msg {
-                // `lock.unwrap()` will always succeed no one else locks it.
-                #[allow(clippy::unwrap_used)]
-                if let Err(e) = mep_out_port.lock().unwrap().send(&midi_message_to_send[..]) {
-                    // `unwrap()` will always succeed succeed channel receiver is in main.
-                    midi_send_error_to_main
-                        .send(format!("Error when trying to send midi message: {}", e))
-                        .unwrap();
-                }
-            }
-            Ok(Value::Empty)
+    // Octave of the middle C in note names, clap validates that it is one of "C3", "C4" or "C5".
+    let middle_c_octave = matches
+        .value_of("middle-c")
+        .and_then(|middle_c| middle_c.strip_prefix('C')?.parse::<i64>().ok())
+        .unwrap_or(4);
+
+    // Add "midi.note_on", "midi.note_off" and "midi.cc" functions
+    extensions::add_channel_message_helpers(&mut midi_module, &mep_out_ports[0], middle_c_octave);
+
+    // Add "midi.cc14" function
+    extensions::add_cc14(&mut midi_module, &mep_out_ports[0]);
+
+    // Add "midi.note_number" and "midi.note_name" functions
+    extensions::add_note_names(&mut midi_module, middle_c_octave);
+
+    // Add "midi.message_type", "midi.channel" and "midi.data" functions
+    extensions::add_message_helpers(&mut midi_module);
+
+    // Add "midi.sysex" function
+    extensions::add_sysex(&mut midi_module, &mep_out_ports[0]);
+
+    // State of the running script which is reset every time a script is (re)loaded.
+    // clap validates that the tempo is a positive number.
+    let bpm = matches
+        .value_of("bpm")
+        .and_then(|bpm| bpm.parse::<f64>().ok());
+    let script_state = ScriptState::new(
+        matches.is_present("thru"),
+        !matches.is_present("no-panic-on-switch"),
+        &chosen_script_path,
+        bpm,
+    );
+
+    // Add "midi.load_data" function
+    extensions::add_load_data(
+        &mut midi_module,
+        Arc::clone(&script_state.path),
+        scripts_folder_path.clone(),
+    );
+
+    // Add "midi.panic" function
+    extensions::add_panic(&mut midi_module, &mep_out_ports);
+
+    // Add "midi.output_ok" function
+    extensions::add_output_ok(&mut midi_module, &mep_out_ports);
+
+    // Add "midi.panic_on_switch" function
+    extensions::add_panic_on_switch(&mut midi_module, &script_state.panic_on_switch);
+
+    // Add "midi.every" function
+    extensions::add_every(&mut midi_module, &script_state.timers);
+
+    // Add "midi.reset" function
+    extensions::add_reset(&mut midi_module, &script_state.reset_requested);
+
+    // Add "midi.channel_filter" function
+    extensions::add_channel_filter(&mut midi_module, &script_state.channel_filter);
+
+    // Add "midi.thru" function
+    extensions::add_thru(&mut midi_module, &script_state.thru);
+
+    // Add "midi.clock_start" and "midi.clock_stop" functions
+    extensions::add_clock(
+        &mut midi_module,
+        &script_state.clock,
+        &script_state.tempo,
+        &mep_out_ports[0],
+    );
+
+    // Add "midi.after" and "midi.send_after_beats" functions
+    let schedule = Schedule::default();
+    extensions::add_after(&mut midi_module, &schedule);
+    extensions::add_send_after_beats(&mut midi_module, &schedule, &script_state.tempo);
+
+    // Add "midi.active_notes" function
+    // This flag defaults to "out" and clap validates that it is one of the possible values.
+    #[allow(clippy::unwrap_used)]
+    let active_note_sources = match matches.value_of("active-notes").unwrap() {
+        "in" => vec![received_notes.clone()],
+        "out" => vec![sent_notes],
+        _ => vec![received_notes.clone(), sent_notes],
+    };
+    extensions::add_active_notes(&mut midi_module, active_note_sources);
+
+    // Add "midi.dump" function
+    // This flag defaults to 0 and clap validates that it is an integer.
+    #[allow(clippy::unwrap_used)]
+    let recorder = Recorder::new(matches.value_of("record").unwrap().parse()?);
+    extensions::add_dump(&mut midi_module, &recorder);
+
+    // Add "midi.now" function
+    extensions::add_now(&mut midi_module, started_at);
+
+    // Add "midi.args" map
+    let args = extensions::make_args(matches.values_of("set").into_iter().flatten());
+    midi_module.add_map("args", args.clone());
+
+    // Add "midi.log" function
+    extensions::add_log(&mut midi_module, log_to_main.clone());
+
+    // clap validates that it is a positive integer.
+    let seed = matches
+        .value_of("seed")
+        .map(str::parse::<u64>)
+        .transpose()?;
+
+    // Load the scripts which process the incoming messages before the running one.
+    let mut chain_stages = Vec::new();
+    for name_or_index in chain.iter().take(chain.len().saturating_sub(1)) {
+        let stage_path = if let Some(idx) = find_script(&available_scripts, name_or_index) {
+            &available_scripts[idx]
         } else {
-            midi_send_error_to_main
-                .send(send_error_message.to_owned())
-                .map(|_| Value::Empty)
-                .map_err(|err| RuntimeError::from(err.to_string()))
+            tui.clear_lines(1)?;
+            bail!(
+                "{} {}",
+                BULB,
+                format!(
+                    "There is no script in \"~/.mep\" with the index or a name containing \"{}\" to put in the chain.",
+                    name_or_index
+                )
+                .red()
+            );
+        };
+        match ChainStage::new(
+            stage_path,
+            log_to_main.clone(),
+            started_at,
+            seed,
+            &args,
+            middle_c_octave,
+            &scripts_folder_path,
+        ) {
+            Ok(stage) => chain_stages.push(stage),
+            Err(err) => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}\n{}",
+                    BULB,
+                    format!(
+                        "There is an error in: {}, which is in the chain.",
+                        stage_path
+                    )
+                    .red(),
+                    err
+                );
+            }
         }
-    });
+    }
+
+    // Add "midi.bypass" and "midi.solo" functions
+    add_bypass(&mut midi_module, &chain_stages);
+
+    // Add "midi.transpose" and "midi.velocity_curve" functions
+    extensions::add_transforms(&mut midi_module, middle_c_octave);
+
+    // Add "midi.send_all" function
+    extensions::add_send_all(&mut midi_module, &mep_out_ports);
+
+    // Add "midi.send_to" function
+    extensions::add_send_to(&mut midi_module, &output_names, &mep_out_ports);
+
+    // Add "midi.osc_send" function
+    let osc_destination = if let Some(address) = matches.value_of("osc-out") {
+        match open_osc_out(address) {
+            Ok(osc_destination) => Some(osc_destination),
+            Err(err) => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!("Couldn't send OSC messages to \"{}\": {}", address, err).red()
+                );
+            }
+        }
+    } else {
+        None
+    };
+    add_osc_send(&mut midi_module, osc_destination);
+
+    // OSC messages are passed to "midi.osc_listen" only if "--osc-in" is given.
+    let from_osc = if let Some(address) = matches.value_of("osc-in") {
+        match UdpSocket::bind(address) {
+            Ok(socket) => {
+                let (osc_to_main, from_osc) = unbounded();
+                spawn_osc_receiver(socket, osc_to_main);
+                from_osc
+            }
+            Err(err) => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!("Couldn't receive OSC messages on \"{}\": {}", address, err).red()
+                );
+            }
+        }
+    } else {
+        never()
+    };
+
+    // Add "midi.send" function
+    extensions::add_send(&mut midi_module, &mep_out_ports, midi_send_error_to_main);
 
     // Make the handler call "midi.listen" function
-    let (midi_in_to_main, from_midi_in) = channel::<Vec<u8>>();
-    let _mep_in_port = mep_in
-        .create_virtual(
-            &input_port_name,
-            move |_stamp, message, _| {
+    // Messages are sent with whether thru may forward them, which is not the case for the replayed ones.
+    let (midi_in_to_main, from_midi_in) = unbounded::<(u64, Vec<u8>, bool)>();
+    // Every connection needs its own callback, a new one is made when an unplugged port is reconnected.
+    let make_on_midi_in = {
+        // Main keeps a sender too, so the channel stays open without an input port in "--dry-run".
+        let on_midi_in_to_main = midi_in_to_main.clone();
+        move || -> OnReceive {
+            let recorder = recorder.clone();
+            let on_midi_in_to_main = on_midi_in_to_main.clone();
+            Box::new(move |stamp: u64, message: &[u8]| {
+                recorder.record(stamp, message);
                 let msg: Vec<u8> = message.iter().copied().collect();
                 #[allow(clippy::unwrap_used)]
                 // The receiver is in the main thread and will live through the whole lifetime of the app.
                 // Because of this unwrap is safe here.
-                midi_in_to_main.send(msg).unwrap();
-            },
-            (),
-        )
-        .map_err(|err| {
-            anyhow!(
-                "Couldn't create virtual midi input port named {}.\nError: {:?}",
-                input_port_name,
-                err
-            )
-        })?;
+                on_midi_in_to_main.send((stamp, msg, true)).unwrap();
+            })
+        }
+    };
+    let (mep_in_port, connected_input_port_name) = if let Some(backend) = &backend {
+        match backend.open_input(
+            &input_port_name,
+            matches.value_of("connect-in"),
+            make_on_midi_in(),
+        ) {
+            Ok((connection, connected_input_port_name)) => {
+                (Some(connection), connected_input_port_name)
+            }
+            Err(err) => {
+                tui.clear_lines(1)?;
+                return Err(err);
+            }
+        }
+    } else {
+        (None, None)
+    };
+    let mep_in_port: InputPort = Arc::new(Mutex::new(mep_in_port));
+
+    // The clock is stopped and the ports are closed in order however "mep" exits from now on.
+    let shutdown = Shutdown::new(&script_state.clock, &mep_in_port, &mep_out_ports);
+    let _shutdown_guard = shutdown.guard();
+
+    // An interrupt silences the output ports before exiting, wherever main is blocked.
+    std::thread::spawn({
+        let shutdown = shutdown.clone();
+        move || {
+            if interrupts.recv().is_ok() {
+                shutdown.run(true);
+                tui::restore_terminal();
+                // Exit successfully
+                std::process::exit(0);
+            }
+        }
+    });
 
-    // Add "koto_midi", "random" and other custom extensions to script runtime prelude.
-    let mut prelude = runtime.prelude();
-    prelude.add_map("midi", midi_module);
-    prelude.add_value("random", koto_random::make_module());
+    // Notified once every message in "--input-file" is fed to the script.
+    let mut replay_finished = if let Some(replayed_messages) = replayed_messages {
+        let (replay_finished_to_main, replay_finished) = bounded(1);
+        spawn_replay(
+            replayed_messages,
+            started_at,
+            midi_in_to_main.clone(),
+            replay_finished_to_main,
+        );
+        replay_finished
+    } else {
+        never()
+    };
+    let mut replay_done = false;
+
+    // Ports connected with "--connect-in" and "--connect-out" are reconnected if they are unplugged and come back.
+    let (port_change_to_main, mut port_changes) = unbounded();
+    if let Some(backend) = &backend {
+        spawn_port_monitor(
+            Arc::clone(backend),
+            connected_input_port_name.clone(),
+            connected_output_port_name.clone(),
+            port_change_to_main,
+        );
+    }
+
+    // Let the user know what to connect to in their DAW.
+    // Other apps see our virtual input as an output port and vice versa.
+    if let Some(backend) = &backend {
+        let (os_input_port_names, os_output_port_names) = backend.port_names().unwrap_or_default();
+        let exposed_input_port_name = connected_input_port_name
+            .unwrap_or_else(|| resolve_os_port_name(&os_output_port_names, &input_port_name));
+        let exposed_output_port_names = (0..output_count)
+            .map(|index| match (index, &connected_output_port_name) {
+                (0, Some(connected_output_port_name)) => connected_output_port_name.clone(),
+                _ => resolve_os_port_name(
+                    &os_input_port_names,
+                    &indexed_output_port_name(&output_port_name, index),
+                ),
+            })
+            .collect();
+        tui.set_port_names(exposed_input_port_name, exposed_output_port_names)?;
+    } else {
+        tui.set_port_names(
+            "none (dry run)".to_owned(),
+            (0..output_count)
+                .map(|index| format!("out {} (dry run, logged below)", index))
+                .collect(),
+        )?;
+    }
+
+    // Init script runtime with "koto_midi", "random" and other custom extensions in its prelude.
+    // Scripts change the module they get, e.g. by defining "midi.listen", so every runtime gets a copy of it.
+    let pristine_midi_module = midi_module;
+    let mut runtime = make_runtime(&pristine_midi_module, &args, seed);
 
-    let mut context = Context::from((
+    let mut context = Context {
         scripts_folder_path,
         available_scripts,
         chosen_index_checked,
         chosen_script,
         chosen_script_path,
-    ));
+        script_state,
+        follow_new: matches.is_present("follow-new"),
+        recreate_with_examples: !explicit_scripts_dir,
+        single_script: single_script.map(|script_path| script_path.display().to_string()),
+        dependencies: Vec::new(),
+        shared_modules: Vec::new(),
+    };
 
     // Tries to compile the chosen script with dynamic error handling.
     compile_run_block_until_valid(&tui, &from_watcher, &mut context, &mut runtime)?;
@@ -378,282 +1306,1040 @@ fn main() -> Result<()> {
     tui.highlight_and_render(
         &chosen_index_checked.to_string(),
         &context.available_scripts,
+        &context.scripts_folder_path,
     )?;
 
     runtime.run()?;
 
-    // A receiver for the thread for non-blocking stdin
-    let stdin_channel = spawn_stdin_channel();
+    // The highlighted script in the picker, it is the running one unless moved with the arrow keys.
+    let mut picker_index = Some(context.chosen_index_checked);
+
+    let auto_panic = !matches.is_present("no-auto-panic");
+
+    // Count of the received messages, to show that something is arriving.
+    let mut received_messages: usize = 0;
 
     // Main loop
     loop {
-        // TODO: Might be better to move this midi receiver to it's own thread. And propagate necessary messages there..
-        // The problem is runtime creates a deadlock if we move the clone of it inside the closure..
-        // Find a nice solution to it.
-        // If we don't throttle this loop it consumes %100 CPU as expected.
-        // For now, 0.00025 secs or low is enough for midi messages, average of %3.5~ CPU and 1ms round trip latency.
-        // This technique of hot reloading in midi receive errors has drawbacks listed in the upper part.
-        std::thread::sleep(std::time::Duration::from_micros(250));
-
-        // Process midi received messages
-        if let Ok(message) = from_midi_in.try_recv() {
-            match call_midi_listen_with(&message, &mut runtime) {
-                Ok(_) => continue,
-                Err(err) => {
+        // Without ports nothing else can come in, stop once the input file and what it caused is processed.
+        if dry_run && replay_done && from_midi_in.is_empty() && next_due_in(&schedule).is_none() {
+            // Show what is logged last before leaving, the sender threads log the sent messages.
+            for port in &mep_out_ports {
+                port.flush();
+            }
+            for log_message in logs.try_iter() {
+                tui.log(&log_message)?;
+            }
+            return Ok(());
+        }
+
+        // Block until something happens instead of polling, so an idle "mep" doesn't consume CPU.
+        // If there are scheduled messages, wake up when the earliest one is due.
+        let schedule_timer = next_due_in(&schedule).map_or_else(never, after);
+        // And when the earliest "midi.every" timer is due.
+        let script_timer =
+            extensions::next_timer_due_in(&context.script_state.timers).map_or_else(never, after);
+        // Wake up to draw the last count of received messages if it was throttled.
+        let activity_timer = tui.activity_redraw_in().map_or_else(never, after);
+
+        select! {
+            // Process midi received messages
+            recv(from_midi_in) -> message => {
+                // The sender lives in the input port's callback through the whole lifetime of the app.
+                if let Ok((stamp, message, thru_allowed)) = message {
+                    received_notes.track(&message);
+                    received_messages = received_messages.wrapping_add(1);
+                    tui.set_activity(received_messages)?;
+                    // Without a chain the message reaches the running script as it is.
+                    let messages = run_chain(&mut chain_stages, stamp, &message);
+                    // The script rejects forwarding the message by returning false for any of what reaches it.
+                    let result = messages.and_then(|messages| {
+                        messages.iter().try_fold(true, |forward, message| {
+                            call_midi_listen_with(
+                                stamp,
+                                message,
+                                &mut runtime,
+                                context
+                                    .script_state
+                                    .channel_filter
+                                    .load(Ordering::Relaxed),
+                                &context.script_state.listener_cache,
+                            )
+                            .map(|forward_this| forward && forward_this)
+                            .map_err(|err| (context.chosen_script_path.clone(), err))
+                        })
+                    });
+                    // A broken script doesn't stop forwarding.
+                    let forward = result.as_ref().map_or(true, |forward| *forward);
+                    let thru = context.script_state.thru.load(Ordering::Relaxed);
+                    if thru_allowed && thru && forward {
+                        mep_out_ports[0].send(&message);
+                    }
+                    if let Err((script_path, err)) = result {
+                        // Don't leave notes hanging because of the crashed script.
+                        if auto_panic {
+                            send_panic(&mep_out_ports);
+                        }
+                        tui.clear()?;
+                        tui.show_error(
+                            ErrorCategory::Runtime,
+                            &script_path,
+                            &runtime_error_message(&err),
+                        )?;
+                    }
+                }
+            }
+            // Show what the script logged.
+            recv(logs) -> log_message => {
+                if let Ok(log_message) = log_message {
+                    tui.log(&log_message)?;
+                }
+            }
+            recv(midi_send_errors) -> error_message => {
+                if let Ok(error_message) = error_message {
                     tui.clear()?;
-                    // TODO: maybe downcast ref here
-                    if let RuntimeErrorType::StringError(error_message) = err.error {
-                        tui.show_error(&context.chosen_script_path, &error_message)?;
+                    tui.show_error(ErrorCategory::Send, &context.chosen_script_path, &error_message)?;
+                }
+            }
+            recv(user_input) -> input => {
+                let input = if let Ok(input) = input {
+                    input
+                } else {
+                    // stdin is closed, e.g. "ctrl-d" is pressed or the pipe feeding it has ended.
+                    // The script is already chosen so this is the end of the session rather than an error.
+                    shutdown.run(true);
+                    return Ok(());
+                };
+                if let UserInput::Reload = input {
+                    tui.write_to_log_file(
+                        LogEvent::Reload,
+                        Some(&context.chosen_script_path),
+                        &format!("Reloading {}", context.chosen_script_path),
+                    );
+                    reload_script(&tui, &from_watcher, &mut context, &mut runtime)?;
+                    picker_index = Some(context.chosen_index_checked);
+                } else if let UserInput::Choice(choice) = input {
+                    if choose_script(&tui, &mut context, &choice, &mep_out_ports)? {
+                        reload_script(&tui, &from_watcher, &mut context, &mut runtime)?;
+                        picker_index = Some(context.chosen_index_checked);
                     }
-                    if try_debug(&tui, &from_watcher, &mut runtime, &mut context).is_ok() {
-                        continue;
-                    };
+                } else if let Some(idx) = handle_picker_input(
+                    &tui,
+                    input,
+                    &mut picker_index,
+                    &context.available_scripts,
+                    &context.scripts_folder_path,
+                )? {
+                    switch_to_script(
+                        &tui,
+                        &from_watcher,
+                        &mut context,
+                        &mut runtime,
+                        idx,
+                        &mep_out_ports,
+                    )?;
+                    picker_index = Some(idx);
                 }
             }
-        }
-        if let Ok(error_message) = midi_send_errors.try_recv() {
-            tui.clear()?;
-            tui.show_error(&context.chosen_script_path, &error_message)?;
-            if try_debug(&tui, &from_watcher, &mut runtime, &mut context).is_ok() {
-                continue;
+            recv(from_watcher) -> message_from_watcher => {
+                let message_from_watcher = if let Ok(message_from_watcher) = message_from_watcher {
+                    message_from_watcher
+                } else {
+                    bail!("The watcher of \"~/.mep\" folder has stopped.");
+                };
+                // Saving repeatedly, e.g. on every key stroke, shouldn't compile the script for every save.
+                let (message_from_watcher, next_message_from_watcher) =
+                    coalesce_writes(message_from_watcher, &from_watcher);
+                for message_from_watcher in
+                    std::iter::once(message_from_watcher).chain(next_message_from_watcher)
+                {
+                    // A changed script might describe itself differently now.
+                    match &message_from_watcher {
+                        WatcherToMainMessage::NoticeWrite(path)
+                        | WatcherToMainMessage::NoticeRemove(path)
+                        | WatcherToMainMessage::Create(path) => {
+                            tui.forget_description(Some(&path.to_string_lossy()));
+                        }
+                        WatcherToMainMessage::ScriptsFolderRemoved => tui.forget_description(None),
+                        _ => {}
+                    }
+                    // A script in the chain is reloaded in place, the running script stays as it is.
+                    if let WatcherToMainMessage::NoticeWrite(path) = &message_from_watcher {
+                        let path = path.to_string_lossy();
+                        if let Some(stage) = chain_stages.iter_mut().find(|stage| stage.path == path) {
+                            tui.write_to_log_file(
+                                LogEvent::Reload,
+                                Some(&path),
+                                &format!("Reloading {}", path),
+                            );
+                            if let Err(err) = stage.reload() {
+                                tui.clear()?;
+                                tui.show_error(ErrorCategory::Compile, &stage.path, &err.to_string())?;
+                            } else {
+                                tui.highlight_and_render(
+                                    &context.chosen_index_checked.to_string(),
+                                    &context.available_scripts,
+                                    &context.scripts_folder_path,
+                                )?;
+                            }
+                            continue;
+                        }
+                    }
+                    // If the change couldn't be applied the screen stays as it is until the next one.
+                    let _ = try_debug(
+                        &tui,
+                        message_from_watcher,
+                        &from_watcher,
+                        &mut runtime,
+                        &mut context,
+                    );
+                }
+                // The list is re-rendered with the running script highlighted.
+                picker_index = Some(context.chosen_index_checked);
             }
-        }
-
-        match stdin_channel.try_recv() {
-            Ok(mut user_choice) => {
-                context.chosen_index_checked = if let Ok(idx) = user_choice.trim().parse() {
-                    idx
+            recv(schedule_timer) -> _ => {}
+            recv(script_timer) -> _ => {}
+            recv(activity_timer) -> _ => {
+                tui.set_activity(received_messages)?;
+            }
+            recv(port_changes) -> change => {
+                // Ports are only monitored when they are opened through a backend.
+                let backend = if let Some(backend) = &backend {
+                    backend
                 } else {
-                    // User entered invalid value or negative value, try again
-                    user_choice.clear();
-                    tui.ignore_choice()?;
+                    port_changes = never();
                     continue;
                 };
-                if chosen_index_checked > max_idx {
-                    // User entered index out of positive bounds, try again
-                    user_choice.clear();
-                    tui.ignore_choice()?;
-                    continue;
+                let notice = match change {
+                    Ok(PortChange::InputLost(name)) => {
+                        // The script keeps running, so its state survives until the port is back.
+                        // `lock.unwrap()` will always succeed because no one panics while holding it.
+                        #[allow(clippy::unwrap_used)]
+                        drop(mep_in_port.lock().unwrap().take());
+                        format!("\"{}\" is disconnected, waiting for it to come back.", name)
+                    }
+                    Ok(PortChange::InputBack(name)) => {
+                        match backend.open_input(&input_port_name, Some(&name), make_on_midi_in()) {
+                            Ok((connection, _)) => {
+                                // `lock.unwrap()` will always succeed because no one panics while holding it.
+                                #[allow(clippy::unwrap_used)]
+                                mep_in_port.lock().unwrap().replace(connection);
+                                format!("\"{}\" is reconnected.", name)
+                            }
+                            Err(err) => format!("Couldn't reconnect to \"{}\": {}", name, err),
+                        }
+                    }
+                    Ok(PortChange::OutputLost(name)) => {
+                        mep_out_ports[0].replace(Output::Disconnected);
+                        format!(
+                            "\"{}\" is disconnected, what is sent to it is dropped until it comes back.",
+                            name
+                        )
+                    }
+                    Ok(PortChange::OutputBack(name)) => {
+                        match backend.open_output(&output_port_name, Some(&name)) {
+                            Ok((connection, _)) => {
+                                mep_out_ports[0].replace(Output::Port(connection));
+                                format!("\"{}\" is reconnected.", name)
+                            }
+                            Err(err) => format!("Couldn't reconnect to \"{}\": {}", name, err),
+                        }
+                    }
+                    Err(_) => {
+                        // The monitor isn't running, nothing is connected to an existing port.
+                        port_changes = never();
+                        continue;
+                    }
+                };
+                tui.write_to_log_file(LogEvent::Port, None, &notice);
+                tui.log(&notice)?;
+            }
+            // Process received OSC messages
+            recv(from_osc) -> message => {
+                // The sender lives in the OSC receiver thread through the whole lifetime of the app.
+                if let Ok(message) = message {
+                    if let Err(err) = call_midi_osc_listen(&mut runtime, message) {
+                        tui.clear()?;
+                        tui.show_error(
+                            ErrorCategory::Runtime,
+                            &context.chosen_script_path,
+                            &runtime_error_message(&err),
+                        )?;
+                    }
                 }
+            }
+            recv(replay_finished) -> _ => {
+                replay_done = true;
+                // The sender is gone after notifying, don't wake up for it again.
+                replay_finished = never();
+            }
+        }
 
-                context.chosen_script_path =
-                    context.available_scripts[context.chosen_index_checked].clone();
-                context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
-
-                // Tries to compile the chosen script with dynamic error handling.
-                compile_run_block_until_valid(&tui, &from_watcher, &mut context, &mut runtime)?;
+        // Shown next to the count of received messages, drawn when the activity timer fires.
+        tui.set_outputs_ok(mep_out_ports.iter().all(OutputPort::is_ok));
+
+        // Bypassed stages of the chain are dimmed in the list.
+        let bypassed_stages = chain_stages
+            .iter()
+            .filter(|stage| stage.is_bypassed())
+            .map(|stage| stage.path.clone())
+            .collect();
+        if tui.set_bypassed(bypassed_stages) {
+            tui.highlight_and_render(
+                &context.chosen_index_checked.to_string(),
+                &context.available_scripts,
+                &context.scripts_folder_path,
+            )?;
+        }
 
-                tui.highlight_and_render(
-                    &context.chosen_index_checked.to_string(),
-                    &context.available_scripts,
+        // Call the functions of the due "midi.every" timers, one which throws is not called again.
+        let mut failed_timers = Vec::new();
+        for (index, callback) in extensions::due_timers(&context.script_state.timers) {
+            if let Err(err) = runtime.call_function(callback, &[]) {
+                failed_timers.push(index);
+                tui.clear()?;
+                tui.show_error(
+                    ErrorCategory::Runtime,
+                    &context.chosen_script_path,
+                    &format!("Calling a \"midi.every\" function is failed, {}", err),
                 )?;
             }
-            Err(TryRecvError::Empty) => {
-                if try_debug(&tui, &from_watcher, &mut runtime, &mut context).is_ok() {
-                    continue;
-                }
-            }
-            // TODO: Maybe join the thread? Currently erroring and terminating.
-            Err(TryRecvError::Disconnected) => bail!("stdin channel disconnected!"),
         }
+        extensions::reschedule_timers(&context.script_state.timers);
+        extensions::remove_timers(&context.script_state.timers, &failed_timers);
+
+        // The script asked to start over with "midi.reset", nothing it has left in the runtime survives.
+        if context
+            .script_state
+            .reset_requested
+            .swap(false, Ordering::Relaxed)
+        {
+            runtime = make_runtime(&pristine_midi_module, &args, seed);
+            tui.write_to_log_file(
+                LogEvent::Reload,
+                Some(&context.chosen_script_path),
+                &format!("Resetting {}", context.chosen_script_path),
+            );
+            reload_script(&tui, &from_watcher, &mut context, &mut runtime)?;
+        }
+
+        // Send scheduled messages which are due.
+        // Note that while the script is waiting for a fix in "compile_run_block_until_valid" this loop and so the schedule pauses.
+        send_due_messages(&schedule, &mep_out_ports[0]);
     }
 
     // unreachable
 }
 
+/// Whether the file is a koto script, the extension is matched case insensitively e.g. ".KOTO" is fine too.
+///
+/// Both the list of scripts and the watcher use this so they never disagree.
+fn has_koto_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(std::ffi::OsStr::to_str)
+        .map_or(false, |extension| {
+            extension.trim().eq_ignore_ascii_case("koto")
+        })
+}
+
+/// Whether a file or folder is hidden or left by an editor while it is working on a file.
+///
+/// E.g. ".arp.koto.swp" of vim, ".#arp.koto" and "#arp.koto#" of emacs or "arp.koto~" for backups.
+fn is_hidden_or_temporary(name: &str) -> bool {
+    name.starts_with('.') || name.starts_with('#') || name.ends_with('#') || name.ends_with('~')
+}
+
+/// Whether the file at `path` is a script to list and watch in the scripts folder.
+///
+/// Hidden and temporary files are skipped together with everything in hidden folders, e.g. ".git".
+/// Only the file name is checked for a path outside of the scripts folder, which itself may be hidden as ".mep".
+fn is_koto_script(path: &Path, scripts_folder_path: &Path) -> bool {
+    let relative_path = path
+        .strip_prefix(scripts_folder_path)
+        .unwrap_or_else(|_| path.file_name().map_or(path, Path::new));
+    has_koto_extension(path)
+        && relative_path
+            .components()
+            .all(|component| !is_hidden_or_temporary(&component.as_os_str().to_string_lossy()))
+}
+
 fn collect_available_scripts_to(
     vector_to_collect_to: &mut Vec<String>,
     scripts_folder_path: &Path,
 ) -> Result<()> {
-    let script_paths = fs::read_dir(&scripts_folder_path)?;
-    // List and collect all scripts which has a ".koto" extension.
-    for path in script_paths {
-        let path_buf = path?.path();
-        match path_buf.extension() {
-            Some(extension) => match extension.to_str() {
-                Some(extension) => {
-                    if "koto" == extension {
-                        let full_path = format!("{}", path_buf.display());
-                        vector_to_collect_to.push(full_path);
-                    }
+    fn collect_recursively(
+        vector_to_collect_to: &mut Vec<String>,
+        folder_path: &Path,
+        scripts_folder_path: &Path,
+    ) -> Result<()> {
+        let script_paths = fs::read_dir(&folder_path)?;
+        // List and collect all scripts which has a ".koto" extension, also in sub folders.
+        for path in script_paths {
+            let path_buf = path?.path();
+            if path_buf.is_dir() {
+                let hidden = path_buf.file_name().map_or(false, |name| {
+                    is_hidden_or_temporary(&name.to_string_lossy())
+                });
+                if !hidden {
+                    collect_recursively(vector_to_collect_to, &path_buf, scripts_folder_path)?;
                 }
-                None => {
-                    continue;
-                }
-            },
-            None => {
                 continue;
             }
+            if is_koto_script(&path_buf, scripts_folder_path) {
+                let full_path = format!("{}", path_buf.display());
+                vector_to_collect_to.push(full_path);
+            }
         }
+        Ok(())
     }
+
+    collect_recursively(
+        vector_to_collect_to,
+        scripts_folder_path,
+        scripts_folder_path,
+    )?;
+    // "read_dir" doesn't guarantee any order, sort to keep the indexes stable.
+    vector_to_collect_to.sort();
     Ok(())
 }
 
-fn get_scripts_folder_path(home: &str) -> PathBuf {
-    let mut scripts_folder_path = PathBuf::new();
-    scripts_folder_path.push(&home);
-    scripts_folder_path.push(SCRIPTS_FOLDER_NAME);
-    scripts_folder_path
+/// Lists the scripts again after a change in the scripts folder.
+///
+/// A script given as a path stays the only one listed as long as it exists.
+fn recollect_available_scripts(context: &Context) -> Result<Vec<String>> {
+    let mut available_scripts = vec![];
+    if let Some(script_path) = &context.single_script {
+        if Path::new(script_path).exists() {
+            available_scripts.push(script_path.clone());
+        }
+    } else {
+        collect_available_scripts_to(&mut available_scripts, &context.scripts_folder_path)?;
+    }
+    Ok(available_scripts)
 }
 
-macro_rules! midi_listen_error {
-    ($l:literal) => {
-        Err(RuntimeError::with_prefix(
-            RuntimeError::from($l.to_owned()),
-            &"Error".magenta().to_string(),
-        ))
-    };
+/// Finds a script by its index in the list or by a part of its file name.
+fn find_script(available_scripts: &[String], name_or_index: &str) -> Option<usize> {
+    if let Ok(idx) = parse_choice(name_or_index, available_scripts.len()) {
+        return Some(idx);
+    }
+    available_scripts.iter().position(|script| {
+        Path::new(script).file_name().map_or(false, |file_name| {
+            file_name.to_string_lossy().contains(name_or_index)
+        })
+    })
 }
 
-#[allow(clippy::option_if_let_else)]
-fn call_midi_listen_with(message: &[u8], runtime: &mut Koto) -> Result<(), RuntimeError> {
-    if let Some(midi_value) = runtime.prelude().data().get_with_string("midi") {
-        if let Value::Map(midi_module_map) = midi_value {
-            if let Some(message_listener) = midi_module_map.data().get_with_string("listen") {
-                if let Value::Function(_) = message_listener {
-                    // Make a list of koto values from u8 slice.
-                    let message_values = message
-                        .iter()
-                        .map(|byte| Value::Number(byte.into()))
-                        .collect::<Vec<Value>>();
-                    // Call "midi.listen" function in script with the midi message.
-                    runtime
-                        .call_function(
-                            message_listener.clone(),
-                            &[Value::List(ValueList::from_slice(&message_values))],
-                        )
-                        .map(|_| ())
-                        .map_err(|err| {
-                            RuntimeError::with_prefix(
-                                RuntimeError::from(format!(
-                                    "Calling \"midi.listen\" is failed, {}",
-                                    err.to_string()
-                                )),
-                                &"Error".magenta().to_string(),
-                            )
-                        })
-                } else {
-                    midi_listen_error!("\"midi.listen\" is defined but it is not a function")
-                }
-            } else {
-                midi_listen_error!("Try defining a function as \"midi.listen\". If not there please try importing \"midi\" on top of your script like \"import midi\".")
+/// Copies the "midi" module so changes to the copy don't reach the original, "midi.args" included.
+fn copy_midi_module(midi_module: &ValueMap, args: &ValueMap) -> ValueMap {
+    let mut copy = ValueMap::with_data(midi_module.data().clone());
+    copy.add_map("args", ValueMap::with_data(args.data().clone()));
+    copy
+}
+
+/// A fresh runtime with "midi" and "random" in its prelude, the script path is set when the script is loaded.
+fn make_runtime(pristine_midi_module: &ValueMap, args: &ValueMap, seed: Option<u64>) -> Koto {
+    let runtime = Koto::default();
+    let mut prelude = runtime.prelude();
+    prelude.add_map("midi", copy_midi_module(pristine_midi_module, args));
+    prelude.add_value("random", make_random_module(seed));
+    runtime
+}
+
+/// The "random" module, seeded if "--seed" is given and seeded from entropy otherwise.
+fn make_random_module(seed: Option<u64>) -> Value {
+    seed.map_or_else(koto_random::make_module, koto_random::make_seeded_module)
+}
+
+/// Home directory to look for ".mep" in, "--home" if it is given.
+fn find_home(tui: &Tui, matches: &ArgMatches) -> Result<PathBuf> {
+    // "--home" overrides the discovered home directory, e.g. in containers where it is not where ".mep" lives.
+    if let Some(path) = matches.value_of("home") {
+        match fs::canonicalize(path) {
+            Ok(dir) if dir.is_dir() => Ok(dir),
+            _ => {
+                tui.clear_lines(1)?;
+                bail!(
+                    "{} {}",
+                    BULB,
+                    format!(
+                        "\"{}\" which is given with \"--home\" is not an existing directory.",
+                        path
+                    )
+                    .red()
+                );
             }
-        } else {
-            midi_listen_error!("\"midi\" has been found but it is not a map. Try importing \"midi\" on top of your script like \"import midi\". And do not use the same name further.")
         }
+    } else if let Some(dir) = home_dir() {
+        // Try to discover user's home directory
+        Ok(dir)
     } else {
-        midi_listen_error!("Try importing \"midi\" on top of your script like \"import midi\"")
+        tui.clear_lines(1)?;
+        bail!("{} {}", BULB, "\"mep\" couldn't determine the location of your home directory, to help it please run it with \"--home <absolute-path-to-your-home-directory>\"".blue());
     }
 }
 
+fn get_scripts_folder_path(home: &str) -> PathBuf {
+    let mut scripts_folder_path = PathBuf::new();
+    scripts_folder_path.push(&home);
+    scripts_folder_path.push(SCRIPTS_FOLDER_NAME);
+    scripts_folder_path
+}
+
+/// Path of a backup folder next to the scripts folder, suffixed with the current unix time in seconds.
+fn get_backup_folder_path(scripts_folder_path: &Path) -> Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut backup_folder_path = scripts_folder_path.as_os_str().to_owned();
+    backup_folder_path.push(format!(".bak-{}", timestamp));
+    Ok(PathBuf::from(backup_folder_path))
+}
+
 fn init_midi_io(
     command_line_options: &ArgMatches,
-) -> Result<(MidiInput, MidiOutput, String, String)> {
-    let mep_in = MidiInput::new("mep_input")?;
-    let mep_out = MidiOutput::new("mep_output")?;
-
+    config: &Config,
+    input_client_name: &str,
+    output_client_name: &str,
+) -> (Arc<dyn MidiBackend>, String, String) {
     let mut input_port_name = String::from("_in");
     let mut output_port_name = String::from("_out");
 
-    let mep_input_port_name = match command_line_options.value_of("port") {
-        Some(port_name) => {
+    // Precedence is "--port-in" > "--port" + "_in" > "mep_in", same for the output.
+    let mep_input_port_name = match (
+        command_line_options
+            .value_of("port-in")
+            .or_else(|| config.port_in.as_deref()),
+        command_line_options
+            .value_of("port")
+            .or_else(|| config.port.as_deref()),
+    ) {
+        (Some(port_name), _) => port_name.to_owned(),
+        (None, Some(port_name)) => {
             input_port_name.insert_str(0, port_name);
             input_port_name
         }
-        None => "mep_in".to_owned(),
+        (None, None) => "mep_in".to_owned(),
     };
-    let mep_output_port_name = match command_line_options.value_of("port") {
-        Some(port_name) => {
+    let mep_output_port_name = match (
+        command_line_options
+            .value_of("port-out")
+            .or_else(|| config.port_out.as_deref()),
+        command_line_options
+            .value_of("port")
+            .or_else(|| config.port.as_deref()),
+    ) {
+        (Some(port_name), _) => port_name.to_owned(),
+        (None, Some(port_name)) => {
             output_port_name.insert_str(0, port_name);
             output_port_name
         }
-        None => "mep_out".to_owned(),
+        (None, None) => "mep_out".to_owned(),
     };
-    Ok((mep_in, mep_out, mep_input_port_name, mep_output_port_name))
+    (
+        Arc::new(Midir::new(input_client_name, output_client_name)),
+        mep_input_port_name,
+        mep_output_port_name,
+    )
 }
 
-fn spawn_stdin_channel() -> Receiver<String> {
-    let (stdin_to_main, from_stdin) = channel::<String>();
-    std::thread::spawn(move || -> Result<Receiver<String>> {
-        loop {
-            let mut choice = String::new();
-            if stdin().read_line(&mut choice).is_ok() {
-                stdin_to_main.send(choice)?;
+/// Opens `count` output ports, the first one connects to an existing port if `connect_out` is given.
+///
+/// The first port is named `output_port_name` and the rest are suffixed with their index, e.g. `mep_out_1`.
+/// Returns the name of the existing port too, if one is connected.
+fn open_output_ports(
+    backend: &dyn MidiBackend,
+    output_port_name: &str,
+    count: usize,
+    connect_out: Option<&str>,
+) -> Result<(Vec<Output>, Option<String>)> {
+    let mut ports = Vec::with_capacity(count);
+    let mut connected_port_name = None;
+    for index in 0..count {
+        let port_name = indexed_output_port_name(output_port_name, index);
+        let connect_to = if index == 0 { connect_out } else { None };
+        let (connection, connected) = backend.open_output(&port_name, connect_to)?;
+        if index == 0 {
+            connected_port_name = connected;
+        }
+        ports.push(Output::Port(connection));
+    }
+    Ok((ports, connected_port_name))
+}
+
+/// Names of the outputs given to "--outputs" instead of a count, in the order of the ports.
+fn parse_output_names(outputs: &str) -> Result<Vec<String>, String> {
+    let mut output_names: Vec<String> = Vec::new();
+    for name in outputs.split(',').map(str::trim) {
+        if name.is_empty() {
+            return Err(
+                "Please provide a positive integer or comma separated output names.".to_owned(),
+            );
+        }
+        if output_names.iter().any(|known| known == name) {
+            return Err(format!(
+                "The output name \"{}\" is given more than once.",
+                name
+            ));
+        }
+        output_names.push(name.to_owned());
+    }
+    Ok(output_names)
+}
+
+/// Name of the output port at `index`, the first one is not suffixed.
+fn indexed_output_port_name(output_port_name: &str, index: usize) -> String {
+    if index == 0 {
+        output_port_name.to_owned()
+    } else {
+        format!("{}_{}", output_port_name, index)
+    }
+}
+
+/// Finds the name which the OS lists a port that "mep" created with, falls back to `port_name`.
+///
+/// Some platforms decorate the names of virtual ports, e.g. "mep_output:mep_out 129:0" with ALSA.
+fn resolve_os_port_name(os_port_names: &[String], port_name: &str) -> String {
+    // Matching a plain substring would confuse "mep_out" with "mep_out_1".
+    let suffixed = format!("{} ", port_name);
+    os_port_names
+        .iter()
+        .find(|name| name.ends_with(port_name) || name.contains(&suffixed))
+        .cloned()
+        .unwrap_or_else(|| port_name.to_owned())
+}
+
+/// Handles the user's input in the script picker, it might move the highlight or search in the list.
+///
+/// `picker_index` is the index of the highlighted script in the whole list, `None` if nothing is highlighted.
+/// Returns the index of the script to run if one is chosen.
+fn handle_picker_input(
+    tui: &Tui,
+    input: UserInput,
+    picker_index: &mut Option<usize>,
+    available_scripts: &[String],
+    scripts_folder_path: &Path,
+) -> Result<Option<usize>> {
+    let render = |picker_index: Option<usize>| match picker_index {
+        Some(idx) => {
+            tui.highlight_and_render(&idx.to_string(), available_scripts, scripts_folder_path)
+        }
+        None => tui.list_scripts(available_scripts, scripts_folder_path),
+    };
+    match input {
+        // Check the freshly entered index against the current list,
+        // scripts might have been added or removed since the start.
+        UserInput::Choice(choice) => match parse_choice(&choice, available_scripts.len()) {
+            Ok(idx) => return Ok(Some(idx)),
+            // Tell why and let the user try again.
+            Err(err) => {
+                tui.ignore_choice(&choice)?;
+                tui.log(&err.to_string())?;
+            }
+        },
+        UserInput::Commit => match *picker_index {
+            // Only a listed script can be chosen while searching.
+            Some(idx) if tui.visible_scripts(available_scripts).contains(&idx) => {
+                tui.set_filter(None);
+                return Ok(Some(idx));
             }
+            _ => tui.ignore_choice("")?,
+        },
+        UserInput::Up => {
+            *picker_index =
+                move_picker(*picker_index, true, &tui.visible_scripts(available_scripts));
+            render(*picker_index)?;
         }
-    });
-    from_stdin
+        UserInput::Down => {
+            *picker_index = move_picker(
+                *picker_index,
+                false,
+                &tui.visible_scripts(available_scripts),
+            );
+            render(*picker_index)?;
+        }
+        UserInput::Search(query) => {
+            let searching = query.is_some();
+            tui.set_filter(query);
+            if searching {
+                // Highlight the top match so "enter" chooses it.
+                *picker_index = tui.visible_scripts(available_scripts).first().copied();
+            }
+            render(*picker_index)?;
+        }
+        UserInput::Typed(typed) => tui.echo_choice(&typed)?,
+        // Render the page which fits in the new size, unless something else than the list is shown.
+        UserInput::Resize if tui.is_prompt_visible() => render(*picker_index)?,
+        UserInput::Resize => {}
+        // Nothing is running yet to reload.
+        UserInput::Reload => {}
+    }
+    Ok(None)
 }
 
-fn try_debug(
+/// Why an entered index doesn't choose a script.
+#[derive(Debug, PartialEq, Eq)]
+enum ChoiceError {
+    NotANumber(String),
+    /// A number, but there is no script with this index. Negative numbers and ones too large for an index are here too.
+    OutOfRange(String, usize),
+}
+
+impl std::fmt::Display for ChoiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotANumber(choice) => write!(
+                f,
+                "\"{}\" is not a number, please enter the index of a script.",
+                choice
+            ),
+            Self::OutOfRange(choice, 0) => {
+                write!(
+                    f,
+                    "There is no script with index {}, the list is empty.",
+                    choice
+                )
+            }
+            Self::OutOfRange(choice, script_count) => write!(
+                f,
+                "There is no script with index {}, please enter one from 0 to {}.",
+                choice,
+                script_count - 1
+            ),
+        }
+    }
+}
+
+/// Parses an index entered to choose one of `script_count` scripts.
+fn parse_choice(choice: &str, script_count: usize) -> Result<usize, ChoiceError> {
+    let choice = choice.trim();
+    match choice.parse::<usize>() {
+        Ok(idx) if idx < script_count => Ok(idx),
+        Ok(_) => Err(ChoiceError::OutOfRange(choice.to_owned(), script_count)),
+        Err(_) => {
+            let digits = choice.strip_prefix('-').unwrap_or(choice);
+            if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+                // Negative or too large to parse.
+                Err(ChoiceError::OutOfRange(choice.to_owned(), script_count))
+            } else {
+                Err(ChoiceError::NotANumber(choice.to_owned()))
+            }
+        }
+    }
+}
+
+/// Moves the highlight in the script picker one row up or down, staying in the listed scripts.
+///
+/// If nothing or a script which is not listed is highlighted, the first listed script is highlighted.
+fn move_picker(picker_index: Option<usize>, up: bool, visible_scripts: &[usize]) -> Option<usize> {
+    let position = match picker_index
+        .and_then(|idx| visible_scripts.iter().position(|visible| *visible == idx))
+    {
+        None => 0,
+        Some(position) if up => position.saturating_sub(1),
+        Some(position) => position
+            .saturating_add(1)
+            .min(visible_scripts.len().saturating_sub(1)),
+    };
+    visible_scripts.get(position).copied()
+}
+
+/// Runs the script at `index` of the list and renders the list with it highlighted.
+fn switch_to_script(
     tui: &Tui,
-    watcher_channel: &Receiver<WatcherToMainMessage>,
-    runtime: &mut Koto,
+    from_watcher: &Receiver<WatcherToMainMessage>,
     context: &mut Context,
+    runtime: &mut Koto,
+    index: usize,
+    mep_out_ports: &[OutputPort],
 ) -> Result<()> {
-    if let Ok(message_from_watcher) = watcher_channel.try_recv() {
-        use WatcherToMainMessage::*;
-        match message_from_watcher {
-            NoticeWrite(path) => {
-                // We need to make this path shared.
-                context.chosen_script_path = path.to_string_lossy().into();
-                context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
-                if compile_run_block_until_valid(tui, watcher_channel, context, runtime).is_ok() {
-                    // Script fixed or there was no problem.
-                    tui.highlight_and_render(
-                        &context.chosen_index_checked.to_string(),
-                        &context.available_scripts,
-                    )?;
-                    return Ok(());
+    choose_index(context, index, mep_out_ports);
+    reload_script(tui, from_watcher, context, runtime)
+}
+
+/// Chooses the script at the index the user entered while a script is running.
+///
+/// Returns `false` and leaves the context as it is if the index doesn't choose a script.
+fn choose_script(
+    tui: &Tui,
+    context: &mut Context,
+    choice: &str,
+    mep_out_ports: &[OutputPort],
+) -> Result<bool> {
+    // Check the freshly entered index against the current list,
+    // scripts might have been added or removed since the start.
+    match parse_choice(choice, context.available_scripts.len()) {
+        Ok(index) => {
+            choose_index(context, index, mep_out_ports);
+            Ok(true)
+        }
+        // Tell why and let the user try again.
+        Err(err) => {
+            tui.ignore_choice(choice)?;
+            tui.log(&err.to_string())?;
+            Ok(false)
+        }
+    }
+}
+
+/// Makes the script at `index` of the list the chosen one, it is run once it is read again.
+fn choose_index(context: &mut Context, index: usize, mep_out_ports: &[OutputPort]) {
+    // The script which is left won't release the notes it is playing, its setting decides.
+    if index != context.chosen_index_checked
+        && context.script_state.panic_on_switch.load(Ordering::Relaxed)
+    {
+        send_panic(mep_out_ports);
+    }
+    context.chosen_index_checked = index;
+    context.chosen_script_path = context.available_scripts[index].clone();
+}
+
+/// Reads the running script from the disk and loads it again, e.g. when "r" is pressed.
+fn reload_script(
+    tui: &Tui,
+    from_watcher: &Receiver<WatcherToMainMessage>,
+    context: &mut Context,
+    runtime: &mut Koto,
+) -> Result<()> {
+    context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
+    compile_run_block_until_valid(tui, from_watcher, context, runtime)?;
+    tui.highlight_and_render(
+        &context.chosen_index_checked.to_string(),
+        &context.available_scripts,
+        &context.scripts_folder_path,
+    )
+}
+
+/// Reads the user's input in its own thread.
+///
+/// In raw mode keys are read one by one, otherwise whole lines are read, e.g. when stdin is piped.
+fn spawn_user_input_channel(raw_mode: bool, interrupt_to_main: Sender<()>) -> Receiver<UserInput> {
+    let (input_to_main, from_input) = unbounded::<UserInput>();
+    std::thread::spawn(move || -> Result<()> {
+        if !raw_mode {
+            loop {
+                let mut choice = String::new();
+                match stdin().read_line(&mut choice) {
+                    // End of file, dropping the sender lets main know that stdin is closed.
+                    Ok(0) | Err(_) => return Ok(()),
+                    Ok(_) => input_to_main.send(UserInput::Choice(choice))?,
                 }
             }
-            NoticeRemove(_) => {
-                // Get diff
-                let mut new_available_scripts = vec![];
-                collect_available_scripts_to(
-                    &mut new_available_scripts,
+        }
+        let mut typed = String::new();
+        // Typing after "/" searches in the list until "enter" or "esc" is pressed.
+        let mut search_query: Option<String> = None;
+        loop {
+            let (code, modifiers) = match event::read()? {
+                Event::Key(KeyEvent { code, modifiers }) => (code, modifiers),
+                // The list is paginated by the height of the terminal.
+                Event::Resize(_, _) => {
+                    input_to_main.send(UserInput::Resize)?;
+                    continue;
+                }
+                Event::Mouse(_) => continue,
+            };
+            // Raw mode doesn't raise a signal for Ctrl-C.
+            if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+                let _ = interrupt_to_main.try_send(());
+                continue;
+            }
+            let input = match code {
+                KeyCode::Up => UserInput::Up,
+                KeyCode::Down => UserInput::Down,
+                KeyCode::Char(character) if search_query.is_some() => {
+                    let query = search_query.get_or_insert_with(String::new);
+                    query.push(character);
+                    UserInput::Search(Some(query.clone()))
+                }
+                KeyCode::Backspace if search_query.is_some() => {
+                    let query = search_query.get_or_insert_with(String::new);
+                    query.pop();
+                    UserInput::Search(Some(query.clone()))
+                }
+                KeyCode::Esc if search_query.is_some() => {
+                    search_query = None;
+                    UserInput::Search(None)
+                }
+                KeyCode::Enter if search_query.is_some() => {
+                    search_query = None;
+                    UserInput::Commit
+                }
+                // Quits like "ctrl-c" does.
+                KeyCode::Char('q') => {
+                    let _ = interrupt_to_main.try_send(());
+                    continue;
+                }
+                KeyCode::Char('r') => {
+                    typed.clear();
+                    UserInput::Reload
+                }
+                KeyCode::Char('/') => {
+                    typed.clear();
+                    search_query = Some(String::new());
+                    UserInput::Search(Some(String::new()))
+                }
+                KeyCode::Char(digit) if digit.is_ascii_digit() => {
+                    typed.push(digit);
+                    UserInput::Typed(typed.clone())
+                }
+                KeyCode::Backspace => {
+                    typed.pop();
+                    UserInput::Typed(typed.clone())
+                }
+                KeyCode::Esc => {
+                    typed.clear();
+                    UserInput::Typed(typed.clone())
+                }
+                KeyCode::Enter if typed.is_empty() => UserInput::Commit,
+                KeyCode::Enter => UserInput::Choice(std::mem::take(&mut typed)),
+                _ => continue,
+            };
+            input_to_main.send(input)?;
+        }
+    });
+    from_input
+}
+
+/// Drops the writes to the same script which are already waiting behind `message_from_watcher`,
+/// the script is read when it is compiled so compiling it once picks up the latest contents.
+///
+/// Returns the message to act on and the first waiting message which is not a write to the same script, if any.
+fn coalesce_writes(
+    message_from_watcher: WatcherToMainMessage,
+    from_watcher: &Receiver<WatcherToMainMessage>,
+) -> (WatcherToMainMessage, Option<WatcherToMainMessage>) {
+    let written_path = if let WatcherToMainMessage::NoticeWrite(path) = &message_from_watcher {
+        path.clone()
+    } else {
+        return (message_from_watcher, None);
+    };
+    // Don't block, only what has already arrived is coalesced.
+    while let Ok(next_message) = from_watcher.try_recv() {
+        match next_message {
+            WatcherToMainMessage::NoticeWrite(path) if path == written_path => {}
+            next_message => return (message_from_watcher, Some(next_message)),
+        }
+    }
+    (message_from_watcher, None)
+}
+
+/// What happens to the chosen script after a script is removed from the scripts folder.
+#[derive(Debug, PartialEq, Eq)]
+enum ChosenAfterRemove {
+    /// Another script is removed, the chosen one is at this index of the new list.
+    Unchanged(usize),
+    /// The chosen script is renamed, to this path at this index of the new list.
+    Renamed(String, usize),
+    /// The chosen script is removed, the first script in the new list is chosen instead.
+    FellBack(String, usize),
+    /// The chosen script is removed and there are no scripts left.
+    NoScripts,
+}
+
+/// Tells whether the chosen script is renamed or removed by comparing the lists of scripts before and after the change.
+///
+/// A rename shows up as a removal of the old path and a path which wasn't in the old list.
+fn find_chosen_after_remove(
+    old_scripts: &[String],
+    new_scripts: &[String],
+    chosen_script_path: &str,
+) -> ChosenAfterRemove {
+    if let Some(index) = new_scripts
+        .iter()
+        .position(|script| script == chosen_script_path)
+    {
+        return ChosenAfterRemove::Unchanged(index);
+    }
+    if let Some((index, renamed_path)) = new_scripts
+        .iter()
+        .enumerate()
+        .find(|(_, script)| !old_scripts.contains(script))
+    {
+        return ChosenAfterRemove::Renamed(renamed_path.clone(), index);
+    }
+    new_scripts
+        .first()
+        .map_or(ChosenAfterRemove::NoScripts, |first_script| {
+            ChosenAfterRemove::FellBack(first_script.clone(), 0)
+        })
+}
+
+fn try_debug(
+    tui: &Tui,
+    message_from_watcher: WatcherToMainMessage,
+    watcher_channel: &Receiver<WatcherToMainMessage>,
+    runtime: &mut Koto,
+    context: &mut Context,
+) -> Result<()> {
+    use WatcherToMainMessage::*;
+    match message_from_watcher {
+        NoticeWrite(path) => {
+            let script_path = if let Some(script_path) = script_to_load_after_write(context, &path)
+            {
+                script_path
+            } else {
+                // Another script next to the single one is changed.
+                return Ok(());
+            };
+            tui.write_to_log_file(
+                LogEvent::Reload,
+                Some(&script_path),
+                &format!("Reloading {}", script_path),
+            );
+            // We need to make this path shared.
+            context.chosen_script_path = script_path;
+            context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
+            if compile_run_block_until_valid(tui, watcher_channel, context, runtime).is_ok() {
+                // Script fixed or there was no problem.
+                tui.highlight_and_render(
+                    &context.chosen_index_checked.to_string(),
+                    &context.available_scripts,
                     &context.scripts_folder_path,
                 )?;
-                let modified_script_path: String = new_available_scripts
-                    .clone()
-                    .into_iter()
-                    .filter(|item| !context.available_scripts.contains(item))
-                    .collect();
-                // dbg!(&context.chosen_script_path,&path,modified_script_path);
-                // Replace available scripts.
-                std::mem::swap(&mut context.available_scripts, &mut new_available_scripts);
-
-                if fs::read_to_string(&context.chosen_script_path).is_err() {
-                    // dbg!(&context.chosen_script_path,&path);
-                    // std::thread::sleep(Duration::from_secs(4));
-                    // Either the currently chosen script is removed or renamed.
-                    if fs::read_to_string(&modified_script_path).is_ok() {
-                        // Script is renamed
-                        context.chosen_script = fs::read_to_string(&modified_script_path)?;
-                        context.chosen_script_path = modified_script_path;
-                        // Update chosen index.
-                        for (i, path) in context.available_scripts.iter().enumerate() {
-                            if *path == context.chosen_script_path {
-                                context.chosen_index_checked = i;
-                            }
-                        }
-                    } else {
-                        // Script is removed, check if there are available scripts.
-                        // "~/.mep" folder is empty
-                        if context.available_scripts.is_empty() {
-                            tui.clear_lines(1)?;
-                            bail!(
-                                "{} {}",
-                                BULB,
-                                "There are no event processor scripts found in \"~/.mep\". Maybe put a couple?".blue()
-                            );
-                        }
-
-                        // Fall back to first script in the list
-                        context.chosen_script = fs::read_to_string(&context.available_scripts[0])?;
-                        context.chosen_script_path = context.available_scripts[0].clone();
-                        context.chosen_index_checked = 0;
-                    }
+                return Ok(());
+            }
+        }
+        NoticeRemove(path) => {
+            tui.write_to_log_file(
+                LogEvent::Remove,
+                Some(&path.to_string_lossy()),
+                &format!("Removed {}", path.display()),
+            );
+            let new_available_scripts = recollect_available_scripts(context)?;
+            let chosen_after_remove = find_chosen_after_remove(
+                &context.available_scripts,
+                &new_available_scripts,
+                &context.chosen_script_path,
+            );
+            // Replace available scripts.
+            context.available_scripts = new_available_scripts;
+
+            match chosen_after_remove {
+                ChosenAfterRemove::Unchanged(index) => {
+                    // Another script is removed.
+                    // Just re-render, the chosen script might have moved in the list.
+                    context.chosen_index_checked = index;
+                    tui.highlight_and_render(
+                        &context.chosen_index_checked.to_string(),
+                        &context.available_scripts,
+                        &context.scripts_folder_path,
+                    )?;
+                    return Ok(());
+                }
+                ChosenAfterRemove::NoScripts => {
+                    // "~/.mep" folder is empty
+                    tui.clear_lines(1)?;
+                    bail!(
+                        "{} {}",
+                        BULB,
+                        "There are no event processor scripts found in \"~/.mep\". Maybe put a couple?"
+                            .blue()
+                    );
+                }
+                ChosenAfterRemove::Renamed(path, index)
+                | ChosenAfterRemove::FellBack(path, index) => {
+                    context.chosen_script = fs::read_to_string(&path)?;
+                    context.chosen_script_path = path;
+                    context.chosen_index_checked = index;
                     // Run new script
                     if compile_run_block_until_valid(tui, watcher_channel, context, runtime).is_ok()
                     {
@@ -661,52 +2347,103 @@ fn try_debug(
                         tui.highlight_and_render(
                             &context.chosen_index_checked.to_string(),
                             &context.available_scripts,
+                            &context.scripts_folder_path,
                         )?;
                         return Ok(());
                     }
-                } else {
-                    // Another script is removed.
-                    // Just re-render.
+                }
+            }
+        }
+        Create(path) => {
+            tui.write_to_log_file(
+                LogEvent::Create,
+                Some(&path.to_string_lossy()),
+                &format!("Created {}", path.display()),
+            );
+            let mut new_available_scripts = recollect_available_scripts(context)?;
+            std::mem::swap(&mut context.available_scripts, &mut new_available_scripts);
+
+            let created_script_path: String = path.to_string_lossy().into();
+            let created_script_index = context
+                .available_scripts
+                .iter()
+                .position(|script| *script == created_script_path);
 
-                    tui.highlight_and_render(
-                        &context.chosen_index_checked.to_string(),
-                        &context.available_scripts,
-                    )?;
-                    return Ok(());
+            match created_script_index {
+                Some(index) if context.follow_new => {
+                    // Switch to the new script.
+                    context.chosen_index_checked = index;
+                    context.chosen_script_path = created_script_path;
+                    context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
+                    compile_run_block_until_valid(tui, watcher_channel, context, runtime)?;
+                }
+                _ => {
+                    // Just re-list the scripts with the existing choice.
+                    // The new script might have shifted the sorted list so find the chosen one again.
+                    if let Some(index) = context
+                        .available_scripts
+                        .iter()
+                        .position(|script| *script == context.chosen_script_path)
+                    {
+                        context.chosen_index_checked = index;
+                    }
                 }
             }
-            Create(_) => {
-                // Just re-list the scripts with the existing choice.
-                let mut new_available_scripts = vec![];
-                collect_available_scripts_to(
-                    &mut new_available_scripts,
-                    &context.scripts_folder_path,
-                )?;
-                std::mem::swap(&mut context.available_scripts, &mut new_available_scripts);
 
-                tui.highlight_and_render(
-                    &context.chosen_index_checked.to_string(),
-                    &context.available_scripts,
-                )?;
+            tui.highlight_and_render(
+                &context.chosen_index_checked.to_string(),
+                &context.available_scripts,
+                &context.scripts_folder_path,
+            )?;
+            return Ok(());
+        }
+        ScriptsFolderRemoved => {
+            tui.write_to_log_file(
+                LogEvent::FolderRemoved,
+                None,
+                "\"~/.mep\" folder is removed, recreating it",
+            );
+            if !recreate_scripts_folder(context)? {
+                tui.log(&format!(
+                    "\"{}\" folder is removed, please restore it.",
+                    context.scripts_folder_path.display()
+                ))?;
                 return Ok(());
             }
-            Error(err, path) => {
-                let p: String = match path {
-                    Some(path) => path.to_string_lossy().into(),
-                    None => "".into(),
-                };
-                tui.clear_lines(1)?;
-                bail!(
-                    "{} {} with the script located in {}. Message: {}",
-                    BULB,
-                    "Error ".magenta(),
-                    p,
-                    err.to_string(),
-                );
-            }
+            compile_run_block_until_valid(tui, watcher_channel, context, runtime)?;
+
+            // Back to the list so another script can be chosen.
+            tui.highlight_and_render(
+                &context.chosen_index_checked.to_string(),
+                &context.available_scripts,
+                &context.scripts_folder_path,
+            )?;
+            tui.log("\"~/.mep\" folder was removed, it is recreated with example scripts.")?;
+            return Ok(());
+        }
+        WatcherFailed(reason, persistent) => {
+            return log_watcher_failure(tui, &reason, persistent);
+        }
+        WatcherRecovered => {
+            tui.write_to_log_file(LogEvent::Watcher, None, "The watcher has recovered");
+            return tui.log("Watching \"~/.mep\" folder again, changes are reloaded.");
+        }
+        Error(err, path) => {
+            let p: String = match path {
+                Some(path) => path.to_string_lossy().into(),
+                None => "".into(),
+            };
+            tui.clear_lines(1)?;
+            bail!(
+                "{} {} with the script located in {}. Message: {}",
+                BULB,
+                "Error ".magenta(),
+                p,
+                err.to_string(),
+            );
         }
     }
-    Err(anyhow!("No change in \"~/.mep\" folder detected."))
+    Err(anyhow!("Couldn't apply the change in \"~/.mep\" folder."))
 }
 
 fn compile_run_block_until_valid(
@@ -715,57 +2452,395 @@ fn compile_run_block_until_valid(
     context: &mut Context,
     runtime: &mut Koto,
 ) -> Result<()> {
+    // Every script starts from a clean state, e.g. listening to all channels without a running clock.
+    context.script_state.reset();
+    // Imported scripts are compiled again, they might have changed since they were imported.
+    runtime.clear_module_cache();
+    // A single script has no scripts folder to import from.
+    context.dependencies = script_dependencies(
+        Path::new(&context.chosen_script_path),
+        &context.chosen_script,
+        context
+            .single_script
+            .is_none()
+            .then(|| context.scripts_folder_path.as_path()),
+    );
+    let shared_modules_added = add_shared_modules(runtime, context);
+    // Imports and data files are found relative to the script.
+    context.script_state.set_path(&context.chosen_script_path);
+    if let Err(err) = shared_modules_added {
+        return show_error_and_wait_for_fix(
+            tui,
+            ErrorCategory::Runtime,
+            &err,
+            from_watcher,
+            context,
+            runtime,
+        );
+    }
+    // A previously loaded script's startup hook shouldn't run for this one.
+    if let Some(Value::Map(mut midi_module_map)) =
+        runtime.prelude().data().get_with_string("midi").cloned()
+    {
+        midi_module_map.add_value("startup", Value::Empty);
+    }
     match runtime.compile(&context.chosen_script) {
-        Ok(chunk) => match runtime.run_chunk(chunk) {
-            Ok(_) => Ok(()),
+        // Run the script and then its startup hook once, before any messages flow.
+        Ok(chunk) => match runtime
+            .run_chunk(chunk)
+            .and_then(|_| call_midi_startup(runtime))
+        {
+            Ok(_) => {
+                // Starting over while the script is loaded would load it again and again.
+                context
+                    .script_state
+                    .reset_requested
+                    .store(false, Ordering::Relaxed);
+                // Some scripts only send, so this is not an error but they won't react to the input.
+                if !has_midi_listener(runtime) {
+                    tui.warn("\"midi.listen\" is not defined, the script won't react to incoming messages.")?;
+                }
+                Ok(())
+            }
             Err(err) => {
                 // Runtime time error found in script.
-                tui.clear()?;
-                tui.show_error(&context.chosen_script_path, &err.to_string())?;
-                loop {
-                    if let Ok(WatcherToMainMessage::NoticeWrite(path)) = from_watcher.recv() {
-                        // A fix attempt had been made.
-                        context.chosen_script_path = path.to_string_lossy().into();
-                        context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
-                        match compile_run_block_until_valid(tui, from_watcher, context, runtime) {
-                            Ok(_) => {
-                                // Script is fixed.
-                                return Ok(());
-                            }
-                            Err(_) => {
-                                // Didn't work out try one more time.
-                                continue;
-                            }
-                        }
-                    }
-                }
+                show_error_and_wait_for_fix(
+                    tui,
+                    ErrorCategory::Runtime,
+                    &err.to_string(),
+                    from_watcher,
+                    context,
+                    runtime,
+                )
             }
         },
         Err(err) => {
             // Compile time error found in script.
-            tui.clear()?;
-            tui.show_error(&context.chosen_script_path, &err.to_string())?;
-            loop {
-                if let Ok(WatcherToMainMessage::NoticeWrite(path)) = from_watcher.recv() {
-                    // A fix attempt had been made.
-                    context.chosen_script_path = path.to_string_lossy().into();
-                    context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
-                    match compile_run_block_until_valid(tui, from_watcher, context, runtime) {
-                        Ok(_) => {
-                            // Script is fixed.
-                            return Ok(());
-                        }
-                        Err(_) => {
-                            // Didn't work out try one more time.
-                            continue;
-                        }
-                    }
+            show_error_and_wait_for_fix(
+                tui,
+                ErrorCategory::Compile,
+                &err.to_string(),
+                from_watcher,
+                context,
+                runtime,
+            )
+        }
+    }
+}
+
+/// Path of the script to load after the script at `path` is written.
+///
+/// It is the running script if `path` is imported by it, `None` if only a single script is run and `path` is another one.
+fn script_to_load_after_write(context: &Context, path: &Path) -> Option<String> {
+    if context
+        .dependencies
+        .iter()
+        .any(|dependency| dependency.path == path)
+    {
+        return Some(context.chosen_script_path.clone());
+    }
+    match &context.single_script {
+        Some(script_path) if Path::new(script_path) != path => None,
+        _ => Some(path.to_string_lossy().into()),
+    }
+}
+
+/// Scripts which are imported by the script at `script_path`, also the ones which they import.
+///
+/// Like koto does, a module is looked up next to the script which imports it. If it isn't there it is looked up
+/// in `scripts_folder`, from where `add_shared_modules` imports it.
+fn script_dependencies(
+    script_path: &Path,
+    script: &str,
+    scripts_folder: Option<&Path>,
+) -> Vec<Dependency> {
+    let mut dependencies: Vec<Dependency> = Vec::new();
+    let mut to_visit = vec![(script_path.to_path_buf(), script.to_owned())];
+    while let Some((path, source)) = to_visit.pop() {
+        let folder = path.parent().unwrap_or_else(|| Path::new(""));
+        for module in imported_modules(&source) {
+            let (module_path, from_scripts_folder) = match (
+                module_path(folder, module),
+                scripts_folder.and_then(|scripts_folder| module_path(scripts_folder, module)),
+            ) {
+                (Some(module_path), _) => (module_path, false),
+                (None, Some(module_path)) => (module_path, true),
+                // Not a script, e.g. "midi" of the prelude.
+                (None, None) => continue,
+            };
+            if module_path != script_path
+                && !dependencies
+                    .iter()
+                    .any(|dependency| dependency.path == module_path)
+            {
+                if let Ok(source) = fs::read_to_string(&module_path) {
+                    to_visit.push((module_path.clone(), source));
+                }
+                dependencies.push(Dependency {
+                    module: module.to_owned(),
+                    path: module_path,
+                    from_scripts_folder,
+                });
+            }
+        }
+    }
+    dependencies
+}
+
+/// Path of the script which koto imports as `module` from `folder`, "<module>.koto" or else "<module>/main.koto".
+///
+/// The parts of a dotted module are folders, "lib.helpers" is "lib/helpers.koto". Without such a script
+/// "helpers" is looked up in what the "lib" module exports.
+fn module_path(folder: &Path, module: &str) -> Option<PathBuf> {
+    let nested = folder.join(module.split('.').collect::<PathBuf>());
+    let root = module.split('.').next().unwrap_or(module);
+    [
+        nested.with_extension("koto"),
+        nested.join("main.koto"),
+        folder.join(format!("{}.koto", root)),
+        folder.join(root).join("main.koto"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+/// The first part of a dotted module, the name koto looks up in the prelude, e.g. "lib" for "lib.helpers".
+fn module_root(module: &str) -> &str {
+    module.split('.').next().unwrap_or(module)
+}
+
+/// Adds the modules which the running script imports from the scripts folder to the prelude under their names.
+///
+/// Koto only looks for a module next to the script which imports it, after looking in the prelude. So a script
+/// in a sub folder can import e.g. "lib/main.koto" from the root of the scripts folder as "lib".
+/// The modules added for the previous script are removed first, the module cache should be cleared before.
+/// The running script's path is set afterwards, also if an import fails.
+fn add_shared_modules(runtime: &mut Koto, context: &mut Context) -> Result<(), String> {
+    let added = import_shared_modules(runtime, context);
+    runtime.set_script_path(Some(PathBuf::from(&context.chosen_script_path)));
+    added
+}
+
+/// Imports the shared modules of `add_shared_modules` as if by a script in the scripts folder.
+fn import_shared_modules(runtime: &mut Koto, context: &mut Context) -> Result<(), String> {
+    for module in context.shared_modules.drain(..) {
+        runtime
+            .prelude()
+            .data_mut()
+            .remove(&ValueKey::from(Value::Str(module.as_str().into())));
+    }
+    let shared_modules = context
+        .dependencies
+        .iter()
+        .filter(|dependency| dependency.from_scripts_folder)
+        // It would shadow a module with the same name next to the script which imports that one.
+        .filter(|dependency| {
+            !context.dependencies.iter().any(|other| {
+                !other.from_scripts_folder
+                    && module_root(&other.module) == module_root(&dependency.module)
+            })
+        })
+        .map(|dependency| dependency.module.clone())
+        .collect::<Vec<String>>();
+    for module in shared_modules {
+        let root = module_root(&module).to_owned();
+        // The modules of the prelude, e.g. "midi", are imported before any script anyway.
+        if !context.shared_modules.contains(&root)
+            && runtime.prelude().data().get_with_string(&root).is_some()
+        {
+            continue;
+        }
+        // Imported as if by a script in the scripts folder, so koto finds it there.
+        runtime.set_script_path(Some(context.scripts_folder_path.join("mep_import.koto")));
+        // "import lib.helpers" binds "helpers".
+        let name = module.rsplit('.').next().unwrap_or(&module);
+        let chunk = runtime
+            .compile(&format!("import {}\n{}\n", module, name))
+            .map_err(|err| format!("Importing \"{}\" failed, {}", module, err))?;
+        let imported = runtime
+            .run_chunk(chunk)
+            .map_err(|err| format!("Importing \"{}\" failed, {}", module, err))?;
+        add_to_prelude(runtime, &module, imported);
+        if !context.shared_modules.contains(&root) {
+            context.shared_modules.push(root);
+        }
+    }
+    Ok(())
+}
+
+/// Adds `value` to the prelude under a dotted `module`, e.g. "lib.helpers" as "helpers" in a "lib" map.
+fn add_to_prelude(runtime: &mut Koto, module: &str, value: Value) {
+    let mut map = runtime.prelude();
+    let mut parts = module.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            map.add_value(part, value);
+            return;
+        }
+        let existing = map.data().get_with_string(part).cloned();
+        map = if let Some(Value::Map(existing)) = existing {
+            existing
+        } else {
+            let nested = ValueMap::default();
+            map.add_map(part, nested.clone());
+            nested
+        };
+    }
+}
+
+/// Names of the modules in "import <module>, .." and "from <module> import .." lines of a script.
+///
+/// The script is only scanned line by line, not parsed, imports inside comments are skipped.
+/// Modules which are not files, e.g. "midi", are left to be filtered out by the caller.
+fn imported_modules(script: &str) -> Vec<&str> {
+    let mut in_block_comment = false;
+    script
+        .lines()
+        .map(str::trim_start)
+        .filter_map(move |line| {
+            // "#- .. -#" comments span lines, "#" comments end with the line.
+            if in_block_comment {
+                in_block_comment = !line.contains("-#");
+                return None;
+            }
+            if line.starts_with("#-") {
+                in_block_comment = !line.contains("-#");
+                return None;
+            }
+            Some(line.split('#').next().unwrap_or_default())
+        })
+        .flat_map(|line| {
+            let modules = if let Some(modules) = line.strip_prefix("import ") {
+                modules
+            } else if let Some(from) = line.strip_prefix("from ") {
+                from.split(" import").next().unwrap_or_default()
+            } else {
+                ""
+            };
+            modules.split(',').map(str::trim)
+        })
+        // "lib.helpers" is kept whole, it might be "lib/helpers.koto" or "helpers" of the "lib" module.
+        .filter(|module| {
+            module.split('.').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|character| character.is_alphanumeric() || character == '_')
+            })
+        })
+        .collect()
+}
+
+/// Shows the error of the chosen script and blocks until it is changed to a valid one.
+fn show_error_and_wait_for_fix(
+    tui: &Tui,
+    category: ErrorCategory,
+    err: &str,
+    from_watcher: &Receiver<WatcherToMainMessage>,
+    context: &mut Context,
+    runtime: &mut Koto,
+) -> Result<()> {
+    tui.clear()?;
+    tui.show_error(category, &context.chosen_script_path, err)?;
+    loop {
+        match from_watcher.recv() {
+            Ok(WatcherToMainMessage::NoticeWrite(path)) => {
+                tui.forget_description(Some(&path.to_string_lossy()));
+                // A fix attempt had been made, maybe in a script which is imported.
+                context.chosen_script_path =
+                    if let Some(script_path) = script_to_load_after_write(context, &path) {
+                        script_path
+                    } else {
+                        continue;
+                    };
+                context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
+                if compile_run_block_until_valid(tui, from_watcher, context, runtime).is_ok() {
+                    // Script is fixed.
+                    return Ok(());
                 }
+                // Didn't work out try one more time.
+            }
+            // A fix can't be noticed while the watcher is failing, so it is told here too.
+            Ok(WatcherToMainMessage::WatcherFailed(reason, persistent)) => {
+                log_watcher_failure(tui, &reason, persistent)?;
             }
+            _ => {}
         }
     }
 }
 
+/// Tells that the changes in "~/.mep" folder are not noticed while the watcher is failing.
+fn log_watcher_failure(tui: &Tui, reason: &str, persistent: bool) -> Result<()> {
+    tui.write_to_log_file(
+        LogEvent::Watcher,
+        None,
+        &format!("The watcher couldn't be set up: {}", reason),
+    );
+    if persistent {
+        tui.log(&format!(
+            "Changes in \"~/.mep\" folder are not reloaded, the watcher keeps failing: {}. Still retrying.",
+            reason
+        ))
+    } else {
+        tui.log(&format!(
+            "The watcher of \"~/.mep\" folder has failed, retrying: {}",
+            reason
+        ))
+    }
+}
+
+/// Brings the removed scripts folder back with the example scripts and lists its scripts again.
+///
+/// The chosen script is kept if it is one of the examples, the first one is chosen otherwise.
+/// Returns `false` without touching anything if the folder is given with "--scripts-dir".
+fn recreate_scripts_folder(context: &mut Context) -> Result<bool> {
+    // Someone else might have already brought it back, don't overwrite their scripts.
+    if !context.scripts_folder_path.exists() {
+        if !context.recreate_with_examples {
+            // A folder given with "--scripts-dir" is the user's, don't put examples in it.
+            return Ok(false);
+        }
+        populate_with_examples(&context.scripts_folder_path)?;
+    }
+    let mut new_available_scripts = recollect_available_scripts(context)?;
+    std::mem::swap(&mut context.available_scripts, &mut new_available_scripts);
+    if context.available_scripts.is_empty() {
+        return Err(anyhow!(
+            "There are no event processor scripts found in \"~/.mep\"."
+        ));
+    }
+
+    context.chosen_index_checked = context
+        .available_scripts
+        .iter()
+        .position(|script| *script == context.chosen_script_path)
+        .unwrap_or(0);
+    context.chosen_script_path = context.available_scripts[context.chosen_index_checked].clone();
+    context.chosen_script = fs::read_to_string(&context.chosen_script_path)?;
+    Ok(true)
+}
+
+/// Fills the scripts folder with the example scripts which ship with "mep", creating it if necessary.
+fn populate_with_examples(scripts_folder_path: &Path) -> Result<()> {
+    fs::create_dir_all(scripts_folder_path)?;
+    write_embedded_directory(&EXAMPLE_SCRIPTS, scripts_folder_path)
+}
+
+/// Writes the files of a directory built into the binary, keeping their paths relative to `to`.
+fn write_embedded_directory(directory: &Dir, to: &Path) -> Result<()> {
+    for file in directory.files() {
+        let path = to.join(file.path());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, file.contents())?;
+    }
+    for directory in directory.dirs() {
+        write_embedded_directory(directory, to)?;
+    }
+    Ok(())
+}
+
 // Borrowed from,
 // https://stackoverflow.com/questions/26958489/how-to-copy-a-folder-recursively-in-rust
 pub fn copy_directory_contents<U: AsRef<Path>, V: AsRef<Path>>(from: U, to: V) -> Result<()> {
@@ -790,7 +2865,6 @@ pub fn copy_directory_contents<U: AsRef<Path>, V: AsRef<Path>>(from: U, to: V) -
             fs::create_dir_all(&dest)?;
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(1000));
         for entry in fs::read_dir(working_path)? {
             let entry = entry?;
             let path = entry.path();
@@ -812,3 +2886,738 @@ pub fn copy_directory_contents<U: AsRef<Path>, V: AsRef<Path>>(from: U, to: V) -
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use extensions::ListenerCache;
+    use koto::runtime::ValueNumber;
+
+    /// A runtime running a script whose "midi.listen" keeps the last message as "midi.received".
+    fn runtime_keeping_received_message() -> Koto {
+        let mut runtime = Koto::default();
+        runtime.prelude().add_map("midi", koto_midi::make_module());
+        let chunk = match runtime
+            .compile("import midi\nmidi.listen = |message| midi.received = message\n")
+        {
+            Ok(chunk) => chunk,
+            Err(err) => panic!("the script doesn't compile: {}", err),
+        };
+        if let Err(err) = runtime.run_chunk(chunk) {
+            panic!("the script doesn't run: {}", err);
+        }
+        runtime
+    }
+
+    fn received_message(runtime: &mut Koto) -> Vec<i64> {
+        let midi_module = match runtime.prelude().data().get_with_string("midi") {
+            Some(Value::Map(midi_module)) => midi_module.clone(),
+            _ => panic!("\"midi\" is not a map"),
+        };
+        let received = midi_module.data().get_with_string("received").cloned();
+        match received {
+            Some(Value::List(message)) => message
+                .data()
+                .iter()
+                .map(|byte| match byte {
+                    Value::Number(ValueNumber::I64(byte)) => *byte,
+                    other => panic!("{} is not a byte", other),
+                })
+                .collect(),
+            _ => panic!("\"midi.listen\" didn't receive a message"),
+        }
+    }
+
+    #[test]
+    fn switching_from_a_valid_index_to_an_out_of_range_one_is_rejected() {
+        let script_count = 3;
+        assert_eq!(parse_choice("2", script_count), Ok(2));
+        // The last valid index is one less than the count, the count itself is out of range.
+        assert_eq!(
+            parse_choice("3", script_count),
+            Err(ChoiceError::OutOfRange("3".to_owned(), script_count))
+        );
+        assert_eq!(
+            parse_choice("30", script_count),
+            Err(ChoiceError::OutOfRange("30".to_owned(), script_count))
+        );
+    }
+
+    #[test]
+    fn an_out_of_range_choice_leaves_the_chosen_script_as_it_is() {
+        let scripts_folder_path =
+            std::env::temp_dir().join(format!("mep-choose-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scripts_folder_path);
+        assert!(populate_with_examples(&scripts_folder_path).is_ok());
+        let mut context = context_in(&scripts_folder_path);
+        let tui = Tui::new(true, true, Theme::plain());
+
+        match choose_script(&tui, &mut context, "1\n", &[]) {
+            Ok(chosen) => assert!(chosen),
+            Err(err) => panic!("a listed script isn't chosen: {}", err),
+        }
+        let chosen_script_path = context.available_scripts[1].clone();
+        assert_eq!(context.chosen_script_path, chosen_script_path);
+
+        let out_of_range = context.available_scripts.len().to_string();
+        match choose_script(&tui, &mut context, &out_of_range, &[]) {
+            Ok(chosen) => assert!(!chosen),
+            Err(err) => panic!("an out of range index isn't just ignored: {}", err),
+        }
+        assert_eq!(context.chosen_index_checked, 1);
+        assert_eq!(context.chosen_script_path, chosen_script_path);
+        let _ = fs::remove_dir_all(&scripts_folder_path);
+    }
+
+    #[test]
+    fn a_long_sysex_message_reaches_the_script_intact() {
+        let mut message = vec![0xF0_u8];
+        message.extend((0..498_u16).map(|index| u8::try_from(index % 128).unwrap_or(0)));
+        message.push(0xF7);
+        assert_eq!(message.len(), 500);
+
+        let mut runtime = runtime_keeping_received_message();
+        let forwarded = call_midi_listen_with(
+            0,
+            &message,
+            &mut runtime,
+            extensions::ALL_CHANNELS,
+            &ListenerCache::default(),
+        );
+        assert!(forwarded.is_ok());
+        assert_eq!(
+            received_message(&mut runtime),
+            message.iter().copied().map(i64::from).collect::<Vec<i64>>()
+        );
+    }
+
+    #[test]
+    fn copying_a_nested_folder_copies_every_file() {
+        let from = std::env::temp_dir().join(format!("mep-copy-from-{}", std::process::id()));
+        let to = std::env::temp_dir().join(format!("mep-copy-to-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&from);
+        let _ = fs::remove_dir_all(&to);
+        assert!(fs::create_dir_all(from.join("a").join("b")).is_ok());
+        assert!(fs::write(from.join("top.koto"), "top").is_ok());
+        assert!(fs::write(from.join("a").join("b").join("deep.koto"), "deep").is_ok());
+
+        let started_at = Instant::now();
+        assert!(copy_directory_contents(&from, &to).is_ok());
+        // Three folders used to take three seconds.
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            fs::read_to_string(to.join("top.koto")).ok(),
+            Some("top".to_owned())
+        );
+        assert_eq!(
+            fs::read_to_string(to.join("a").join("b").join("deep.koto")).ok(),
+            Some("deep".to_owned())
+        );
+
+        let _ = fs::remove_dir_all(&from);
+        let _ = fs::remove_dir_all(&to);
+    }
+
+    #[test]
+    fn three_writes_to_a_script_are_compiled_once() {
+        let (to_main, from_watcher) = unbounded();
+        let script = PathBuf::from("/scripts/a.koto");
+        for _ in 0..3 {
+            assert!(to_main
+                .send(WatcherToMainMessage::NoticeWrite(script.clone()))
+                .is_ok());
+        }
+        let first = match from_watcher.recv() {
+            Ok(first) => first,
+            Err(err) => panic!("nothing is received: {}", err),
+        };
+
+        let (message, next_message) = coalesce_writes(first, &from_watcher);
+        // Every message returned is acted on, so a single write means a single compile.
+        assert!(
+            matches!(message, WatcherToMainMessage::NoticeWrite(ref path) if *path == script),
+            "{:?}",
+            message
+        );
+        assert!(next_message.is_none(), "{:?}", next_message);
+        assert!(from_watcher.is_empty());
+    }
+
+    #[test]
+    fn a_write_to_another_script_is_not_coalesced() {
+        let (to_main, from_watcher) = unbounded();
+        let script = PathBuf::from("/scripts/a.koto");
+        let other_script = PathBuf::from("/scripts/b.koto");
+        assert!(to_main
+            .send(WatcherToMainMessage::NoticeWrite(script.clone()))
+            .is_ok());
+        assert!(to_main
+            .send(WatcherToMainMessage::NoticeWrite(other_script.clone()))
+            .is_ok());
+
+        let (message, next_message) = coalesce_writes(
+            WatcherToMainMessage::NoticeWrite(script.clone()),
+            &from_watcher,
+        );
+        assert!(matches!(message, WatcherToMainMessage::NoticeWrite(ref path) if *path == script));
+        assert!(
+            matches!(next_message, Some(WatcherToMainMessage::NoticeWrite(ref path)) if *path == other_script)
+        );
+    }
+
+    fn scripts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    #[test]
+    fn removing_a_script_before_the_chosen_one_moves_its_index() {
+        assert_eq!(
+            find_chosen_after_remove(&scripts(&["a", "b", "c"]), &scripts(&["b", "c"]), "c"),
+            ChosenAfterRemove::Unchanged(1)
+        );
+    }
+
+    #[test]
+    fn removing_the_chosen_script_falls_back_to_the_first_one() {
+        assert_eq!(
+            find_chosen_after_remove(&scripts(&["a", "b", "c"]), &scripts(&["a", "c"]), "b"),
+            ChosenAfterRemove::FellBack("a".to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn removing_a_script_after_the_chosen_one_keeps_its_index() {
+        assert_eq!(
+            find_chosen_after_remove(&scripts(&["a", "b", "c"]), &scripts(&["a", "b"]), "a"),
+            ChosenAfterRemove::Unchanged(0)
+        );
+    }
+
+    #[test]
+    fn renaming_the_chosen_script_follows_it() {
+        assert_eq!(
+            find_chosen_after_remove(&scripts(&["a", "b", "c"]), &scripts(&["a", "c", "d"]), "b"),
+            ChosenAfterRemove::Renamed("d".to_owned(), 2)
+        );
+    }
+
+    #[test]
+    fn removing_the_last_script_leaves_none() {
+        assert_eq!(
+            find_chosen_after_remove(&scripts(&["a"]), &[], "a"),
+            ChosenAfterRemove::NoScripts
+        );
+    }
+
+    #[test]
+    fn the_koto_extension_is_matched_in_any_case() {
+        for name in ["arp.koto", "arp.KOTO", "arp.Koto"] {
+            assert!(
+                has_koto_extension(Path::new(name)),
+                "{} is not a script",
+                name
+            );
+        }
+        for name in ["arp", "arp.txt", "arp.koto.bak", "koto", ".koto"] {
+            assert!(!has_koto_extension(Path::new(name)), "{} is a script", name);
+        }
+    }
+
+    #[test]
+    fn the_lister_and_the_watcher_agree_on_the_extension() {
+        let scripts_folder = Path::new("/home/user/.mep");
+        assert!(is_koto_script(
+            &scripts_folder.join("arp.KOTO"),
+            scripts_folder
+        ));
+        assert!(is_koto_script(
+            &scripts_folder.join("nested").join("arp.Koto"),
+            scripts_folder
+        ));
+        assert!(!is_koto_script(
+            &scripts_folder.join("README"),
+            scripts_folder
+        ));
+    }
+
+    #[test]
+    fn editor_temporary_files_are_not_scripts() {
+        let scripts_folder = Path::new("/home/user/.mep");
+        // Emacs lock and auto save files, backups, vim swap files and the file vim writes to test the folder.
+        for name in [".#x.koto", "#x.koto#", "x.koto~", ".x.koto.swp", "4913"] {
+            assert!(
+                !is_koto_script(&scripts_folder.join(name), scripts_folder),
+                "{} is a script",
+                name
+            );
+        }
+        assert!(is_hidden_or_temporary(".#x.koto"));
+        assert!(is_hidden_or_temporary("x.koto~"));
+        assert!(is_hidden_or_temporary(".x.koto.swp"));
+        assert!(!is_hidden_or_temporary("x.koto"));
+    }
+
+    #[test]
+    fn scripts_in_hidden_folders_are_skipped() {
+        let scripts_folder = Path::new("/home/user/.mep");
+        assert!(!is_koto_script(
+            &scripts_folder.join(".git").join("x.koto"),
+            scripts_folder
+        ));
+        // The scripts folder itself is hidden, only what is inside it counts.
+        assert!(is_koto_script(
+            &scripts_folder.join("x.koto"),
+            scripts_folder
+        ));
+    }
+
+    #[test]
+    fn a_choice_is_parsed_without_its_line_ending() {
+        assert_eq!(parse_choice("1\r\n", 3), Ok(1));
+        assert_eq!(parse_choice(" 0 \n", 3), Ok(0));
+    }
+
+    #[test]
+    fn an_empty_choice_is_not_a_number() {
+        assert_eq!(
+            parse_choice("", 3),
+            Err(ChoiceError::NotANumber(String::new()))
+        );
+        assert_eq!(
+            parse_choice("\r\n", 3),
+            Err(ChoiceError::NotANumber(String::new()))
+        );
+    }
+
+    #[test]
+    fn a_choice_with_other_characters_is_not_a_number() {
+        for choice in ["a", "1a", "1.5", "-", "+-1", "one"] {
+            assert_eq!(
+                parse_choice(choice, 3),
+                Err(ChoiceError::NotANumber(choice.to_owned())),
+                "{}",
+                choice
+            );
+        }
+    }
+
+    #[test]
+    fn negative_and_overflowing_choices_are_out_of_range() {
+        for choice in ["-1", "99999999999999999999999"] {
+            assert_eq!(
+                parse_choice(choice, 3),
+                Err(ChoiceError::OutOfRange(choice.to_owned(), 3)),
+                "{}",
+                choice
+            );
+        }
+        assert_eq!(
+            parse_choice("0", 0),
+            Err(ChoiceError::OutOfRange("0".to_owned(), 0))
+        );
+    }
+
+    #[test]
+    fn a_reloaded_listener_is_called_once_the_cache_is_cleared() {
+        let mut runtime = runtime_keeping_received_message();
+        let listener_cache = ListenerCache::default();
+        let call = |runtime: &mut Koto| {
+            call_midi_listen_with(
+                0,
+                &[0x90, 60, 100],
+                runtime,
+                extensions::ALL_CHANNELS,
+                &listener_cache,
+            )
+        };
+        assert!(call(&mut runtime).is_ok());
+        assert_eq!(received_message(&mut runtime), vec![0x90, 60, 100]);
+
+        // Like a script which is reloaded with a new "midi.listen".
+        let chunk =
+            match runtime.compile("import midi\nmidi.listen = |message| midi.received = [1]\n") {
+                Ok(chunk) => chunk,
+                Err(err) => panic!("the script doesn't compile: {}", err),
+            };
+        if let Err(err) = runtime.run_chunk(chunk) {
+            panic!("the script doesn't run: {}", err);
+        }
+        listener_cache.replace(None);
+        assert!(call(&mut runtime).is_ok());
+        assert_eq!(received_message(&mut runtime), vec![1]);
+    }
+
+    #[test]
+    fn a_message_received_by_the_backend_is_listened_to_and_sent_back() {
+        let mock = backend::Mock::default();
+        // Like the callback which main makes with `make_on_midi_in`.
+        let (midi_in_to_main, from_midi_in) = unbounded();
+        let on_receive: OnReceive = Box::new(move |stamp, message| {
+            let _ = midi_in_to_main.send((stamp, message.to_vec()));
+        });
+        let _input = match mock.open_input("mep_in", None, on_receive) {
+            Ok((input, _)) => input,
+            Err(err) => panic!("the mock input isn't opened: {}", err),
+        };
+        let outputs = match open_output_ports(&mock, "mep_out", 1, None) {
+            Ok((outputs, _)) => outputs,
+            Err(err) => panic!("the mock output isn't opened: {}", err),
+        };
+        let (error_to_main, _) = unbounded();
+        let ports: Vec<OutputPort> = outputs
+            .into_iter()
+            .map(|output| {
+                OutputPort::spawn(
+                    output,
+                    error_to_main.clone(),
+                    false,
+                    ActiveNotes::default(),
+                    None,
+                )
+            })
+            .collect();
+        let mut midi_module = koto_midi::make_module();
+        extensions::add_send(&mut midi_module, &ports, error_to_main);
+        let mut runtime = Koto::default();
+        runtime.prelude().add_map("midi", midi_module);
+        let chunk =
+            match runtime.compile("import midi\nmidi.listen = |message| midi.send message\n") {
+                Ok(chunk) => chunk,
+                Err(err) => panic!("the script doesn't compile: {}", err),
+            };
+        if let Err(err) = runtime.run_chunk(chunk) {
+            panic!("the script doesn't run: {}", err);
+        }
+
+        mock.receive(0, &[0x90, 60, 100]);
+        mock.receive(1, &[0x80, 60, 0]);
+        let listener_cache = ListenerCache::default();
+        for (stamp, message) in from_midi_in.try_iter() {
+            let forwarded = call_midi_listen_with(
+                stamp,
+                &message,
+                &mut runtime,
+                extensions::ALL_CHANNELS,
+                &listener_cache,
+            );
+            assert!(forwarded.is_ok());
+        }
+        ports[0].flush();
+        assert_eq!(
+            mock.sent("mep_out"),
+            vec![vec![0x90, 60, 100], vec![0x80, 60, 0]]
+        );
+    }
+
+    #[test]
+    fn errors_which_are_not_strings_are_shown_too() {
+        // Calling something which isn't a function and throwing a map.
+        for script in [
+            "import midi\nmidi.listen = |message|\n  x = 42\n  x message\n",
+            "import midi\nmidi.listen = |message|\n  throw {message: \"oops\", @display: |self| self.message}\n",
+        ] {
+            let mut runtime = Koto::default();
+            runtime.prelude().add_map("midi", koto_midi::make_module());
+            let chunk = match runtime.compile(script) {
+                Ok(chunk) => chunk,
+                Err(err) => panic!("the script doesn't compile: {}", err),
+            };
+            if let Err(err) = runtime.run_chunk(chunk) {
+                panic!("the script doesn't run: {}", err);
+            }
+            match call_midi_listen_with(
+                0,
+                &[0x90, 60, 100],
+                &mut runtime,
+                extensions::ALL_CHANNELS,
+                &ListenerCache::default(),
+            ) {
+                Ok(_) => panic!("\"midi.listen\" doesn't fail in:\n{}", script),
+                Err(err) => assert!(
+                    !runtime_error_message(&err).trim().is_empty(),
+                    "the error of\n{}\nhas no message",
+                    script
+                ),
+            }
+        }
+    }
+
+    /// A context listing the scripts in `scripts_folder_path`, with the first one chosen.
+    fn context_in(scripts_folder_path: &Path) -> Context {
+        let mut available_scripts = Vec::new();
+        if let Err(err) = collect_available_scripts_to(&mut available_scripts, scripts_folder_path)
+        {
+            panic!("the scripts aren't listed: {}", err);
+        }
+        let chosen_script_path = available_scripts.first().cloned().unwrap_or_default();
+        Context {
+            scripts_folder_path: scripts_folder_path.to_path_buf(),
+            available_scripts,
+            chosen_index_checked: 0,
+            chosen_script: fs::read_to_string(&chosen_script_path).unwrap_or_default(),
+            script_state: ScriptState::new(false, true, &chosen_script_path, None),
+            chosen_script_path,
+            follow_new: false,
+            recreate_with_examples: true,
+            single_script: None,
+            dependencies: Vec::new(),
+            shared_modules: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_removed_scripts_folder_is_recreated_with_the_examples() {
+        let scripts_folder_path =
+            std::env::temp_dir().join(format!("mep-recreate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scripts_folder_path);
+        assert!(populate_with_examples(&scripts_folder_path).is_ok());
+        let mut context = context_in(&scripts_folder_path);
+        let examples = context.available_scripts.clone();
+        assert!(!examples.is_empty());
+        let chosen_script_path = context.chosen_script_path.clone();
+
+        assert!(fs::remove_dir_all(&scripts_folder_path).is_ok());
+        let recreated = recreate_scripts_folder(&mut context);
+        let listed_again = context_in(&scripts_folder_path).available_scripts;
+        let _ = fs::remove_dir_all(&scripts_folder_path);
+
+        assert!(matches!(recreated, Ok(true)), "{:?}", recreated.err());
+        assert_eq!(context.available_scripts, examples);
+        assert_eq!(listed_again, examples);
+        // The chosen script is one of the examples, so it stays chosen.
+        assert_eq!(context.chosen_script_path, chosen_script_path);
+        assert!(!context.chosen_script.is_empty());
+    }
+
+    #[test]
+    fn a_removed_scripts_dir_is_left_to_the_user() {
+        let scripts_folder_path =
+            std::env::temp_dir().join(format!("mep-scripts-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scripts_folder_path);
+        assert!(fs::create_dir_all(&scripts_folder_path).is_ok());
+        let mut context = context_in(&scripts_folder_path);
+        context.recreate_with_examples = false;
+        assert!(fs::remove_dir_all(&scripts_folder_path).is_ok());
+        assert!(matches!(recreate_scripts_folder(&mut context), Ok(false)));
+        assert!(!scripts_folder_path.exists());
+    }
+
+    /// A note on, its note off as a note on with zero velocity and a control change.
+    ///
+    /// Note 61 is a C#, which "channel_spread" routes to the second channel.
+    const RECORDED_INPUT: &[&[u8]] = &[&[144, 61, 100], &[144, 61, 0], &[176, 1, 64]];
+
+    /// Runs `script` in a runtime made like main's and feeds `input` to it through a mock backend.
+    ///
+    /// Returns what the script sends, or the first error it runs into.
+    fn run_against_recorded_input(script: &str, input: &[&[u8]]) -> Result<Vec<Vec<u8>>, String> {
+        let mock = backend::Mock::default();
+        let (midi_in_to_main, from_midi_in) = unbounded();
+        let on_receive: OnReceive = Box::new(move |stamp, message| {
+            let _ = midi_in_to_main.send((stamp, message.to_vec()));
+        });
+        let (_input, _) = mock
+            .open_input("mep_in", None, on_receive)
+            .map_err(|err| err.to_string())?;
+        let (outputs, _) =
+            open_output_ports(&mock, "mep_out", 1, None).map_err(|err| err.to_string())?;
+        let (error_to_main, send_errors) = unbounded();
+        let ports: Vec<OutputPort> = outputs
+            .into_iter()
+            .map(|output| {
+                OutputPort::spawn(
+                    output,
+                    error_to_main.clone(),
+                    false,
+                    ActiveNotes::default(),
+                    None,
+                )
+            })
+            .collect();
+        let mut midi_module = koto_midi::make_module();
+        extensions::add_send(&mut midi_module, &ports, error_to_main);
+        let mut runtime = make_runtime(&midi_module, &ValueMap::default(), Some(1));
+        let chunk = runtime.compile(script).map_err(|err| err.to_string())?;
+        runtime
+            .run_chunk(chunk)
+            .map_err(|err| runtime_error_message(&err))?;
+
+        for (stamp, message) in (0..).zip(input) {
+            mock.receive(stamp, message);
+        }
+        let listener_cache = ListenerCache::default();
+        for (stamp, message) in from_midi_in.try_iter() {
+            call_midi_listen_with(
+                stamp,
+                &message,
+                &mut runtime,
+                extensions::ALL_CHANNELS,
+                &listener_cache,
+            )
+            .map_err(|err| runtime_error_message(&err))?;
+        }
+        ports[0].flush();
+        if let Some(err) = send_errors.try_iter().next() {
+            return Err(err);
+        }
+        Ok(mock.sent("mep_out"))
+    }
+
+    fn example_script(name: &str) -> &'static str {
+        match EXAMPLE_SCRIPTS
+            .get_file(name)
+            .and_then(|script| script.contents_utf8())
+        {
+            Some(script) => script,
+            None => panic!("there is no example script named {}", name),
+        }
+    }
+
+    #[test]
+    fn every_example_script_runs_against_recorded_input() {
+        let scripts = EXAMPLE_SCRIPTS
+            .files()
+            .iter()
+            .filter(|script| has_koto_extension(script.path()))
+            .collect::<Vec<_>>();
+        assert!(!scripts.is_empty(), "there are example scripts to run");
+        for script in scripts {
+            let source = match script.contents_utf8() {
+                Some(source) => source,
+                None => panic!("{} is not text", script.path().display()),
+            };
+            if let Err(err) = run_against_recorded_input(source, RECORDED_INPUT) {
+                panic!("{} fails: {}", script.path().display(), err);
+            }
+        }
+    }
+
+    #[test]
+    fn echo_messages_sends_back_what_it_receives() {
+        assert_eq!(
+            run_against_recorded_input(example_script("echo_messages.koto"), RECORDED_INPUT),
+            Ok(vec![
+                vec![0x90, 61, 100],
+                vec![0x90, 61, 0],
+                vec![0xB0, 1, 64]
+            ])
+        );
+    }
+
+    #[test]
+    fn channel_spread_routes_notes_to_their_channels() {
+        assert_eq!(
+            run_against_recorded_input(example_script("channel_spread.koto"), RECORDED_INPUT),
+            Ok(vec![vec![0x91, 61, 100], vec![0x91, 61, 0]])
+        );
+    }
+
+    #[test]
+    fn boilerplate_sends_nothing() {
+        assert_eq!(
+            run_against_recorded_input(example_script("boilerplate.koto"), RECORDED_INPUT),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn random_velocity_is_the_same_with_the_same_seed() {
+        let input: &[&[u8]] = &[&[144, 60, 100], &[144, 62, 100], &[144, 64, 100]];
+        let script = example_script("random_velocity.koto");
+        let first = run_against_recorded_input(script, input);
+        assert!(matches!(&first, Ok(sent) if sent.len() == 3), "{:?}", first);
+        assert_eq!(first, run_against_recorded_input(script, input));
+    }
+
+    #[test]
+    fn imports_in_comments_are_skipped() {
+        let script = "import midi, helpers\n# import commented\nfrom lib.scales import minor # import trailing\n#-\nimport in_block\n-#\n  import indented\n";
+        assert_eq!(
+            imported_modules(script),
+            vec!["midi", "helpers", "lib.scales", "indented"]
+        );
+    }
+
+    #[test]
+    fn imports_are_looked_up_next_to_the_script_and_then_in_the_scripts_folder() {
+        let scripts_folder =
+            std::env::temp_dir().join(format!("mep-imports-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scripts_folder);
+        let sub_folder = scripts_folder.join("arps");
+        assert!(fs::create_dir_all(scripts_folder.join("lib")).is_ok());
+        assert!(fs::create_dir_all(&sub_folder).is_ok());
+        assert!(fs::write(
+            scripts_folder.join("lib").join("main.koto"),
+            "import scales\n"
+        )
+        .is_ok());
+        assert!(fs::write(scripts_folder.join("scales.koto"), "").is_ok());
+        assert!(fs::write(scripts_folder.join("helpers.koto"), "").is_ok());
+        assert!(fs::write(sub_folder.join("helpers.koto"), "").is_ok());
+        let script_path = sub_folder.join("arp.koto");
+        let script = "import midi, helpers, lib\n";
+        assert!(fs::write(&script_path, script).is_ok());
+
+        let dependencies = script_dependencies(&script_path, script, Some(&scripts_folder))
+            .into_iter()
+            .map(|dependency| {
+                (
+                    dependency.module,
+                    dependency.path,
+                    dependency.from_scripts_folder,
+                )
+            })
+            .collect::<Vec<(String, PathBuf, bool)>>();
+        assert_eq!(
+            dependencies,
+            vec![
+                // Next to the script, it takes precedence over the one in the scripts folder.
+                ("helpers".to_owned(), sub_folder.join("helpers.koto"), false),
+                (
+                    "lib".to_owned(),
+                    scripts_folder.join("lib").join("main.koto"),
+                    true
+                ),
+                // Imported by "lib", which is in a sub folder of its own.
+                (
+                    "scales".to_owned(),
+                    scripts_folder.join("scales.koto"),
+                    true
+                ),
+            ]
+        );
+        // A single script has no scripts folder to fall back to.
+        assert_eq!(script_dependencies(&script_path, script, None).len(), 1);
+
+        let _ = fs::remove_dir_all(&scripts_folder);
+    }
+
+    #[test]
+    fn a_dotted_import_is_a_script_in_a_sub_folder() {
+        let scripts_folder =
+            std::env::temp_dir().join(format!("mep-dotted-imports-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scripts_folder);
+        let lib = scripts_folder.join("lib");
+        assert!(fs::create_dir_all(&lib).is_ok());
+        assert!(fs::write(lib.join("helpers.koto"), "import scales\n").is_ok());
+        assert!(fs::write(lib.join("scales.koto"), "").is_ok());
+        let script_path = scripts_folder.join("arp.koto");
+        let script = "import lib.helpers\n";
+        assert!(fs::write(&script_path, script).is_ok());
+
+        let dependencies = script_dependencies(&script_path, script, Some(&scripts_folder))
+            .into_iter()
+            .map(|dependency| (dependency.module, dependency.path))
+            .collect::<Vec<(String, PathBuf)>>();
+        assert_eq!(
+            dependencies,
+            vec![
+                ("lib.helpers".to_owned(), lib.join("helpers.koto")),
+                // Imported by "lib/helpers.koto", so it is looked up next to it.
+                ("scales".to_owned(), lib.join("scales.koto")),
+            ]
+        );
+
+        let _ = fs::remove_dir_all(&scripts_folder);
+    }
+}
@@ -0,0 +1,76 @@
+//! Watching the existing ports which "mep" is connected to with "--connect-in" and "--connect-out",
+//! so they are reconnected when they come back after being unplugged.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use crossbeam_channel::Sender;
+
+use crate::backend::MidiBackend;
+
+/// How often the ports are listed to find out if a connected one is gone or back.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connected port which has disappeared or appeared again, with its name.
+pub enum PortChange {
+    InputLost(String),
+    InputBack(String),
+    OutputLost(String),
+    OutputBack(String),
+}
+
+/// Lists the ports periodically in its own thread and notifies main when a connected one changes.
+///
+/// Nothing is watched if neither of the ports is connected to an existing one.
+pub fn spawn_port_monitor(
+    backend: Arc<dyn MidiBackend>,
+    input_port_name: Option<String>,
+    output_port_name: Option<String>,
+    to_main: Sender<PortChange>,
+) {
+    if input_port_name.is_none() && output_port_name.is_none() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut input_present = true;
+        let mut output_present = true;
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let (input_port_names, output_port_names) = match backend.port_names() {
+                Ok(port_names) => port_names,
+                // Try again later, listing might fail while a device is being enumerated.
+                Err(_) => continue,
+            };
+
+            let mut changes = Vec::new();
+            if let Some(name) = &input_port_name {
+                let present = input_port_names.contains(name);
+                if present != input_present {
+                    input_present = present;
+                    changes.push(if present {
+                        PortChange::InputBack(name.clone())
+                    } else {
+                        PortChange::InputLost(name.clone())
+                    });
+                }
+            }
+            if let Some(name) = &output_port_name {
+                let present = output_port_names.contains(name);
+                if present != output_present {
+                    output_present = present;
+                    changes.push(if present {
+                        PortChange::OutputBack(name.clone())
+                    } else {
+                        PortChange::OutputLost(name.clone())
+                    });
+                }
+            }
+
+            for change in changes {
+                if to_main.send(change).is_err() {
+                    // Main is gone.
+                    return;
+                }
+            }
+        }
+    });
+}
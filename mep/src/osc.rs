@@ -0,0 +1,197 @@
+//! Receiving and sending OSC messages next to midi, with "--osc-in" and "--osc-out".
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+};
+
+use crossbeam_channel::Sender;
+use koto::{
+    runtime::{runtime_error, RuntimeError, Value, ValueList, ValueMap, ValueNumber},
+    Koto,
+};
+use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+
+/// Large enough for any OSC packet which fits in a single UDP datagram.
+const RECEIVE_BUFFER_SIZE: usize = 65_536;
+
+/// Resolves the address to send OSC messages to and binds a socket to send them from.
+pub fn open_osc_out(address: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let destination = address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the address couldn't be resolved",
+        )
+    })?;
+    let local_address = if destination.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    Ok((UdpSocket::bind(local_address)?, destination))
+}
+
+/// Receives OSC packets in its own thread and sends the messages in them to main.
+///
+/// Messages in bundles are sent one by one, packets which can't be decoded are skipped.
+pub fn spawn_osc_receiver(socket: UdpSocket, to_main: Sender<OscMessage>) {
+    thread::spawn(move || {
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            let size = match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => size,
+                // Try again, e.g. an ICMP error from an earlier send might be reported here on some platforms.
+                Err(_) => continue,
+            };
+            if let Ok(packet) = decoder::decode(&buffer[..size]) {
+                let mut messages = Vec::new();
+                flatten_packet(packet, &mut messages);
+                for message in messages {
+                    if to_main.send(message).is_err() {
+                        // Main is gone.
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn flatten_packet(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => messages.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                flatten_packet(packet, messages);
+            }
+        }
+    }
+}
+
+/// Converts an OSC argument to a koto value.
+///
+/// Types without a koto counterpart, e.g. time tags, become empty values.
+fn osc_to_value(arg: OscType) -> Value {
+    match arg {
+        OscType::Int(number) => Value::Number(i64::from(number).into()),
+        OscType::Long(number) => Value::Number(number.into()),
+        OscType::Float(number) => Value::Number(f64::from(number).into()),
+        OscType::Double(number) => Value::Number(number.into()),
+        OscType::Inf => Value::Number(f64::INFINITY.into()),
+        OscType::String(text) => Value::Str(text.into()),
+        OscType::Char(character) => Value::Str(character.to_string().into()),
+        OscType::Bool(boolean) => Value::Bool(boolean),
+        OscType::Blob(bytes) => Value::List(ValueList::from_slice(
+            &bytes
+                .iter()
+                .map(|byte| Value::Number(i64::from(*byte).into()))
+                .collect::<Vec<Value>>(),
+        )),
+        _ => Value::Empty,
+    }
+}
+
+/// Converts a koto value to an OSC argument, `None` if it can't be sent.
+fn value_to_osc(value: &Value) -> Option<OscType> {
+    match value {
+        Value::Number(ValueNumber::I64(number)) => {
+            Some(i32::try_from(*number).map_or(OscType::Long(*number), OscType::Int))
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::as_conversions)]
+        // Most OSC receivers only understand 32 bit floats.
+        Value::Number(ValueNumber::F64(number)) => Some(OscType::Float(*number as f32)),
+        Value::Str(text) => Some(OscType::String(text.as_str().to_owned())),
+        Value::Bool(boolean) => Some(OscType::Bool(*boolean)),
+        Value::Empty => Some(OscType::Nil),
+        _ => None,
+    }
+}
+
+/// Adds "midi.osc_send" which sends an OSC message to the address given with "--osc-out".
+///
+/// Without "--osc-out" it throws an error, so the script finds out why nothing is sent.
+pub fn add_osc_send(midi_module: &mut ValueMap, destination: Option<(UdpSocket, SocketAddr)>) {
+    midi_module.add_fn("osc_send", move |vm, args| {
+        let (address, args) = match vm.get_args(args) {
+            [Value::Str(address)] => (address.as_str().to_owned(), Vec::new()),
+            [Value::Str(address), Value::List(args)] => {
+                let args = args
+                    .data()
+                    .iter()
+                    .map(value_to_osc)
+                    .collect::<Option<Vec<OscType>>>();
+                if let Some(args) = args {
+                    (address.as_str().to_owned(), args)
+                } else {
+                    return runtime_error!(
+                        "midi.osc_send - arguments should be numbers, strings, booleans or ()"
+                    );
+                }
+            }
+            _ => {
+                return runtime_error!(
+                    "midi.osc_send - expected an address and a list of arguments. Ex. midi.osc_send \"/filter/cutoff\", [0.5]"
+                )
+            }
+        };
+        let (socket, destination) = if let Some(destination) = &destination {
+            destination
+        } else {
+            return runtime_error!(
+                "midi.osc_send - run \"mep\" with \"--osc-out <address:port>\" to send OSC messages"
+            );
+        };
+        let packet = OscPacket::Message(OscMessage {
+            addr: address,
+            args,
+        });
+        match encoder::encode(&packet) {
+            Ok(bytes) => match socket.send_to(&bytes, destination) {
+                Ok(_) => Ok(Value::Empty),
+                Err(err) => runtime_error!("midi.osc_send - couldn't send the message: {}", err),
+            },
+            Err(err) => runtime_error!("midi.osc_send - couldn't encode the message: {:?}", err),
+        }
+    });
+}
+
+/// Calls "midi.osc_listen" of the script with the address and the arguments of the message.
+///
+/// Messages are ignored if the script doesn't define it.
+pub fn call_midi_osc_listen(runtime: &mut Koto, message: OscMessage) -> Result<(), RuntimeError> {
+    let listener = match runtime.prelude().data().get_with_string("midi") {
+        Some(Value::Map(midi_module_map)) => midi_module_map
+            .data()
+            .get_with_string("osc_listen")
+            .cloned(),
+        _ => None,
+    };
+    match listener {
+        Some(listener @ Value::Function(_)) => {
+            let args = message
+                .args
+                .into_iter()
+                .map(osc_to_value)
+                .collect::<Vec<Value>>();
+            runtime
+                .call_function(
+                    listener,
+                    &[
+                        Value::Str(message.addr.into()),
+                        Value::List(ValueList::from_slice(&args)),
+                    ],
+                )
+                .map(|_| ())
+                .map_err(|err| {
+                    RuntimeError::from(format!(
+                        "Calling \"midi.osc_listen\" is failed, {}",
+                        err.to_string()
+                    ))
+                })
+        }
+        Some(Value::Empty) | None => Ok(()),
+        Some(_) => runtime_error!("\"midi.osc_listen\" is defined but it is not a function"),
+    }
+}
@@ -0,0 +1,96 @@
+//! Feeding the messages in a file to the running script as if they were received, with "--input-file".
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Sender;
+
+/// A message to replay and how long to wait before it.
+pub type ReplayedMessage = (Duration, Vec<u8>);
+
+/// Parses the messages in an input file.
+///
+/// Every line is either a list of bytes separated by commas, optionally preceded by a delay in milliseconds
+/// e.g. "250: 144, 60, 100", or a line written by "midi.dump" e.g. "1634567 90 3C 7F" which is replayed
+/// with the delays between the recorded timestamps. Empty lines and lines starting with "#" are skipped.
+pub fn parse_input_file(text: &str) -> Result<Vec<ReplayedMessage>, String> {
+    let mut messages = Vec::new();
+    // Timestamp of the previous line in the format of "midi.dump", in microseconds.
+    let mut previous_stamp: Option<u64> = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line_number = index + 1;
+        let message = if line.contains(',') {
+            parse_listed_message(line)
+        } else {
+            parse_dumped_message(line).map(|(stamp, message)| {
+                let delay = previous_stamp.map_or(Duration::ZERO, |previous_stamp| {
+                    Duration::from_micros(stamp.saturating_sub(previous_stamp))
+                });
+                previous_stamp = Some(stamp);
+                (delay, message)
+            })
+        };
+        messages.push(message.ok_or_else(|| {
+            format!(
+                "Line {} is not a message, expected e.g. \"250: 144, 60, 100\" or \"1634567 90 3C 7F\": {}",
+                line_number, line
+            )
+        })?);
+    }
+    Ok(messages)
+}
+
+/// Parses e.g. "250: 144, 60, 100" or "144, 60, 100".
+fn parse_listed_message(line: &str) -> Option<ReplayedMessage> {
+    let (delay_ms, bytes) = match line.split_once(':') {
+        Some((delay_ms, bytes)) => (delay_ms.trim().parse::<u64>().ok()?, bytes),
+        None => (0, line),
+    };
+    let message = bytes
+        .split(',')
+        .map(|byte| byte.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((Duration::from_millis(delay_ms), message))
+}
+
+/// Parses a line written by "midi.dump" e.g. "1634567 90 3C 7F" to its timestamp and message.
+fn parse_dumped_message(line: &str) -> Option<(u64, Vec<u8>)> {
+    let mut parts = line.split_whitespace();
+    let stamp = parts.next()?.parse::<u64>().ok()?;
+    let message = parts
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if message.is_empty() {
+        return None;
+    }
+    Some((stamp, message))
+}
+
+/// Sends the messages to main in their own thread, waiting the delay before each.
+///
+/// Messages are stamped with the microseconds elapsed since `started_at` and marked to be left out of thru.
+/// `finished` is notified once every message is sent.
+pub fn spawn_replay(
+    messages: Vec<ReplayedMessage>,
+    started_at: Instant,
+    to_main: Sender<(u64, Vec<u8>, bool)>,
+    finished: Sender<()>,
+) {
+    thread::spawn(move || {
+        for (delay, message) in messages {
+            thread::sleep(delay);
+            let stamp = u64::try_from(started_at.elapsed().as_micros()).unwrap_or(u64::MAX);
+            if to_main.send((stamp, message, false)).is_err() {
+                // Main is gone.
+                return;
+            }
+        }
+        let _ = finished.send(());
+    });
+}
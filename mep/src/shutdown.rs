@@ -0,0 +1,75 @@
+//! Tears down what "mep" has started in order when it exits, also after an error or an interrupt.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use crate::backend::InputConnection;
+use crate::extensions::{send_panic, stop_clock, Clock, Output, OutputPort};
+
+/// The connection of the input port, `None` while it is unplugged.
+///
+/// It is shared, so the interrupt handler can close it while main reconnects it.
+pub type InputPort = Arc<Mutex<Option<Box<dyn InputConnection>>>>;
+
+/// Everything which needs to be stopped or closed before exiting, shared by main and the interrupt handler.
+///
+/// The watcher, stdin and port monitor threads only send to main, they stop by themselves once it is gone.
+#[derive(Clone)]
+pub struct Shutdown {
+    clock: Arc<Mutex<Option<Clock>>>,
+    input_port: InputPort,
+    output_ports: Vec<OutputPort>,
+    done: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new(
+        clock: &Arc<Mutex<Option<Clock>>>,
+        input_port: &InputPort,
+        output_ports: &[OutputPort],
+    ) -> Self {
+        Self {
+            clock: Arc::clone(clock),
+            input_port: Arc::clone(input_port),
+            output_ports: output_ports.to_vec(),
+            done: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stops the clock, closes the input port and then the output ports, only the first call does anything.
+    ///
+    /// With `panic` every output is silenced before it is closed.
+    pub fn run(&self, panic: bool) {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Nothing is sent from other threads from now on.
+        stop_clock(&self.clock);
+        // `lock.unwrap()` will always succeed because no one panics while holding it.
+        #[allow(clippy::unwrap_used)]
+        drop(self.input_port.lock().unwrap().take());
+        if panic {
+            send_panic(&self.output_ports);
+        }
+        // The ports are closed last, after what is queued is sent.
+        for port in &self.output_ports {
+            port.replace(Output::Disconnected);
+            port.flush();
+        }
+    }
+
+    /// Runs the teardown when the returned guard is dropped, wherever main returns from.
+    pub fn guard(&self) -> ShutdownGuard {
+        ShutdownGuard(self.clone())
+    }
+}
+
+pub struct ShutdownGuard(Shutdown);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.0.run(false);
+    }
+}
@@ -1,42 +1,476 @@
-use std::path::PathBuf;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    panic,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use console::Term;
-use crossterm::style::{Attribute, StyledContent, Stylize};
+use crossterm::{
+    cursor, execute,
+    style::{Attribute, Color, ResetColor, SetAttribute, StyledContent, Stylize},
+    terminal,
+};
 
 use anyhow::Result;
 
+/// A script describes itself with a comment like "# @desc Simple arpeggiator" in its first few lines.
+const DESCRIPTION_PREFIX: &str = "# @desc";
+const DESCRIPTION_LINES: usize = 5;
+
 const VALUE_ENTRY_LINE: &str = "Please choose a script to run and start watching for changes.\nType a digit from the list and then press \"enter\":";
-const VALUE_ENTRY_LINE_LENGTH: usize =
-    "\nType a digit from the list and then press \"enter\":".len();
+const VALUE_ENTRY_PROMPT: &str = "Type a digit from the list and then press \"enter\":";
+/// Replaces both of the value entry lines with "--quiet".
+const QUIET_VALUE_ENTRY_PROMPT: &str = ">";
+/// Shown below the list when keys are read one by one, "--quiet" leaves it out.
+const COMMANDS_LINE: &str = "\u{2191}/\u{2193} move  / search  r reload  q quit";
+const OUTPUTS_OK: &str = "\u{25cf} out ok";
+const OUTPUTS_FAILING: &str = "\u{25cf} out failing";
+const INTRO_LINE: &str = "Here are your event processor scripts,";
 pub const BULB: &str = "\u{1f4a1}";
+const LOG_LINES_CAP: usize = 5;
+const ACTIVITY_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Kind of an error shown by `Tui::show_error`, each is labeled and colored differently.
+#[derive(Clone, Copy)]
+pub enum ErrorCategory {
+    /// The script couldn't be parsed or compiled.
+    Compile,
+    /// The script threw while running, e.g. in "midi.listen".
+    Runtime,
+    /// A message which the script sent couldn't be sent.
+    Send,
+}
+
+impl ErrorCategory {
+    const fn label(self) -> &'static str {
+        match self {
+            Self::Compile => "compile error",
+            Self::Runtime => "runtime error",
+            Self::Send => "send error",
+        }
+    }
+}
+
+/// How the entries of the log file are written.
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// The time followed by the message, e.g. "1634567890.123 Reloading /home/me/.mep/arp.koto".
+    Text,
+    /// One JSON object per line, for log aggregators and other tools.
+    Json,
+}
+
+/// What an entry in the log file is about.
+#[derive(Clone, Copy)]
+pub enum LogEvent {
+    Reload,
+    Create,
+    Remove,
+    FolderRemoved,
+    Error,
+    SendError,
+    Warning,
+    Port,
+    Watcher,
+}
+
+impl LogEvent {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Reload => "reload",
+            Self::Create => "create",
+            Self::Remove => "remove",
+            Self::FolderRemoved => "folder_removed",
+            Self::Error => "error",
+            Self::SendError => "send_error",
+            Self::Warning => "warning",
+            Self::Port => "port",
+            Self::Watcher => "watcher",
+        }
+    }
+
+    const fn level(self) -> &'static str {
+        match self {
+            Self::Error | Self::SendError => "error",
+            Self::FolderRemoved | Self::Warning | Self::Watcher => "warn",
+            Self::Reload | Self::Create | Self::Remove | Self::Port => "info",
+        }
+    }
+}
+
+/// Colors of the terminal interface by what they are used for.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    /// Headings, e.g. "Here are your event processor scripts,".
+    pub intro: Color,
+    /// Indexes of the scripts which are not highlighted.
+    pub index: Color,
+    /// Index of the highlighted script.
+    pub highlight: Color,
+    /// Names of the scripts.
+    pub name: Color,
+    /// The line which asks for a choice.
+    pub prompt: Color,
+    /// Send errors and notices about removing scripts.
+    pub error: Color,
+    /// The status of the output ports while they send without errors.
+    pub ok: Color,
+    pub compile_error: Color,
+    pub runtime_error: Color,
+    /// Notices which don't need an action, e.g. that a backup is made.
+    pub notice: Color,
+    /// Port names, logs and the details of errors.
+    pub text: Color,
+    /// Without styling nothing but the text is written, e.g. with "--no-color".
+    pub styled: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            intro: Color::Blue,
+            index: Color::Yellow,
+            highlight: Color::Green,
+            name: Color::Red,
+            prompt: Color::Green,
+            error: Color::Red,
+            ok: Color::Green,
+            compile_error: Color::Yellow,
+            runtime_error: Color::Magenta,
+            notice: Color::Yellow,
+            text: Color::White,
+            styled: true,
+        }
+    }
+}
+
+impl Theme {
+    /// A theme which writes no escape codes at all.
+    pub fn plain() -> Self {
+        Self {
+            styled: false,
+            ..Self::default()
+        }
+    }
+}
+
+/// Where the list is on the screen after it is rendered, so a change of the highlight repaints only two rows.
+struct RenderedList {
+    scripts: Vec<String>,
+    filter: Option<String>,
+    terminal_size: (u16, u16),
+    /// Indexes of the scripts on the rendered page with the rows they are on and their numbers in the list.
+    rows: Vec<(usize, usize, String)>,
+    highlighted: Option<usize>,
+    prompt_row: usize,
+}
 
 pub struct Tui {
     stdout: Term,
+    stderr: Term,
+    /// In headless mode nothing is drawn, notices and errors are written to stderr as plain lines.
+    headless: bool,
+    /// Leaves out the intro, the instructions and the notices about the scripts folder, errors are still shown.
+    quiet: bool,
+    log_lines: RefCell<VecDeque<String>>,
+    /// Names of the input and output ports as the other apps see them, known after the ports are created.
+    port_names: RefCell<Option<(String, Vec<String>)>>,
+    /// Count of the received messages, shown in the line below the value entry line.
+    activity: Cell<usize>,
+    shown_activity: Cell<usize>,
+    /// Whether every output port sends without errors, `None` until it is known.
+    outputs_ok: Cell<Option<bool>>,
+    shown_outputs_ok: Cell<Option<bool>>,
+    activity_shown_at: Cell<Option<Instant>>,
+    /// Whether the cursor waits on the value entry line, so the lines below it may be redrawn.
+    prompt_visible: Cell<bool>,
+    /// In raw mode keys are read one by one and nothing is echoed by the terminal.
+    raw_mode: Cell<bool>,
+    /// Search query of the script picker, only the scripts whose file name contains it are listed.
+    filter: RefCell<Option<String>>,
+    /// Errors and reloads are appended here too if "--log-file" is given.
+    log_file: RefCell<Option<(File, LogFormat)>>,
+    /// Warnings waiting to be logged below the list once it is rendered.
+    warnings: RefCell<Vec<String>>,
+    theme: Cell<Theme>,
+    /// Styling is never written if "NO_COLOR" is set or stdout is not a terminal, whatever the theme is.
+    colors_allowed: bool,
+    /// `None` if anything else is drawn since the list is rendered, the next render draws everything then.
+    rendered_list: RefCell<Option<RenderedList>>,
+    /// Descriptions of the scripts by their paths, read once and kept until the script changes.
+    descriptions: RefCell<HashMap<String, Option<String>>>,
+    /// Paths of the bypassed scripts in the chain, they are dimmed in the list.
+    bypassed: RefCell<Vec<String>>,
+}
+
+/// Keeps the cursor hidden while it is alive, so redrawing doesn't flicker.
+///
+/// The cursor is shown again when it is dropped, also when rendering fails half way.
+struct HiddenCursor<'a>(&'a Term);
+
+impl<'a> HiddenCursor<'a> {
+    fn new(term: &'a Term) -> Result<Self> {
+        term.hide_cursor()?;
+        Ok(Self(term))
+    }
+}
+
+impl Drop for HiddenCursor<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// Restores the terminal when it is dropped or if "mep" panics while it is alive.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    pub fn install() -> Self {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            default_hook(info);
+        }));
+        Self
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Shows the cursor, resets styling and disables raw mode.
+pub fn restore_terminal() {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(
+        io::stdout(),
+        SetAttribute(Attribute::Reset),
+        ResetColor,
+        cursor::Show
+    );
+}
+
+/// Keeps the terminal in raw mode while it is alive.
+pub struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
 }
 impl Tui {
-    pub fn new() -> Self {
+    pub fn new(headless: bool, quiet: bool, theme: Theme) -> Self {
+        let stdout = Term::stdout();
+        let colors_allowed =
+            colors_allowed(std::env::var_os("NO_COLOR").as_deref(), stdout.is_term());
         Self {
-            stdout: Term::stdout(),
+            stdout,
+            stderr: Term::stderr(),
+            headless,
+            quiet,
+            log_lines: RefCell::new(VecDeque::with_capacity(LOG_LINES_CAP)),
+            port_names: RefCell::new(None),
+            activity: Cell::new(0),
+            shown_activity: Cell::new(0),
+            outputs_ok: Cell::new(None),
+            shown_outputs_ok: Cell::new(None),
+            activity_shown_at: Cell::new(None),
+            prompt_visible: Cell::new(false),
+            raw_mode: Cell::new(false),
+            filter: RefCell::new(None),
+            log_file: RefCell::new(None),
+            warnings: RefCell::new(Vec::new()),
+            theme: Cell::new(theme),
+            colors_allowed,
+            rendered_list: RefCell::new(None),
+            descriptions: RefCell::new(HashMap::new()),
+            bypassed: RefCell::new(Vec::new()),
         }
     }
 
+    /// Replaces the theme, e.g. once the colors in the configuration are read.
+    pub fn set_theme(&self, theme: Theme) {
+        self.theme.set(theme);
+    }
+
+    fn is_styled(&self) -> bool {
+        self.colors_allowed && self.theme.get().styled
+    }
+
+    /// Colors the text unless the theme is plain or colors are not allowed.
+    fn paint<'a>(
+        &self,
+        text: &'a str,
+        color: impl FnOnce(&Theme) -> Color,
+    ) -> StyledContent<&'a str> {
+        paint_with_theme(text, color, &self.theme.get(), self.is_styled())
+    }
+
+    /// Opens the file to append the errors and reloads to, it is created if it doesn't exist.
+    pub fn set_log_file(&self, path: &Path, format: LogFormat) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.log_file.replace(Some((file, format)));
+        Ok(())
+    }
+
+    /// Appends an entry with the unix time in seconds to the log file if there is one.
+    ///
+    /// `script` is the path of the script the entry is about, if it is about one. It is only written
+    /// separately in the JSON format, the text format has the message alone.
+    /// Every entry is flushed right away so it survives a crash. Failing to write is ignored,
+    /// the log file shouldn't stop the processing.
+    pub fn write_to_log_file(&self, event: LogEvent, script: Option<&str>, message: &str) {
+        if let Some((file, format)) = self.log_file.borrow_mut().as_mut() {
+            let stamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let message = console::strip_ansi_codes(message)
+                .lines()
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join(" ");
+            let written = match format {
+                LogFormat::Text => writeln!(file, "{:.3} {}", stamp, message),
+                LogFormat::Json => writeln!(
+                    file,
+                    "{}",
+                    serde_json::json!({
+                        "ts": (stamp * 1000.0).round() / 1000.0,
+                        "level": event.level(),
+                        "event": event.name(),
+                        "script": script,
+                        "message": message,
+                    })
+                ),
+            };
+            let _ = written.and_then(|_| file.flush());
+        }
+    }
+
+    /// Switches the terminal to raw mode, so the script picker can be used with the arrow keys.
+    ///
+    /// The terminal is restored when the returned guard is dropped.
+    pub fn enable_raw_mode(&self) -> Result<RawMode> {
+        terminal::enable_raw_mode()?;
+        self.raw_mode.set(true);
+        Ok(RawMode)
+    }
+
     pub fn clear_lines(&self, lines: usize) -> Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        self.rendered_list.replace(None);
         self.stdout.move_cursor_up(lines)?;
         self.stdout.clear_line()?;
         Ok(())
     }
 
     pub fn clear(&self) -> Result<()> {
+        self.log_lines.borrow_mut().clear();
+        self.prompt_visible.set(false);
+        self.rendered_list.replace(None);
+        if self.headless {
+            return Ok(());
+        }
         self.stdout.clear_screen()?;
         Ok(())
     }
     fn write_line(&self, line: StyledContent<&str>) -> Result<()> {
-        self.stdout.write_line(&format!("{}", line))?;
+        if self.headless {
+            self.stderr.write_line(line.content())?;
+            return Ok(());
+        }
+        // Raw mode doesn't return the carriage on a new line, so do it always.
+        self.stdout
+            .write_str(&format!("{}\n", line).replace('\n', "\r\n"))?;
         Ok(())
     }
 
     pub fn intro(&self) -> Result<()> {
-        self.write_line("Here are your event processor scripts,".blue())?;
+        if self.headless || self.quiet {
+            return Ok(());
+        }
+        if let Some(ports_line) = self.ports_line() {
+            self.write_line(self.paint(&ports_line, |theme| theme.text))?;
+        }
+        self.write_line(self.paint(INTRO_LINE, |theme| theme.intro))?;
+        Ok(())
+    }
+
+    fn ports_line(&self) -> Option<String> {
+        self.port_names
+            .borrow()
+            .as_ref()
+            .map(|(input_port_name, output_port_names)| {
+                format!(
+                    "Receiving from \"{}\" and sending to \"{}\".",
+                    input_port_name,
+                    output_port_names.join("\", \"")
+                )
+            })
+    }
+
+    /// Number of rows `intro` takes in the terminal.
+    fn intro_rows(&self) -> usize {
+        if self.quiet {
+            return 0;
+        }
+        self.ports_line().map_or(0, |line| self.rows_of(&line)) + 1
+    }
+
+    /// The lines which ask for a script to be chosen, the last one is where the user types.
+    const fn value_entry_lines(&self) -> &'static str {
+        if self.quiet {
+            QUIET_VALUE_ENTRY_PROMPT
+        } else {
+            VALUE_ENTRY_LINE
+        }
+    }
+
+    const fn value_entry_prompt(&self) -> &'static str {
+        if self.quiet {
+            QUIET_VALUE_ENTRY_PROMPT
+        } else {
+            VALUE_ENTRY_PROMPT
+        }
+    }
+
+    /// Column where what is typed starts, after the prompt and a space.
+    const fn value_entry_column(&self) -> usize {
+        self.value_entry_prompt().len() + 1
+    }
+
+    /// Number of rows a line takes in the terminal, long lines are wrapped by it.
+    fn rows_of(&self, line: &str) -> usize {
+        let (_, columns) = self.stdout.size();
+        rows_in(line, columns)
+    }
+
+    /// Keeps the port names to show them on top of the list of scripts from now on.
+    pub fn set_port_names(
+        &self,
+        input_port_name: String,
+        output_port_names: Vec<String>,
+    ) -> Result<()> {
+        if self.headless {
+            self.stderr.write_line(&format!(
+                "Receiving from \"{}\" and sending to \"{}\".",
+                input_port_name,
+                output_port_names.join("\", \"")
+            ))?;
+        }
+        *self.port_names.borrow_mut() = Some((input_port_name, output_port_names));
+        // The intro changes, so the list moves.
+        self.rendered_list.replace(None);
         Ok(())
     }
     // pub fn no_home(&self) -> Result<()> {
@@ -54,102 +488,811 @@ impl Tui {
     //     )?;
     //     Ok(())
     // }
-    pub fn show_error(&self, info: &str, err: &str) -> Result<()> {
+    pub fn show_error(&self, category: ErrorCategory, info: &str, err: &str) -> Result<()> {
+        let label = category.label();
+        // Point to where it happened if koto tells it, e.g. "/home/me/.mep/arp.koto:12:5", so editors can jump there.
+        let info = error_location(err).map_or_else(
+            || info.to_owned(),
+            |(path, line, column)| format!("{}:{}:{}", path.unwrap_or(info), line, column),
+        );
+        let info = info.as_str();
+        self.write_to_log_file(
+            match category {
+                ErrorCategory::Compile | ErrorCategory::Runtime => LogEvent::Error,
+                ErrorCategory::Send => LogEvent::SendError,
+            },
+            Some(info),
+            &format!("There is a {} in: {}. {}", label, info, err),
+        );
+        if self.headless {
+            let err = err.lines().map(str::trim).collect::<Vec<&str>>().join(" ");
+            self.stderr
+                .write_line(&format!("There is a {} in: {}. {}", label, info, err))?;
+            return Ok(());
+        }
+        let _hidden_cursor = HiddenCursor::new(&self.stdout)?;
         self.clear_lines(1)?;
-        self.write_line(format!("{} There is an error in: {}", BULB, info)[..].magenta())?;
-        self.write_line("Please navigate to the \"~/.mep\" folder and fix your script.".blue())?;
-        self.write_line("".blue())?;
-        self.write_line(err.white().attribute(Attribute::Framed))?;
+        let header = format!("{} There is a {} in: {}", BULB, label, info);
+        self.write_line(self.paint(&header, |theme| match category {
+            ErrorCategory::Compile => theme.compile_error,
+            ErrorCategory::Runtime => theme.runtime_error,
+            ErrorCategory::Send => theme.error,
+        }))?;
+        self.write_line(self.paint(
+            "Please navigate to the \"~/.mep\" folder and fix your script.",
+            |theme| theme.intro,
+        ))?;
+        self.write_line(self.paint("", |theme| theme.intro))?;
+        // Keep koto's own line breaks, e.g. the source line and the caret below it,
+        // and wrap what doesn't fit in the terminal so the cursor math stays right.
+        let (_, width) = self.stdout.size();
+        for line in err.lines() {
+            for wrapped_line in wrap(line, usize::from(width)) {
+                let wrapped_line = self.paint(&wrapped_line, |theme| theme.text);
+                if self.is_styled() {
+                    self.write_line(wrapped_line.attribute(Attribute::Framed))?;
+                } else {
+                    self.write_line(wrapped_line)?;
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn removed_scripts_folder(&self) -> Result<()> {
+    pub fn removed_scripts_folder(&self, path: &Path) -> Result<()> {
         self.clear_lines(1)?;
-        self.write_line(
-            format!("{} \"~/.mep\" folder is removed. Re-run \"mep\" to auto create it and fill it with example scripts.",BULB)[..]
-                .red(),
-        )?;
+        self.write_line(self.paint(
+            &format!("{} \"{}\" folder is removed. Re-run \"mep\" to auto create it and fill it with example scripts.",BULB, path.display())[..],
+            |theme| theme.error,
+        ))?;
+        Ok(())
+    }
+    pub fn confirm_clean(&self, path: &Path) -> Result<()> {
+        self.write_line(self.paint(
+            &format!(
+                "{} \"{}\" folder and all the scripts in it will be removed. Are you sure? [y/N]",
+                BULB,
+                path.display()
+            )[..],
+            |theme| theme.error,
+        ))?;
         Ok(())
     }
-    pub fn reset_scripts_folder(&self) -> Result<()> {
+    pub fn reset_scripts_folder(&self, path: &Path) -> Result<()> {
         self.clear_lines(1)?;
-        self.write_line(
-            format!("{} \"~/.mep\" folder is reset with example scripts.", BULB)[..].red(),
-        )?;
+        if self.quiet {
+            return Ok(());
+        }
+        self.write_line(self.paint(
+            &format!(
+                "{} \"{}\" folder is reset with example scripts.",
+                BULB,
+                path.display()
+            )[..],
+            |theme| theme.error,
+        ))?;
+        Ok(())
+    }
+
+    pub fn backed_up_scripts_folder(&self, backup_path: &Path) -> Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        self.write_line(self.paint(
+            &format!(
+                "{} \"~/.mep\" folder is backed up to {:?}.",
+                BULB, backup_path
+            )[..],
+            |theme| theme.notice,
+        ))?;
         Ok(())
     }
 
     pub fn scripts_folder_not_found(&self) -> Result<()> {
-        self.write_line(
-            format!("{} Scripts folder \"~/.mep\" was not found. \"mep\" has created it and filled it with some example scripts for you.", BULB)[..].yellow(),
-        )?;
+        if self.quiet {
+            return Ok(());
+        }
+        self.write_line(self.paint(
+            &format!("{} Scripts folder \"~/.mep\" was not found. \"mep\" has created it and filled it with some example scripts for you.", BULB)[..],
+            |theme| theme.notice,
+        ))?;
         Ok(())
     }
 
-    pub fn ignore_choice(&self) -> Result<()> {
-        self.stdout.move_cursor_up(1)?;
+    /// Prints the path of the scripts folder as it is, for other programs to read.
+    ///
+    /// It goes to stdout without styling or a carriage return even in headless mode, e.g. for "cd $(mep --print-path)".
+    pub fn print_path(&self, path: &Path) -> Result<()> {
+        self.stdout.write_line(&path.display().to_string())?;
+        Ok(())
+    }
+
+    pub fn version_full(
+        &self,
+        mep_version: &str,
+        koto_version: &str,
+        prelude_modules: &[&str],
+    ) -> Result<()> {
+        self.write_line(self.paint(&format!("mep {}", mep_version), |theme| theme.intro))?;
+        self.write_line(self.paint(&format!("koto {}", koto_version), |theme| theme.text))?;
+        self.write_line(self.paint(
+            &format!("prelude modules: {}", prelude_modules.join(", ")),
+            |theme| theme.text,
+        ))?;
+        Ok(())
+    }
+
+    pub fn list_ports(
+        &self,
+        input_port_names: &[String],
+        output_port_names: &[String],
+    ) -> Result<()> {
+        self.write_line(self.paint("Available midi input ports,", |theme| theme.intro))?;
+        if input_port_names.is_empty() {
+            self.write_line(self.paint("   None found.", |theme| theme.text))?;
+        }
+        for (i, name) in input_port_names.iter().enumerate() {
+            self.write_line(self.paint(&format!("{:<3}{}", i, name), |theme| theme.index))?;
+        }
+
+        self.write_line(self.paint("Available midi output ports,", |theme| theme.intro))?;
+        if output_port_names.is_empty() {
+            self.write_line(self.paint("   None found.", |theme| theme.text))?;
+        }
+        for (i, name) in output_port_names.iter().enumerate() {
+            self.write_line(self.paint(&format!("{:<3}{}", i, name), |theme| theme.highlight))?;
+        }
+        Ok(())
+    }
+
+    /// Renders the last few log lines in a region below the value entry line.
+    ///
+    /// Expects the cursor to be on the value entry line, where it is left after rendering.
+    pub fn log(&self, message: &str) -> Result<()> {
+        if self.headless {
+            for line in message.lines() {
+                self.stderr.write_line(line)?;
+            }
+            return Ok(());
+        }
+        let mut log_lines = self.log_lines.borrow_mut();
+        if message.lines().any(|line| self.rows_of(line) > 1) {
+            // Wrapped lines might scroll the screen, the list isn't where it was rendered anymore.
+            self.rendered_list.replace(None);
+        }
+        for line in message.lines() {
+            if log_lines.len() == LOG_LINES_CAP {
+                log_lines.pop_front();
+            }
+            log_lines.push_back(line.to_owned());
+        }
+
+        // Leave the value entry line and redraw the region below it.
+        let _hidden_cursor = HiddenCursor::new(&self.stdout)?;
+        self.stdout.write_str("\r\n")?;
+        self.stdout.clear_to_end_of_screen()?;
+        self.write_activity_line()?;
+        for line in log_lines.iter() {
+            self.write_line(self.paint(line, |theme| theme.text))?;
+        }
+
+        // Go back to where the user types.
+        self.stdout.move_cursor_up(log_lines.len() + 2)?;
+        self.stdout.move_cursor_right(self.value_entry_column())?;
+        Ok(())
+    }
+
+    /// Logs the warning once the list is rendered next, so it isn't cleared with the screen right away.
+    pub fn warn(&self, message: &str) -> Result<()> {
+        self.write_to_log_file(LogEvent::Warning, None, message);
+        if self.headless {
+            self.stderr.write_line(message)?;
+            return Ok(());
+        }
+        self.warnings.borrow_mut().push(message.to_owned());
+        Ok(())
+    }
+
+    /// Updates the count of received messages and redraws it, at most every 100 milliseconds to avoid flicker.
+    ///
+    /// A count which couldn't be drawn because of throttling is pending, see `activity_redraw_in`.
+    pub fn set_activity(&self, count: usize) -> Result<()> {
+        self.activity.set(count);
+        if self.headless || !self.prompt_visible.get() {
+            return Ok(());
+        }
+        if self.activity_redraw_in() != Some(Duration::ZERO) {
+            return Ok(());
+        }
+        self.shown_activity.set(count);
+        self.shown_outputs_ok.set(self.outputs_ok.get());
+        self.activity_shown_at.set(Some(Instant::now()));
+
+        // Redraw only the line below the value entry line.
+        let _hidden_cursor = HiddenCursor::new(&self.stdout)?;
+        self.stdout.write_str("\r\n")?;
         self.stdout.clear_line()?;
-        self.write_line(VALUE_ENTRY_LINE.green())?;
-        self.stdout.move_cursor_up(1)?;
-        self.stdout.move_cursor_right(VALUE_ENTRY_LINE_LENGTH)?;
+        self.write_activity_line()?;
 
+        // Go back to where the user types.
+        self.stdout.move_cursor_up(2)?;
+        self.stdout.move_cursor_right(self.value_entry_column())?;
         Ok(())
     }
 
-    pub fn list_scripts(&self, available_scripts: &[String]) -> Result<()> {
-        self.clear()?;
-        self.intro()?;
-        for (i, element) in available_scripts.iter().enumerate() {
-            self.write_line(i.to_string().as_str().yellow())?;
+    /// Time left until a pending activity count may be drawn, `None` if there is nothing to draw.
+    pub fn activity_redraw_in(&self) -> Option<Duration> {
+        if self.headless
+            || !self.prompt_visible.get()
+            || (self.activity.get() == self.shown_activity.get()
+                && self.outputs_ok.get() == self.shown_outputs_ok.get())
+        {
+            return None;
+        }
+        Some(
+            self.activity_shown_at
+                .get()
+                .map_or(Duration::ZERO, |shown_at| {
+                    ACTIVITY_REDRAW_INTERVAL.saturating_sub(shown_at.elapsed())
+                }),
+        )
+    }
 
-            self.stdout.move_cursor_up(1)?;
-            self.stdout.move_cursor_right(3)?;
-            self.write_line(
-                format!(
-                    "{:?}",
-                    // Returns empty string if fails.
-                    PathBuf::from(element).file_name().unwrap_or_default()
-                )[..]
-                    .red(),
-            )?;
+    /// Sets whether every output port sends without errors, it is shown next to the count of received messages.
+    ///
+    /// It is drawn with the count, so it waits for "set_activity" like the count does.
+    pub fn set_outputs_ok(&self, ok: bool) {
+        let was_ok = self.outputs_ok.replace(Some(ok));
+        match was_ok {
+            Some(true) if !ok => self.write_to_log_file(
+                LogEvent::Port,
+                None,
+                "Sending through an output port is failing",
+            ),
+            Some(false) if ok => self.write_to_log_file(
+                LogEvent::Port,
+                None,
+                "Sending through the output ports works again",
+            ),
+            _ => {}
         }
+    }
+
+    /// Keeps the paths of the bypassed scripts in the chain to dim them in the list.
+    ///
+    /// Returns true if they changed while the list is shown, so it needs to be rendered again.
+    pub fn set_bypassed(&self, paths: Vec<String>) -> bool {
+        if *self.bypassed.borrow() == paths {
+            return false;
+        }
+        self.bypassed.replace(paths);
+        self.rendered_list.replace(None).is_some()
+    }
+
+    /// Writes the status of the output ports and the count of received messages.
+    fn write_activity_line(&self) -> Result<()> {
+        match self.outputs_ok.get() {
+            Some(true) => self
+                .stdout
+                .write_str(&format!("{} ", self.paint(OUTPUTS_OK, |theme| theme.ok)))?,
+            Some(false) => self.stdout.write_str(&format!(
+                "{} ",
+                self.paint(OUTPUTS_FAILING, |theme| theme.error)
+            ))?,
+            None => {}
+        }
+        self.write_line(self.paint(&self.activity_line(), |theme| theme.text))
+    }
 
-        self.write_line(VALUE_ENTRY_LINE.green())?;
+    fn activity_line(&self) -> String {
+        let count = self.activity.get();
+        if count == 0 {
+            String::new()
+        } else {
+            format!("\u{25c9} msgs: {}", count)
+        }
+    }
+
+    /// Shows what is typed so far after the value entry line, since the terminal doesn't echo in raw mode.
+    pub fn echo_choice(&self, typed: &str) -> Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        self.stdout.clear_line()?;
+        self.stdout.write_str(&format!(
+            "{} {}",
+            self.paint(self.value_entry_prompt(), |theme| theme.prompt),
+            typed
+        ))?;
+        Ok(())
+    }
+
+    /// Clears the rejected `typed` choice and puts the cursor back after the prompt.
+    pub fn ignore_choice(&self, typed: &str) -> Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        if self.raw_mode.get() {
+            // Enter doesn't move the cursor in raw mode, just clear what is typed.
+            return self.echo_choice("");
+        }
+        // The terminal echoed the choice after the prompt, long ones wrap to more rows.
+        let echoed_rows = self.rows_of(&echoed_choice(self.value_entry_prompt(), typed));
+        self.stdout.move_cursor_up(echoed_rows)?;
+        for _ in 0..echoed_rows {
+            self.stdout.clear_line()?;
+            self.stdout.move_cursor_down(1)?;
+        }
+        self.stdout.move_cursor_up(echoed_rows)?;
+        self.write_line(self.paint(self.value_entry_prompt(), |theme| theme.prompt))?;
         self.stdout.move_cursor_up(1)?;
-        self.stdout.move_cursor_right(VALUE_ENTRY_LINE_LENGTH)?;
+        self.stdout.move_cursor_right(self.value_entry_column())?;
+        self.prompt_visible.set(true);
+        // Draw the count again, it might have been cleared with the screen.
+        self.shown_activity.set(0);
+        self.shown_outputs_ok.set(None);
 
         Ok(())
     }
 
-    pub fn highlight_and_render(&self, index: &str, available_scripts: &[String]) -> Result<()> {
+    pub fn list_scripts(
+        &self,
+        available_scripts: &[String],
+        scripts_folder_path: &Path,
+    ) -> Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        self.render_list(None, available_scripts, scripts_folder_path)
+    }
+
+    pub fn highlight_and_render(
+        &self,
+        index: &str,
+        available_scripts: &[String],
+        scripts_folder_path: &Path,
+    ) -> Result<()> {
         let index_as_number: usize = index.parse()?;
-        // self.stdout.clear_last_lines(available_scripts.len() + 1)?;
+        if self.headless {
+            if let Some(script) = available_scripts.get(index_as_number) {
+                self.stderr.write_line(&format!("Running {}", script))?;
+            }
+            return Ok(());
+        }
+        self.render_list(
+            Some(index_as_number),
+            available_scripts,
+            scripts_folder_path,
+        )
+    }
+
+    /// Sets the search query of the script picker, `None` shows all the scripts again.
+    pub fn set_filter(&self, filter: Option<String>) {
+        *self.filter.borrow_mut() = filter;
+    }
+
+    /// Indexes of the scripts which are listed, all of them unless there is a search query.
+    pub fn visible_scripts(&self, available_scripts: &[String]) -> Vec<usize> {
+        match &*self.filter.borrow() {
+            Some(query) => filter_scripts(available_scripts, query),
+            None => (0..available_scripts.len()).collect(),
+        }
+    }
+
+    pub fn is_prompt_visible(&self) -> bool {
+        self.prompt_visible.get()
+    }
+
+    /// Number of scripts which fit in the terminal together with the rest of the screen.
+    fn page_size(&self) -> usize {
+        let (rows, _) = self.stdout.size();
+        // Intro with the port names, page footer, commands, value entry line and the log region below it.
+        let reserved_rows = 2 + 1 + 1 + 2 + 1 + LOG_LINES_CAP;
+        usize::from(rows).saturating_sub(reserved_rows).max(1)
+    }
+
+    /// Renders the visible scripts, numbered by their position in the rendered list.
+    ///
+    /// If only the highlight moves on the page which is already on the screen, only the numbers of the
+    /// previously and the newly highlighted scripts are repainted instead of clearing the screen.
+    fn render_list(
+        &self,
+        highlighted: Option<usize>,
+        available_scripts: &[String],
+        scripts_folder_path: &Path,
+    ) -> Result<()> {
+        let _hidden_cursor = HiddenCursor::new(&self.stdout)?;
+
+        // Only the page which has the highlighted script is rendered, so the intro doesn't scroll off.
+        let visible_scripts = self.visible_scripts(available_scripts);
+        let page_size = self.page_size();
+        let highlighted_position = highlighted
+            .and_then(|idx| visible_scripts.iter().position(|visible| *visible == idx))
+            .unwrap_or(0);
+        let page = highlighted_position / page_size;
+        let page_count = ((visible_scripts.len() + page_size - 1) / page_size).max(1);
+        let page_scripts = visible_scripts
+            .iter()
+            .copied()
+            .enumerate()
+            .skip(page * page_size)
+            .take(page_size)
+            .collect::<Vec<(usize, usize)>>();
+
+        if self.repaint_highlight(highlighted, available_scripts, &page_scripts)? {
+            return self.log_warnings();
+        }
 
         self.clear()?;
         self.intro()?;
-        for (i, element) in available_scripts.iter().enumerate() {
-            if i == index_as_number {
-                self.write_line(i.to_string().as_str().green())?;
+        let mut row = self.intro_rows();
+        let mut rows = Vec::with_capacity(page_scripts.len());
+        for &(i, index) in &page_scripts {
+            let i = i.to_string();
+            if Some(index) == highlighted {
+                self.write_line(self.paint(&i, |theme| theme.highlight))?;
             } else {
-                self.write_line(i.to_string().as_str().yellow())?;
+                self.write_line(self.paint(&i, |theme| theme.index))?;
             }
 
             self.stdout.move_cursor_up(1)?;
             self.stdout.move_cursor_right(3)?;
+            let name = script_name(
+                &available_scripts[index],
+                available_scripts,
+                scripts_folder_path,
+            );
+            if self.bypassed.borrow().contains(&available_scripts[index]) {
+                let name = format!("{} (bypassed)", name);
+                let painted = self.paint(&name, |theme| theme.name);
+                self.write_line(if self.is_styled() {
+                    painted.dim()
+                } else {
+                    painted
+                })?;
+                rows.push((index, row, i));
+                row += self.rows_of(&format!("   {}", name));
+            } else if let Some(description) = self.description(&available_scripts[index]) {
+                self.stdout
+                    .write_str(&format!("{}  ", self.paint(&name, |theme| theme.name)))?;
+                self.write_line(self.paint(&description, |theme| theme.text))?;
+                rows.push((index, row, i));
+                row += self.rows_of(&format!("   {}  {}", name, description));
+            } else {
+                self.write_line(self.paint(&name, |theme| theme.name))?;
+                rows.push((index, row, i));
+                row += self.rows_of(&format!("   {}", name));
+            }
+        }
+
+        if page_count > 1 {
             self.write_line(
-                format!(
-                    "{:?}",
-                    // Returns empty string if fails.
-                    PathBuf::from(element).file_name().unwrap_or_default()
-                )[..]
-                    .red(),
+                self.paint(&format!("page {}/{}", page + 1, page_count), |theme| {
+                    theme.text
+                }),
             )?;
+            row += 1;
+        }
+
+        // The keys are only read one by one in raw mode.
+        if self.raw_mode.get() && !self.quiet {
+            self.write_line(self.paint(COMMANDS_LINE, |theme| theme.text))?;
+            row += self.rows_of(COMMANDS_LINE);
         }
 
-        self.write_line(VALUE_ENTRY_LINE.green())?;
+        self.write_line(self.paint(self.value_entry_lines(), |theme| theme.prompt))?;
+        let value_entry_lines = self.value_entry_lines().lines().collect::<Vec<&str>>();
+        let prompt_row = row
+            + value_entry_lines
+                .iter()
+                .take(value_entry_lines.len().saturating_sub(1))
+                .map(|line| self.rows_of(line))
+                .sum::<usize>();
         self.stdout.move_cursor_up(1)?;
-        self.stdout.move_cursor_right(VALUE_ENTRY_LINE_LENGTH)?;
+        self.stdout.move_cursor_right(self.value_entry_column())?;
+        if let Some(query) = &*self.filter.borrow() {
+            self.stdout.write_str(&format!("/{}", query))?;
+        }
+        self.prompt_visible.set(true);
+        // Draw the count again, it might have been cleared with the screen.
+        self.shown_activity.set(0);
+        self.shown_outputs_ok.set(None);
+
+        let terminal_size = self.stdout.size();
+        // If the screen scrolled, the rows counted from the top are off.
+        if prompt_row + 2 + LOG_LINES_CAP <= usize::from(terminal_size.0) {
+            self.rendered_list.replace(Some(RenderedList {
+                scripts: available_scripts.to_vec(),
+                filter: self.filter.borrow().clone(),
+                terminal_size,
+                rows,
+                highlighted,
+                prompt_row,
+            }));
+        }
+
+        self.log_warnings()
+    }
+
+    /// Repaints the numbers of the previously and the newly highlighted scripts if nothing else changed.
+    ///
+    /// Returns `false` without drawing anything if the whole list should be rendered instead.
+    fn repaint_highlight(
+        &self,
+        highlighted: Option<usize>,
+        available_scripts: &[String],
+        page_scripts: &[(usize, usize)],
+    ) -> Result<bool> {
+        let mut rendered_list = self.rendered_list.borrow_mut();
+        let rendered = match &mut *rendered_list {
+            Some(rendered)
+                if rendered.scripts == available_scripts
+                    && rendered.filter == *self.filter.borrow()
+                    && rendered.terminal_size == self.stdout.size()
+                    && rendered.rows.len() == page_scripts.len()
+                    && rendered
+                        .rows
+                        .iter()
+                        .zip(page_scripts)
+                        .all(|((index, _, _), (_, page_index))| index == page_index) =>
+            {
+                rendered
+            }
+            _ => return Ok(false),
+        };
+
+        if rendered.highlighted != highlighted {
+            for (index, row, i) in &rendered.rows {
+                let color: fn(&Theme) -> Color = if Some(*index) == highlighted {
+                    |theme| theme.highlight
+                } else if Some(*index) == rendered.highlighted {
+                    |theme| theme.index
+                } else {
+                    continue;
+                };
+                self.stdout.move_cursor_to(0, *row)?;
+                self.stdout.write_str(&self.paint(i, color).to_string())?;
+            }
+        }
+        rendered.highlighted = highlighted;
+
+        // Back to the value entry line, clearing what is typed like a full render does.
+        self.stdout.move_cursor_to(0, rendered.prompt_row)?;
+        let query = self
+            .filter
+            .borrow()
+            .as_ref()
+            .map_or_else(String::new, |query| format!("/{}", query));
+        drop(rendered_list);
+        self.echo_choice(&query)?;
+        self.prompt_visible.set(true);
+        Ok(true)
+    }
 
+    /// Description of the script, read from the script only the first time it is asked for.
+    fn description(&self, script_path: &str) -> Option<String> {
+        self.descriptions
+            .borrow_mut()
+            .entry(script_path.to_owned())
+            .or_insert_with(|| read_description(script_path))
+            .clone()
+    }
+
+    /// Reads the description of the script again the next time the list is rendered, e.g. after it is changed.
+    ///
+    /// `None` forgets the descriptions of all the scripts.
+    pub fn forget_description(&self, script_path: Option<&str>) {
+        match script_path {
+            Some(script_path) => {
+                self.descriptions.borrow_mut().remove(script_path);
+            }
+            None => self.descriptions.borrow_mut().clear(),
+        }
+        // The list on the screen might show the old one.
+        self.rendered_list.replace(None);
+    }
+
+    /// Logs the warnings which waited for the list to be rendered.
+    fn log_warnings(&self) -> Result<()> {
+        let warnings = self.warnings.take();
+        for warning in &warnings {
+            self.log(warning)?;
+        }
         Ok(())
     }
 }
+
+/// Indexes of the scripts whose file name contains `query`, ignoring the case.
+pub fn filter_scripts(available_scripts: &[String], query: &str) -> Vec<usize> {
+    let query = query.to_lowercase();
+    available_scripts
+        .iter()
+        .enumerate()
+        .filter(|(_, script)| {
+            Path::new(script).file_name().map_or(false, |file_name| {
+                file_name.to_string_lossy().to_lowercase().contains(&query)
+            })
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Whether styling may be written at all, never if "NO_COLOR" is set or stdout is not a terminal.
+///
+/// See https://no-color.org, an empty value doesn't count.
+fn colors_allowed(no_color: Option<&OsStr>, is_term: bool) -> bool {
+    let no_color = no_color.map_or(false, |value| !value.is_empty());
+    !no_color && is_term
+}
+
+/// Colors the text with the theme if `styled`, otherwise nothing but the text is written.
+fn paint_with_theme<'a>(
+    text: &'a str,
+    color: impl FnOnce(&Theme) -> Color,
+    theme: &Theme,
+    styled: bool,
+) -> StyledContent<&'a str> {
+    if styled {
+        text.with(color(theme))
+    } else {
+        text.stylize()
+    }
+}
+
+/// Name of the script in the list, its file name unless another script has the same one.
+///
+/// Scripts with the same file name are told apart by their paths relative to the scripts folder, e.g. "drums/arp.koto".
+fn script_name(
+    script_path: &str,
+    available_scripts: &[String],
+    scripts_folder_path: &Path,
+) -> String {
+    let script_path = Path::new(script_path);
+    let file_name = script_path.file_name().unwrap_or_default();
+    let same_file_names = available_scripts
+        .iter()
+        .filter(|script| Path::new(script).file_name() == Some(file_name))
+        .count();
+    let name = if same_file_names > 1 {
+        // Falls back to the full path if the script is not in the scripts folder.
+        script_path
+            .strip_prefix(scripts_folder_path)
+            .unwrap_or(script_path)
+    } else {
+        Path::new(file_name)
+    };
+    format!("\"{}\"", name.display())
+}
+
+/// Finds the position in the source excerpt of a koto error, e.g. "/home/me/.mep/arp.koto - 12:5".
+///
+/// The path is returned too if the excerpt has one, the error might be in an imported script.
+/// Some runtime errors don't have an excerpt, then it is `None`.
+fn error_location(err: &str) -> Option<(Option<&str>, usize, usize)> {
+    err.lines().find_map(|line| {
+        let line = line.trim();
+        let (path, position) = match line.rsplit_once(" - ") {
+            Some((path, position)) => (Some(path), position),
+            None => (None, line),
+        };
+        let (line, column) = position.split_once(':')?;
+        Some((path, line.parse().ok()?, column.parse().ok()?))
+    })
+}
+
+/// Finds the description comment in the first lines of the script, `None` if there is none or it can't be read.
+fn read_description(script_path: &str) -> Option<String> {
+    let file = File::open(script_path).ok()?;
+    BufReader::new(file)
+        .lines()
+        .take(DESCRIPTION_LINES)
+        .map_while(std::result::Result::ok)
+        .find_map(|line| {
+            let description = line.trim().strip_prefix(DESCRIPTION_PREFIX)?.trim();
+            (!description.is_empty()).then(|| description.to_owned())
+        })
+}
+
+/// Splits a line into pieces which are at most `width` characters long.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if width == 0 || line.is_empty() {
+        return vec![line.to_owned()];
+    }
+    line.chars()
+        .collect::<Vec<char>>()
+        .chunks(width)
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+/// Number of rows a line takes in a terminal `columns` wide.
+fn rows_in(line: &str, columns: u16) -> usize {
+    let columns = usize::from(columns).max(1);
+    ((console::measure_text_width(line) + columns - 1) / columns).max(1)
+}
+
+/// The line the terminal shows after a choice is typed after `prompt` and entered.
+fn echoed_choice(prompt: &str, typed: &str) -> String {
+    format!("{} {}", prompt, typed.trim_end_matches(['\r', '\n']))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_row_of_an_invalid_entry_is_cleared() {
+        let prompt = VALUE_ENTRY_PROMPT;
+        // The prompt and its space fill the first row exactly, the entry starts on the next one.
+        let columns = u16::try_from(prompt.len() + 1).unwrap_or(u16::MAX);
+        assert_eq!(rows_in(&echoed_choice(prompt, ""), columns), 1);
+        for (typed, rows) in [("7\n", 2), ("42\n", 2), ("123\r\n", 2), ("1234\n", 2)] {
+            assert_eq!(
+                rows_in(&echoed_choice(prompt, typed), columns),
+                rows,
+                "{:?}",
+                typed
+            );
+        }
+        // Every digit is a column in a narrow terminal.
+        for (typed, rows) in [("7\n", 1), ("42\n", 2), ("123\r\n", 3), ("1234\n", 4)] {
+            assert_eq!(
+                rows_in(&echoed_choice("", typed), 1),
+                rows + 1,
+                "{:?}",
+                typed
+            );
+        }
+    }
+
+    #[test]
+    fn the_line_ending_is_not_counted_as_typed() {
+        for typed in ["7", "42", "123", "1234"] {
+            assert_eq!(
+                echoed_choice(VALUE_ENTRY_PROMPT, &format!("{}\r\n", typed)),
+                format!("{} {}", VALUE_ENTRY_PROMPT, typed)
+            );
+        }
+    }
+
+    #[test]
+    fn scripts_with_the_same_file_name_are_shown_with_their_folders() {
+        let scripts_folder_path = std::env::temp_dir().join(".mep");
+        let available_scripts = [
+            scripts_folder_path.join("drums").join("arp.koto"),
+            scripts_folder_path.join("fx").join("arp.koto"),
+            scripts_folder_path.join("fx").join("delay.koto"),
+        ]
+        .iter()
+        .map(|script_path| script_path.display().to_string())
+        .collect::<Vec<String>>();
+        let names = available_scripts
+            .iter()
+            .map(|script| script_name(script, &available_scripts, &scripts_folder_path))
+            .collect::<Vec<String>>();
+        // The names are listed in the same order, so the indexes stay the same.
+        assert_eq!(
+            names,
+            vec![
+                format!("\"{}\"", Path::new("drums").join("arp.koto").display()),
+                format!("\"{}\"", Path::new("fx").join("arp.koto").display()),
+                "\"delay.koto\"".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_but_the_text_is_painted_without_colors() {
+        assert!(!colors_allowed(Some(OsStr::new("1")), true));
+        assert!(!colors_allowed(None, false));
+        assert!(colors_allowed(Some(OsStr::new("")), true));
+        assert!(colors_allowed(None, true));
+        for theme in [Theme::default(), Theme::plain()] {
+            let styled = colors_allowed(Some(OsStr::new("1")), true) && theme.styled;
+            let painted =
+                paint_with_theme("mep_out", |theme| theme.name, &theme, styled).to_string();
+            assert_eq!(console::strip_ansi_codes(&painted), painted);
+            assert_eq!(painted, "mep_out");
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Checks the command line options which are refused before "mep" touches any folder.
+
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+#[test]
+fn clean_and_reset_refuse_a_scripts_dir() {
+    let scripts_dir = std::env::temp_dir().join(format!("mep-scripts-dir-{}", std::process::id()));
+    fs::create_dir_all(&scripts_dir).expect("the scripts folder is created");
+    fs::write(scripts_dir.join("mine.koto"), "import midi\n").expect("a script is written");
+
+    for option in ["--clean", "--reset"] {
+        let status = Command::new(env!("CARGO_BIN_EXE_mep"))
+            .arg("--scripts-dir")
+            .arg(&scripts_dir)
+            .args([option, "--yes"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .expect("mep is started");
+        assert!(
+            !status.success(),
+            "{} is accepted with \"--scripts-dir\"",
+            option
+        );
+        assert!(
+            scripts_dir.join("mine.koto").exists(),
+            "{} touched the scripts folder",
+            option
+        );
+    }
+
+    let _ = fs::remove_dir_all(&scripts_dir);
+}
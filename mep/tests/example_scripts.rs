@@ -0,0 +1,126 @@
+//! Runs scripts against recorded input with "--dry-run", without any midi hardware.
+//!
+//! Every message a script sends is logged as an "out <index>: <bytes>" line, which is compared here.
+//! What the example scripts send is tested in-process, next to `call_midi_listen_with`.
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A script which throws keeps "mep" waiting for a fix, it is killed after this.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tests run in parallel, every run gets its own input file.
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+struct DryRun {
+    success: bool,
+    sent: Vec<String>,
+    stderr: String,
+}
+
+fn example_scripts_folder() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../example_scripts")
+}
+
+/// Runs `script` on its own with `input` replayed to it and collects what it sends.
+fn dry_run(script: &Path, input: &str, extra_args: &[&str]) -> DryRun {
+    let script_name = script
+        .file_stem()
+        .expect("a script has a file name")
+        .to_string_lossy();
+    let input_path = std::env::temp_dir().join(format!(
+        "mep-test-{}-{}-{}.txt",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed),
+        script_name
+    ));
+    fs::write(&input_path, input).expect("the input file is written");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mep"))
+        .arg(script)
+        .args(["--headless", "--dry-run", "--input-file"])
+        .arg(&input_path)
+        .args(extra_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("mep is started");
+
+    // Read while waiting, so a full pipe doesn't block "mep".
+    let mut stderr_pipe = child.stderr.take().expect("stderr is piped");
+    let reader = thread::spawn(move || {
+        let mut stderr = String::new();
+        let _ = stderr_pipe.read_to_string(&mut stderr);
+        stderr
+    });
+
+    let started_at = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("mep can be waited for") {
+            break Some(status);
+        }
+        if started_at.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let stderr = reader.join().expect("stderr is read");
+    let _ = fs::remove_file(&input_path);
+
+    DryRun {
+        success: status.map_or(false, |status| status.success()),
+        sent: stderr
+            .lines()
+            .filter(|line| line.starts_with("out "))
+            .map(str::to_owned)
+            .collect(),
+        stderr,
+    }
+}
+
+#[test]
+fn dry_run_logs_every_message_of_a_burst() {
+    // Far more than main can show at once, all of them arrive at the same time.
+    let input = "144, 60, 100\n".repeat(2000);
+    let run = dry_run(
+        &example_scripts_folder().join("echo_messages.koto"),
+        &input,
+        &[],
+    );
+    assert!(run.success, "{}", run.stderr);
+    assert_eq!(run.sent.len(), 2000);
+    assert!(run.sent.iter().all(|line| line == "out 0: 90 3C 64"));
+}
+
+#[test]
+fn every_line_a_script_logs_is_shown() {
+    let script = std::env::temp_dir().join(format!(
+        "mep-test-{}-{}-log_flood.koto",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(
+        &script,
+        "import midi\nmidi.listen = |message|\n  for i in 0..1000\n    midi.log \"line\", i\n",
+    )
+    .expect("the script is written");
+    let run = dry_run(&script, "144, 60, 100\n", &[]);
+    let _ = fs::remove_file(&script);
+    assert!(run.success, "{}", run.stderr);
+    let logged = run
+        .stderr
+        .lines()
+        .filter(|line| line.starts_with("line "))
+        .count();
+    assert_eq!(logged, 1000);
+}